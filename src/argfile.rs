@@ -0,0 +1,80 @@
+//! `rustc` [`@path` argfiles](https://doc.rust-lang.org/rustc/command-line-arguments.html#path-load-command-line-flags-from-a-path).
+
+use std::ffi::OsStr;
+use std::ffi::OsString;
+
+use anyhow::Context;
+use tempfile::NamedTempFile;
+
+use crate::util::os_str_strip_prefix;
+
+/// A conservative limit on the total length of a command line.
+///
+/// Windows limits the whole command line to 32 KiB.
+#[cfg(windows)]
+const MAX_COMMAND_LINE_LEN: usize = 32 * 1024 - 1024;
+
+/// A conservative limit on the total length of a command line.
+///
+/// `ARG_MAX` is usually much larger than this, but it includes the environment,
+/// and Linux also limits a single arg to 128 KiB.
+#[cfg(not(windows))]
+const MAX_COMMAND_LINE_LEN: usize = 128 * 1024;
+
+/// Expand `@path` args into the lines of the file at `path`, as `rustc` does.
+///
+/// `@shell:path` args (which are unstable) are left as is.
+pub fn expand(args: Vec<OsString>) -> anyhow::Result<Vec<OsString>> {
+    let mut expanded = Vec::with_capacity(args.len());
+    for arg in args {
+        let path = match os_str_strip_prefix(&arg, "@") {
+            Some(path) if os_str_strip_prefix(path, "shell:").is_none() => path,
+            _ => {
+                expanded.push(arg);
+                continue;
+            }
+        };
+        let contents = fs_err::read_to_string(path).context("read `rustc` argfile")?;
+        expanded.extend(contents.lines().map(OsString::from));
+    }
+    Ok(expanded)
+}
+
+fn command_line_len<'a>(program: &OsStr, args: impl IntoIterator<Item = &'a OsString>) -> usize {
+    args.into_iter().map(|arg| arg.len() + 1).sum::<usize>() + program.len()
+}
+
+/// If `program` with `args` would exceed OS command line limits,
+/// write the `args` to an argfile, to be passed as `@path` instead.
+///
+/// The argfile is deleted when dropped, so it must outlive the command.
+/// Returns [`None`] if the args are short enough
+/// or if they can't be represented in an argfile (non-UTF-8 or containing newlines).
+pub fn write_if_too_long(
+    program: &OsStr,
+    args: &[OsString],
+) -> anyhow::Result<Option<NamedTempFile>> {
+    if command_line_len(program, args) <= MAX_COMMAND_LINE_LEN {
+        return Ok(None);
+    }
+    let lines = args
+        .iter()
+        .map(|arg| arg.to_str().filter(|arg| !arg.contains(['\n', '\r'])))
+        .collect::<Option<Vec<_>>>();
+    let Some(lines) = lines else {
+        return Ok(None);
+    };
+    let file = tempfile::Builder::new()
+        .prefix("rustc-args.")
+        .tempfile()
+        .context("create `rustc` argfile")?;
+    fs_err::write(file.path(), lines.join("\n")).context("write `rustc` argfile")?;
+    Ok(Some(file))
+}
+
+/// The `@path` arg for an argfile.
+pub fn arg(file: &NamedTempFile) -> OsString {
+    let mut arg = OsString::from("@");
+    arg.push(file.path());
+    arg
+}
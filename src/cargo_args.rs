@@ -0,0 +1,569 @@
+use std::ffi::OsStr;
+use std::ffi::OsString;
+use std::fmt;
+use std::fmt::Display;
+use std::fmt::Formatter;
+use std::io::IsTerminal;
+use std::mem;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::anyhow;
+
+use crate::package_selection::PackageSelection;
+use crate::util::flag_values;
+use crate::util::os_str_strip_prefix;
+use crate::util::remove_flag_if;
+use crate::util::short_flag_values;
+
+/// How much `cargo` and the wrapper should print.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Verbosity {
+    /// `-q`/`--quiet`.
+    Quiet,
+    #[default]
+    Normal,
+    /// `-v`/`--verbose`.
+    Verbose,
+    /// `-vv`.
+    VeryVerbose,
+}
+
+impl Verbosity {
+    /// The `cargo` flag for this, if any.
+    pub fn cargo_flag(self) -> Option<&'static str> {
+        match self {
+            Self::Quiet => Some("--quiet"),
+            Self::Normal => None,
+            Self::Verbose => Some("--verbose"),
+            Self::VeryVerbose => Some("-vv"),
+        }
+    }
+
+    pub const ALL: [Self; 4] = [Self::Quiet, Self::Normal, Self::Verbose, Self::VeryVerbose];
+
+    /// The name of this, like `very-verbose`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Quiet => "quiet",
+            Self::Normal => "normal",
+            Self::Verbose => "verbose",
+            Self::VeryVerbose => "very-verbose",
+        }
+    }
+}
+
+impl FromStr for Verbosity {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|verbosity| verbosity.name() == s)
+            .ok_or_else(|| anyhow!("unknown verbosity: {s}"))
+    }
+}
+
+impl Display for Verbosity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Whether to use colors, like `--color` and `$CARGO_TERM_COLOR`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum ColorChoice {
+    /// Color if stderr is a terminal.
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Auto => "auto",
+            Self::Always => "always",
+            Self::Never => "never",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        [Self::Auto, Self::Always, Self::Never]
+            .into_iter()
+            .find(|choice| choice.name() == value)
+    }
+
+    /// Resolve [`Self::Auto`] by checking if stderr is a terminal.
+    pub fn should_color(self) -> bool {
+        match self {
+            Self::Auto => std::io::stderr().is_terminal(),
+            Self::Always => true,
+            Self::Never => false,
+        }
+    }
+}
+
+impl FromStr for ColorChoice {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s).ok_or_else(|| anyhow!("unknown `--color`: {s}"))
+    }
+}
+
+impl Display for ColorChoice {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Whether `cargo` may update `Cargo.lock` or access the network,
+/// like `--locked`, `--offline`, and `--frozen`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum LockfilePolicy {
+    #[default]
+    Unlocked,
+    /// `--locked`: `Cargo.lock` must already be up to date.
+    Locked,
+    /// `--offline`: no network access.
+    Offline,
+    /// `--frozen`: both [`Self::Locked`] and [`Self::Offline`].
+    Frozen,
+}
+
+impl LockfilePolicy {
+    /// The `cargo` flag for this, if any.
+    pub fn cargo_flag(self) -> Option<&'static str> {
+        match self {
+            Self::Unlocked => None,
+            Self::Locked => Some("--locked"),
+            Self::Offline => Some("--offline"),
+            Self::Frozen => Some("--frozen"),
+        }
+    }
+
+    fn from_flags(locked: bool, offline: bool) -> Self {
+        match (locked, offline) {
+            (false, false) => Self::Unlocked,
+            (true, false) => Self::Locked,
+            (false, true) => Self::Offline,
+            (true, true) => Self::Frozen,
+        }
+    }
+
+    pub fn is_locked(self) -> bool {
+        matches!(self, Self::Locked | Self::Frozen)
+    }
+
+    pub fn is_offline(self) -> bool {
+        matches!(self, Self::Offline | Self::Frozen)
+    }
+
+    /// This policy, but also without network access.
+    pub fn offline(self) -> Self {
+        Self::from_flags(self.is_locked(), true)
+    }
+}
+
+fn is_lockfile_flag(arg: &OsStr) -> bool {
+    arg == "--locked" || arg == "--offline" || arg == "--frozen"
+}
+
+fn is_verbosity_flag(arg: &OsStr) -> bool {
+    arg == "--quiet"
+        || arg == "--verbose"
+        || os_str_strip_prefix(arg, "-").is_some_and(|flags| {
+            let flags = flags.as_encoded_bytes();
+            !flags.is_empty() && (flags == b"q" || flags.iter().all(|&c| c == b'v'))
+        })
+}
+
+/// Flags before the subcommand that take a separate value, like `--config {value}`.
+const GLOBAL_FLAGS_WITH_VALUES: &[&str] = &["--config", "-Z", "-C", "--color"];
+
+/// Subcommand flags (of `cargo build` and friends) that take a separate value, like `--package {spec}`.
+const FLAGS_WITH_VALUES: &[&str] = &[
+    "-p",
+    "--package",
+    "--exclude",
+    "--bin",
+    "--example",
+    "--test",
+    "--bench",
+    "-F",
+    "--features",
+    "-j",
+    "--jobs",
+    "--profile",
+    "--target",
+    "--target-dir",
+    "--artifact-dir",
+    "--manifest-path",
+    "--lockfile-path",
+    "--message-format",
+    "--color",
+    "--config",
+    "-Z",
+];
+
+/// `cargo` args, split into
+/// `cargo {global_args} {subcommand} {args} -- {trailing_args}`,
+/// so that they can be inspected and mutated without breaking on where flags are placed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CargoArgs {
+    /// Args before the subcommand, like `+nightly`, `--locked`, or `-Z {flag}`.
+    global_args: Vec<OsString>,
+    subcommand: Option<OsString>,
+    /// The subcommand's args, before `--`.
+    args: Vec<OsString>,
+    /// Args after `--`, like the binary's args for `cargo run`,
+    /// or [`None`] if there's no `--`.
+    trailing_args: Option<Vec<OsString>>,
+}
+
+impl CargoArgs {
+    /// Split `cargo` args (not including `cargo` itself).
+    pub fn parse(args: impl IntoIterator<Item = impl Into<OsString>>) -> Self {
+        let mut this = Self::default();
+        let mut args = args.into_iter().map(Into::into);
+        while let Some(arg) = args.next() {
+            if arg == "--" {
+                this.trailing_args = Some(Vec::new());
+                break;
+            }
+            let is_flag = os_str_strip_prefix(&arg, "-").is_some()
+                || os_str_strip_prefix(&arg, "+").is_some();
+            if this.subcommand.is_some() {
+                this.args.push(arg);
+            } else if is_flag {
+                let takes_value = GLOBAL_FLAGS_WITH_VALUES.iter().any(|flag| arg == *flag);
+                this.global_args.push(arg);
+                if takes_value {
+                    this.global_args.extend(args.next());
+                }
+            } else {
+                this.subcommand = Some(arg);
+            }
+        }
+        if let Some(trailing_args) = &mut this.trailing_args {
+            trailing_args.extend(args);
+        }
+        this
+    }
+
+    /// All of the args, in order, for passing to `cargo`.
+    pub fn to_args(&self) -> Vec<OsString> {
+        let mut args = self.global_args.clone();
+        args.extend(self.subcommand.clone());
+        args.extend(self.args.iter().cloned());
+        if let Some(trailing_args) = &self.trailing_args {
+            args.push("--".into());
+            args.extend(trailing_args.iter().cloned());
+        }
+        args
+    }
+
+    pub fn global_args(&self) -> &[OsString] {
+        &self.global_args
+    }
+
+    /// Like `build`, or [`None`] if none was given (e.g. just `cargo --version`).
+    pub fn subcommand(&self) -> Option<&OsStr> {
+        self.subcommand.as_deref()
+    }
+
+    pub fn set_subcommand(&mut self, subcommand: impl Into<OsString>) {
+        self.subcommand = Some(subcommand.into());
+    }
+
+    /// The subcommand's args, before `--`.
+    pub fn args(&self) -> &[OsString] {
+        &self.args
+    }
+
+    pub fn trailing_args(&self) -> Option<&[OsString]> {
+        self.trailing_args.as_deref()
+    }
+
+    /// Remove and return the args after `--`.
+    pub fn take_trailing_args(&mut self) -> Option<Vec<OsString>> {
+        self.trailing_args.take()
+    }
+
+    /// The subcommand's positional args (not flags or their values), before `--`,
+    /// like the test name filter in `cargo test {filter}`.
+    pub fn positional_args(&self) -> impl Iterator<Item = &OsStr> {
+        let mut is_value = false;
+        self.args.iter().filter_map(move |arg| {
+            if mem::take(&mut is_value) {
+                return None;
+            }
+            if os_str_strip_prefix(arg, "-").is_some() {
+                is_value = FLAGS_WITH_VALUES.iter().any(|flag| arg == *flag);
+                return None;
+            }
+            Some(arg.as_os_str())
+        })
+    }
+
+    /// Add args to the subcommand, before any `--`.
+    pub fn add_args(&mut self, args: impl IntoIterator<Item = impl Into<OsString>>) {
+        self.args.extend(args.into_iter().map(Into::into));
+    }
+
+    /// Add args after `--`, adding the `--` if needed.
+    pub fn add_trailing_args(&mut self, args: impl IntoIterator<Item = impl Into<OsString>>) {
+        self.trailing_args
+            .get_or_insert_with(Vec::new)
+            .extend(args.into_iter().map(Into::into));
+    }
+
+    /// Add args before the subcommand, like `--config {value}`.
+    pub fn add_global_args(&mut self, args: impl IntoIterator<Item = impl Into<OsString>>) {
+        self.global_args.extend(args.into_iter().map(Into::into));
+    }
+
+    /// Add `--config {config}`, a `cargo` config override like `build.jobs=1`.
+    pub fn add_config(&mut self, config: impl Into<OsString>) {
+        self.add_global_args(["--config".into(), config.into()]);
+    }
+
+    /// The `--config` overrides, which can be given before or after the subcommand.
+    pub fn configs(&self) -> impl Iterator<Item = &OsStr> {
+        flag_values(&self.global_args, "--config").chain(flag_values(&self.args, "--config"))
+    }
+
+    /// Remove all occurrences of a subcommand flag (and its value).
+    pub fn remove_flag(&mut self, long: &str, short: Option<&str>) {
+        remove_flag_if(&mut self.args, long, short, |_| true);
+    }
+
+    /// The features from `--features`/`-F`, which may be comma or space separated.
+    pub fn features(&self) -> Vec<String> {
+        short_flag_values(&self.args, "--features", "-F")
+            .flat_map(|value| {
+                value
+                    .to_string_lossy()
+                    .split([',', ' '])
+                    .filter(|feature| !feature.is_empty())
+                    .map(ToOwned::to_owned)
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// Enable `features`, which may be `{feature}` or `{package}/{feature}`,
+    /// merging them with any existing `--features`/`-F` into a single `--features`.
+    ///
+    /// With `--all-features`, plain `{feature}`s are already enabled, so only `{package}/{feature}`s are added.
+    pub fn add_features<S: AsRef<str>>(&mut self, features: impl IntoIterator<Item = S>) {
+        let all_features = self.all_features();
+        let mut merged = self.features();
+        for feature in features {
+            let feature = feature.as_ref();
+            let already_enabled = all_features && !feature.contains('/');
+            if !already_enabled && !merged.iter().any(|it| it == feature) {
+                merged.push(feature.to_owned());
+            }
+        }
+        self.remove_flag("--features", Some("-F"));
+        if !merged.is_empty() {
+            self.add_args(["--features".to_owned(), merged.join(",")]);
+        }
+    }
+
+    /// The verbosity from `-q`/`--quiet` and (repeated) `-v`/`--verbose`,
+    /// before or after the subcommand,
+    /// falling back to `$CARGO_TERM_QUIET`/`$CARGO_TERM_VERBOSE`.
+    pub fn verbosity(&self) -> Verbosity {
+        let mut verbose = 0;
+        let mut quiet = false;
+        for arg in self.global_args.iter().chain(&self.args) {
+            if !is_verbosity_flag(arg) {
+                continue;
+            }
+            if arg == "--quiet" || arg == "-q" {
+                quiet = true;
+            } else if arg == "--verbose" {
+                verbose += 1;
+            } else {
+                verbose += arg.len() - 1;
+            }
+        }
+        let env_is_true = |key| std::env::var(key).is_ok_and(|value| value == "true");
+        match (quiet, verbose) {
+            (true, _) => Verbosity::Quiet,
+            (false, 0) if env_is_true("CARGO_TERM_QUIET") => Verbosity::Quiet,
+            (false, 0) if env_is_true("CARGO_TERM_VERBOSE") => Verbosity::Verbose,
+            (false, 0) => Verbosity::Normal,
+            (false, 1) => Verbosity::Verbose,
+            (false, _) => Verbosity::VeryVerbose,
+        }
+    }
+
+    /// The `--color`, before or after the subcommand.
+    pub fn color(&self) -> Option<ColorChoice> {
+        let global = flag_values(&self.global_args, "--color");
+        let color = global.chain(flag_values(&self.args, "--color")).last()?;
+        ColorChoice::parse(color.to_str()?)
+    }
+
+    /// Replace any `-q`/`-v` flags with ones for `verbosity`.
+    pub fn set_verbosity(&mut self, verbosity: Verbosity) {
+        self.global_args.retain(|arg| !is_verbosity_flag(arg));
+        self.args.retain(|arg| !is_verbosity_flag(arg));
+        self.add_args(verbosity.cargo_flag());
+    }
+
+    /// The [`LockfilePolicy`] from `--locked`, `--offline`, and `--frozen`,
+    /// before or after the subcommand, falling back to `$CARGO_NET_OFFLINE`.
+    pub fn lockfile_policy(&self) -> LockfilePolicy {
+        let (mut locked, mut offline) = (false, false);
+        for arg in self.global_args.iter().chain(&self.args) {
+            let frozen = arg == "--frozen";
+            locked |= frozen || arg == "--locked";
+            offline |= frozen || arg == "--offline";
+        }
+        offline |= std::env::var("CARGO_NET_OFFLINE").is_ok_and(|value| value == "true");
+        LockfilePolicy::from_flags(locked, offline)
+    }
+
+    /// Replace any `--locked`, `--offline`, and `--frozen` flags with ones for `policy`.
+    pub fn set_lockfile_policy(&mut self, policy: LockfilePolicy) {
+        self.global_args.retain(|arg| !is_lockfile_flag(arg));
+        self.args.retain(|arg| !is_lockfile_flag(arg));
+        self.add_args(policy.cargo_flag());
+    }
+
+    pub fn has_flag(&self, flag: &str) -> bool {
+        self.args.iter().any(|arg| arg == flag)
+    }
+
+    /// The unstable `-Z` flags, which can be given before or after the subcommand.
+    pub fn unstable_flags(&self) -> impl Iterator<Item = &OsStr> {
+        short_flag_values(&self.global_args, "-Z", "-Z")
+            .chain(short_flag_values(&self.args, "-Z", "-Z"))
+    }
+
+    /// Whether `std` is built from source with `-Z build-std`.
+    pub fn is_build_std(&self) -> bool {
+        self.unstable_flags()
+            .any(|flag| flag == "build-std" || os_str_strip_prefix(flag, "build-std=").is_some())
+    }
+
+    pub fn all_features(&self) -> bool {
+        self.has_flag("--all-features")
+    }
+
+    pub fn no_default_features(&self) -> bool {
+        self.has_flag("--no-default-features")
+    }
+
+    /// The `--target`s.
+    pub fn targets(&self) -> impl Iterator<Item = &OsStr> {
+        flag_values(&self.args, "--target")
+    }
+
+    pub fn add_target(&mut self, target: impl Into<OsString>) {
+        self.add_args(["--target".into(), target.into()]);
+    }
+
+    /// The `--profile`, or `release` for `--release`/`-r`.
+    pub fn profile(&self) -> Option<&OsStr> {
+        flag_values(&self.args, "--profile").last().or_else(|| {
+            (self.has_flag("--release") || self.has_flag("-r")).then_some(OsStr::new("release"))
+        })
+    }
+
+    pub fn set_profile(&mut self, profile: impl Into<OsString>) {
+        self.remove_flag("--profile", None);
+        self.args.retain(|arg| arg != "--release" && arg != "-r");
+        self.add_args(["--profile".into(), profile.into()]);
+    }
+
+    pub fn is_release(&self) -> bool {
+        self.profile() == Some(OsStr::new("release"))
+    }
+
+    /// The `--package`/`-p`s.
+    pub fn packages(&self) -> impl Iterator<Item = &OsStr> {
+        short_flag_values(&self.args, "--package", "-p")
+    }
+
+    /// The [`PackageSelection`] from `-p`/`--package`, `--workspace`/`--all`, and `--exclude`.
+    pub fn package_selection(&self) -> PackageSelection {
+        let lossy = |spec: &OsStr| spec.to_string_lossy().into_owned();
+        if self.has_flag("--workspace") || self.has_flag("--all") {
+            let exclude = flag_values(&self.args, "--exclude").map(lossy).collect();
+            return PackageSelection::Workspace { exclude };
+        }
+        let packages = self.packages().map(lossy).collect::<Vec<_>>();
+        if packages.is_empty() {
+            PackageSelection::Default
+        } else {
+            PackageSelection::Packages(packages)
+        }
+    }
+
+    pub fn add_package(&mut self, package: impl Into<OsString>) {
+        self.add_args(["--package".into(), package.into()]);
+    }
+
+    pub fn manifest_path(&self) -> Option<&Path> {
+        flag_values(&self.args, "--manifest-path")
+            .last()
+            .map(Path::new)
+    }
+
+    pub fn target_dir(&self) -> Option<&Path> {
+        flag_values(&self.args, "--target-dir")
+            .last()
+            .map(Path::new)
+    }
+
+    pub fn set_manifest_path(&mut self, manifest_path: impl Into<OsString>) {
+        self.remove_flag("--manifest-path", None);
+        self.add_args(["--manifest-path".into(), manifest_path.into()]);
+    }
+
+    /// Replace any `--message-format`s, which can be given multiple times, with `format`.
+    pub fn set_message_format(&mut self, format: impl Into<OsString>) {
+        self.remove_flag("--message-format", None);
+        self.add_args(["--message-format".into(), format.into()]);
+    }
+}
+
+/// If invoked as a `cargo` subcommand, like `cargo instrument {args}` for `cargo-instrument`,
+/// `cargo` passes the subcommand name as the first arg, `cargo-instrument instrument {args}`,
+/// so strip it.
+pub(crate) fn strip_cargo_subcommand(mut args: Vec<OsString>) -> Vec<OsString> {
+    let subcommand = args
+        .first()
+        .and_then(|program| Path::new(program).file_stem())
+        .and_then(|stem| os_str_strip_prefix(stem, "cargo-"))
+        .map(ToOwned::to_owned);
+    if let Some(subcommand) = subcommand {
+        if args.get(1) == Some(&subcommand) {
+            args.remove(1);
+        }
+    }
+    args
+}
+
+/// Split a wrapper's command line (including `argv[0]`) at the first `--`
+/// into its own args (for `clap`) and the trailing `cargo` args,
+/// like `cargo-instrument --metadata m.json -- build --release`.
+///
+/// This doesn't validate anything, so the `cargo` args may be empty,
+/// like for `cargo-instrument --help`.
+/// A missing subcommand is only an error once a wrapped build is run.
+pub fn split_cargo_args(
+    args: impl IntoIterator<Item = impl Into<OsString>>,
+) -> (Vec<OsString>, CargoArgs) {
+    let mut args = args.into_iter().map(Into::into);
+    let own_args = args.by_ref().take_while(|arg| arg != "--").collect();
+    let cargo_args = CargoArgs::parse(args);
+    (own_args, cargo_args)
+}
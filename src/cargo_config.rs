@@ -0,0 +1,526 @@
+//! Reading `cargo` config, like `.cargo/config.toml`, the way `cargo` does.
+//!
+//! See <https://doc.rust-lang.org/cargo/reference/config.html>.
+
+use std::env;
+use std::ffi::OsStr;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::ensure;
+use anyhow::Context;
+use toml_edit::Document;
+use toml_edit::Item;
+use toml_edit::Value;
+
+use crate::exit;
+use crate::rustflags::RustFlags;
+
+struct ConfigFile {
+    /// The dir relative paths in this config are relative to,
+    /// the parent of the `.cargo` dir, or the current dir for `--config` and env vars.
+    base_dir: PathBuf,
+    doc: Document,
+}
+
+/// Merged `cargo` config from `--config` args, `.cargo/config.toml`s, and `$CARGO_HOME/config.toml`.
+///
+/// `$CARGO_*` env vars are checked for each key as well.
+pub struct CargoConfig {
+    /// Highest priority first.
+    files: Vec<ConfigFile>,
+    cwd: PathBuf,
+}
+
+fn read_config(cargo_dir: &Path) -> anyhow::Result<Option<ConfigFile>> {
+    for name in ["config.toml", "config"] {
+        let path = cargo_dir.join(name);
+        if !path.is_file() {
+            continue;
+        }
+        let toml = fs_err::read_to_string(&path)?;
+        let doc = toml
+            .parse::<Document>()
+            .with_context(|| format!("invalid `cargo` config: {}", path.display()))?;
+        let base_dir = cargo_dir.parent().unwrap_or(cargo_dir).to_owned();
+        return Ok(Some(ConfigFile { base_dir, doc }));
+    }
+    Ok(None)
+}
+
+/// `$CARGO_HOME`, or else `~/.cargo`, where `~` is `$HOME`, or `%USERPROFILE%` on Windows.
+fn cargo_home() -> Option<PathBuf> {
+    let home_var = if cfg!(windows) { "USERPROFILE" } else { "HOME" };
+    env::var_os("CARGO_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os(home_var).map(|home| Path::new(&home).join(".cargo")))
+}
+
+/// A string that's either whitespace-separated or an array of strings, like `build.rustflags`.
+fn string_list(item: &Item) -> Option<Vec<String>> {
+    match item.as_value()? {
+        Value::String(s) => Some(s.value().split_whitespace().map(Into::into).collect()),
+        Value::Array(array) => Some(
+            array
+                .iter()
+                .filter_map(|value| value.as_str().map(Into::into))
+                .collect(),
+        ),
+        _ => None,
+    }
+}
+
+/// The `$CARGO_*` env var for a config key, like `$CARGO_BUILD_TARGET_DIR` for `build.target-dir`.
+fn env_key(key: &[&str]) -> String {
+    let key = key.join("_").replace(['-', '.'], "_").to_ascii_uppercase();
+    format!("CARGO_{key}")
+}
+
+impl CargoConfig {
+    /// Discover config the way `cargo` run in `cwd` with the `--config` args `cli_configs` would.
+    pub fn discover(cwd: &Path, cli_configs: &[impl AsRef<OsStr>]) -> anyhow::Result<Self> {
+        let mut files = Vec::new();
+        for config in cli_configs {
+            let config = config.as_ref();
+            let config_str = config
+                .to_str()
+                .ok_or_else(|| anyhow!("non-UTF-8 `--config`: {config:?}"))?;
+            let file = match config_str.parse::<Document>() {
+                Ok(doc) if config_str.contains('=') => ConfigFile {
+                    base_dir: cwd.to_owned(),
+                    doc,
+                },
+                _ => {
+                    let path = cwd.join(config);
+                    let toml = fs_err::read_to_string(&path)?;
+                    let doc = toml
+                        .parse::<Document>()
+                        .with_context(|| format!("invalid `cargo` config: {}", path.display()))?;
+                    let base_dir = path.parent().and_then(Path::parent).unwrap_or(cwd);
+                    ConfigFile {
+                        base_dir: base_dir.to_owned(),
+                        doc,
+                    }
+                }
+            };
+            files.push(file);
+        }
+        for dir in cwd.ancestors() {
+            files.extend(read_config(&dir.join(".cargo"))?);
+        }
+        if let Some(cargo_home) = cargo_home() {
+            let already_read = cwd.ancestors().any(|dir| dir.join(".cargo") == cargo_home);
+            if !already_read {
+                files.extend(read_config(&cargo_home)?);
+            }
+        }
+        Ok(Self {
+            files,
+            cwd: cwd.to_owned(),
+        })
+    }
+
+    /// The highest priority value of `key` and the dir it's relative to.
+    fn get(&self, key: &[&str]) -> Option<(&Item, &Path)> {
+        self.files.iter().find_map(|file| {
+            let item = key
+                .iter()
+                .try_fold(file.doc.as_item(), |item, key| item.get(key))?;
+            Some((item, file.base_dir.as_path()))
+        })
+    }
+
+    /// Whether `key` is set to anything, in any config or its env var.
+    pub fn contains(&self, key: &[&str]) -> bool {
+        env::var_os(env_key(key)).is_some() || self.get(key).is_some()
+    }
+
+    /// A string `key`, from its env var or config.
+    pub fn get_str(&self, key: &[&str]) -> Option<String> {
+        if let Ok(value) = env::var(env_key(key)) {
+            return Some(value);
+        }
+        self.get(key)?.0.as_str().map(Into::into)
+    }
+
+    /// A path `key`, which is relative to its config's dir (or the current dir for env vars).
+    fn get_path(&self, key: &[&str]) -> Option<PathBuf> {
+        if let Some(value) = env::var_os(env_key(key)) {
+            return Some(self.cwd.join(value));
+        }
+        let (item, base_dir) = self.get(key)?;
+        Some(base_dir.join(item.as_str()?))
+    }
+
+    /// A string list `key` (see [`string_list`]) merged across all configs, lowest priority first,
+    /// followed by its env var.
+    fn get_string_list(&self, key: &[&str]) -> Option<Vec<String>> {
+        let mut found = false;
+        let mut list = Vec::new();
+        for file in self.files.iter().rev() {
+            let item = key
+                .iter()
+                .try_fold(file.doc.as_item(), |item, key| item.get(key));
+            if let Some(values) = item.and_then(string_list) {
+                found = true;
+                list.extend(values);
+            }
+        }
+        if let Ok(value) = env::var(env_key(key)) {
+            found = true;
+            list.extend(value.split_whitespace().map(Into::into));
+        }
+        found.then_some(list)
+    }
+
+    /// `build.rustc-wrapper`, which is a path relative to its config if it has a `/`,
+    /// or else a program name.
+    pub fn rustc_wrapper(&self) -> Option<PathBuf> {
+        if let Some(wrapper) = env::var_os("RUSTC_WRAPPER") {
+            return Some(wrapper.into())
+                .filter(|wrapper: &PathBuf| !wrapper.as_os_str().is_empty());
+        }
+        let key = ["build", "rustc-wrapper"];
+        if let Some(wrapper) = env::var_os(env_key(&key)) {
+            return Some(wrapper.into());
+        }
+        let (item, base_dir) = self.get(&key)?;
+        let wrapper = item.as_str()?;
+        if wrapper.is_empty() {
+            return None;
+        }
+        Some(if wrapper.contains(['/', '\\']) {
+            base_dir.join(wrapper)
+        } else {
+            wrapper.into()
+        })
+    }
+
+    /// `build.target-dir`, overridden by `$CARGO_TARGET_DIR`.
+    pub fn target_dir(&self) -> Option<PathBuf> {
+        if let Some(target_dir) = env::var_os("CARGO_TARGET_DIR") {
+            return Some(self.cwd.join(target_dir));
+        }
+        self.get_path(&["build", "target-dir"])
+    }
+
+    /// `build.rustdoc`, overridden by `$RUSTDOC`,
+    /// which is a path relative to its config if it has a `/`, or else a program name.
+    pub fn rustdoc(&self) -> Option<PathBuf> {
+        if let Some(rustdoc) = env::var_os("RUSTDOC") {
+            return Some(rustdoc.into());
+        }
+        let key = ["build", "rustdoc"];
+        if let Some(rustdoc) = env::var_os(env_key(&key)) {
+            return Some(rustdoc.into());
+        }
+        let (item, base_dir) = self.get(&key)?;
+        let rustdoc = item.as_str()?;
+        Some(if rustdoc.contains(['/', '\\']) {
+            base_dir.join(rustdoc)
+        } else {
+            rustdoc.into()
+        })
+    }
+
+    /// `build.rustdocflags`.
+    pub fn build_rustdocflags(&self) -> Option<Vec<String>> {
+        self.get_string_list(&["build", "rustdocflags"])
+    }
+
+    /// `build.rustflags`.
+    pub fn build_rustflags(&self) -> Option<Vec<String>> {
+        self.get_string_list(&["build", "rustflags"])
+    }
+
+    /// `target.{target}.rustflags` and `target.'cfg(..)'.rustflags` for all matching `cfgs`,
+    /// which are from `rustc --print cfg` (see [`target_cfgs`]).
+    pub fn target_rustflags(
+        &self,
+        target: &str,
+        cfgs: &[String],
+    ) -> anyhow::Result<Option<Vec<String>>> {
+        let mut rustflags = self.get_string_list(&["target", target, "rustflags"]);
+        let mut cfg_keys = Vec::new();
+        for file in &self.files {
+            let Some(targets) = file.doc.get("target").and_then(Item::as_table_like) else {
+                continue;
+            };
+            for (key, _) in targets.iter() {
+                if key.starts_with("cfg(") && !cfg_keys.iter().any(|it| it == key) {
+                    cfg_keys.push(key.to_owned());
+                }
+            }
+        }
+        for key in cfg_keys {
+            if !cfg_matches(&key, cfgs)? {
+                continue;
+            }
+            if let Some(flags) = self.get_string_list(&["target", &key, "rustflags"]) {
+                rustflags.get_or_insert_with(Vec::new).extend(flags);
+            }
+        }
+        Ok(rustflags)
+    }
+
+    /// The rustflags `cargo` will pass to `rustc` for `target`,
+    /// checking, in order of precedence,
+    /// `$CARGO_ENCODED_RUSTFLAGS`, `$RUSTFLAGS`, [`Self::target_rustflags`], and [`Self::build_rustflags`].
+    pub fn rustflags(&self, target: &str, cfgs: &[String]) -> anyhow::Result<RustFlags> {
+        if let Some(rustflags) = RustFlags::from_env() {
+            return Ok(rustflags);
+        }
+        let flags = match self.target_rustflags(target, cfgs)? {
+            Some(flags) => flags,
+            None => self.build_rustflags().unwrap_or_default(),
+        };
+        let mut rustflags = RustFlags::default();
+        rustflags.extend(flags);
+        Ok(rustflags)
+    }
+
+    /// The rustdocflags `cargo` will pass to `rustdoc`,
+    /// checking, in order of precedence,
+    /// `$CARGO_ENCODED_RUSTDOCFLAGS`, `$RUSTDOCFLAGS`, and [`Self::build_rustdocflags`].
+    pub fn rustdocflags(&self) -> RustFlags {
+        if let Some(rustdocflags) = RustFlags::rustdoc_from_env() {
+            return rustdocflags;
+        }
+        let mut rustdocflags = RustFlags::rustdoc();
+        rustdocflags.extend(self.build_rustdocflags().unwrap_or_default());
+        rustdocflags
+    }
+}
+
+/// The `cfg`s for `target` (or the host if [`None`]), from `rustc --print cfg`,
+/// like `unix` or `target_os="linux"`.
+pub fn target_cfgs(mut rustc: Command, target: Option<&str>) -> anyhow::Result<Vec<String>> {
+    rustc.args(["--print", "cfg"]);
+    if let Some(target) = target {
+        rustc.args(["--target", target]);
+    }
+    let output = exit::checked_output(&mut rustc)?;
+    let stdout =
+        String::from_utf8(output.stdout).context("non-UTF-8 `rustc --print cfg` output")?;
+    Ok(stdout.lines().map(Into::into).collect())
+}
+
+/// Whether the `cfg(..)` expression `expr` matches `cfgs` (see [`target_cfgs`]).
+fn cfg_matches(expr: &str, cfgs: &[String]) -> anyhow::Result<bool> {
+    let tokens = tokenize_cfg(expr)?;
+    let mut parser = CfgParser {
+        tokens: &tokens,
+        cfgs,
+    };
+    let matches = parser.expr()?;
+    ensure!(parser.tokens.is_empty(), "invalid `cfg` expression: {expr}");
+    Ok(matches)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum CfgToken<'a> {
+    Ident(&'a str),
+    Str(&'a str),
+    Punct(char),
+}
+
+fn tokenize_cfg(expr: &str) -> anyhow::Result<Vec<CfgToken<'_>>> {
+    let mut tokens = Vec::new();
+    let mut rest = expr;
+    while let Some(c) = rest.chars().next() {
+        if c.is_whitespace() {
+            rest = &rest[c.len_utf8()..];
+        } else if matches!(c, '(' | ')' | ',' | '=') {
+            tokens.push(CfgToken::Punct(c));
+            rest = &rest[1..];
+        } else if c == '"' {
+            let end = rest[1..]
+                .find('"')
+                .ok_or_else(|| anyhow!("unterminated string in `cfg` expression: {expr}"))?;
+            tokens.push(CfgToken::Str(&rest[1..1 + end]));
+            rest = &rest[end + 2..];
+        } else if c.is_alphanumeric() || c == '_' {
+            let end = rest
+                .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            tokens.push(CfgToken::Ident(&rest[..end]));
+            rest = &rest[end..];
+        } else {
+            bail!("unexpected `{c}` in `cfg` expression: {expr}");
+        }
+    }
+    Ok(tokens)
+}
+
+struct CfgParser<'a, 't> {
+    tokens: &'t [CfgToken<'a>],
+    cfgs: &'t [String],
+}
+
+impl CfgParser<'_, '_> {
+    fn next(&mut self) -> anyhow::Result<CfgToken<'_>> {
+        let (first, rest) = self
+            .tokens
+            .split_first()
+            .ok_or_else(|| anyhow!("unexpected end of `cfg` expression"))?;
+        self.tokens = rest;
+        Ok(first.clone())
+    }
+
+    fn expect(&mut self, punct: char) -> anyhow::Result<()> {
+        let token = self.next()?;
+        ensure!(
+            token == CfgToken::Punct(punct),
+            "expected `{punct}` in `cfg` expression, found {token:?}"
+        );
+        Ok(())
+    }
+
+    /// Parse `({expr}, ...)`.
+    fn list(&mut self) -> anyhow::Result<Vec<bool>> {
+        self.expect('(')?;
+        let mut values = Vec::new();
+        loop {
+            if self.tokens.first() == Some(&CfgToken::Punct(')')) {
+                self.next()?;
+                return Ok(values);
+            }
+            values.push(self.expr()?);
+            match self.next()? {
+                CfgToken::Punct(',') => {}
+                CfgToken::Punct(')') => return Ok(values),
+                token => bail!("expected `,` or `)` in `cfg` expression, found {token:?}"),
+            }
+        }
+    }
+
+    fn expr(&mut self) -> anyhow::Result<bool> {
+        let CfgToken::Ident(name) = self.next()? else {
+            bail!("expected an identifier in `cfg` expression");
+        };
+        let name = name.to_owned();
+        match name.as_str() {
+            "cfg" => {
+                let values = self.list()?;
+                ensure!(values.len() == 1, "`cfg()` takes one predicate");
+                return Ok(values[0]);
+            }
+            "all" => return Ok(self.list()?.into_iter().all(|it| it)),
+            "any" => return Ok(self.list()?.into_iter().any(|it| it)),
+            "not" => {
+                let values = self.list()?;
+                ensure!(values.len() == 1, "`not()` takes one predicate");
+                return Ok(!values[0]);
+            }
+            _ => {}
+        }
+        if self.tokens.first() == Some(&CfgToken::Punct('=')) {
+            self.next()?;
+            let CfgToken::Str(value) = self.next()? else {
+                bail!("expected a string after `{name} =` in `cfg` expression");
+            };
+            let cfg = format!("{name}=\"{value}\"");
+            return Ok(self.cfgs.contains(&cfg));
+        }
+        Ok(self.cfgs.contains(&name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cfgs() -> Vec<String> {
+        [
+            "unix",
+            "debug_assertions",
+            r#"target_os="linux""#,
+            r#"target_arch="x86_64""#,
+        ]
+        .into_iter()
+        .map(Into::into)
+        .collect()
+    }
+
+    fn matches(expr: &str) -> anyhow::Result<bool> {
+        cfg_matches(expr, &cfgs())
+    }
+
+    #[test]
+    fn tokenize() {
+        use CfgToken::*;
+        assert_eq!(
+            tokenize_cfg(r#"cfg(all(unix, target_os = "linux"))"#).unwrap(),
+            [
+                Ident("cfg"),
+                Punct('('),
+                Ident("all"),
+                Punct('('),
+                Ident("unix"),
+                Punct(','),
+                Ident("target_os"),
+                Punct('='),
+                Str("linux"),
+                Punct(')'),
+                Punct(')'),
+            ]
+        );
+    }
+
+    #[test]
+    fn tokenize_quoted_value_with_punctuation() {
+        assert_eq!(
+            tokenize_cfg(r#"feature = "a(b), c=d""#).unwrap(),
+            [
+                CfgToken::Ident("feature"),
+                CfgToken::Punct('='),
+                CfgToken::Str("a(b), c=d"),
+            ]
+        );
+    }
+
+    #[test]
+    fn names_and_values() {
+        assert!(matches("cfg(unix)").unwrap());
+        assert!(!matches("cfg(windows)").unwrap());
+        assert!(matches(r#"cfg(target_os = "linux")"#).unwrap());
+        assert!(matches(r#"cfg(target_os="linux")"#).unwrap());
+        assert!(!matches(r#"cfg(target_os = "macos")"#).unwrap());
+        // A bare name doesn't match a key-value cfg.
+        assert!(!matches("cfg(target_os)").unwrap());
+    }
+
+    #[test]
+    fn all_any_not() {
+        assert!(matches(r#"cfg(all(unix, target_arch = "x86_64"))"#).unwrap());
+        assert!(!matches("cfg(all(unix, windows))").unwrap());
+        assert!(matches("cfg(all())").unwrap());
+        assert!(matches("cfg(any(windows, unix))").unwrap());
+        assert!(!matches("cfg(any(windows, target_os = \"macos\"))").unwrap());
+        assert!(!matches("cfg(any())").unwrap());
+        assert!(matches("cfg(not(windows))").unwrap());
+        assert!(!matches("cfg(not(unix))").unwrap());
+        assert!(matches("cfg(all(unix, not(any(windows, miri)),))").unwrap());
+    }
+
+    #[test]
+    fn errors() {
+        for expr in [
+            "",
+            "cfg(",
+            "cfg(unix",
+            "cfg(unix))",
+            "cfg(unix, windows)",
+            "cfg(not(unix, windows))",
+            "cfg(target_os = linux)",
+            r#"cfg(target_os = "linux)"#,
+            "cfg(unix; windows)",
+            "cfg(all(unix windows))",
+        ] {
+            assert!(matches(expr).is_err(), "{expr}");
+        }
+    }
+}
@@ -0,0 +1,121 @@
+use std::env;
+use std::fmt;
+use std::fmt::Display;
+use std::fmt::Formatter;
+use std::path::PathBuf;
+
+use anyhow::Context;
+
+use crate::util::EnvVar;
+
+/// The `CARGO_*` (and related) env vars that `cargo` sets for `rustc` invocations.
+///
+/// See <https://doc.rust-lang.org/cargo/reference/environment-variables.html#environment-variables-cargo-sets-for-crates>.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CargoEnv {
+    /// `$CARGO_CRATE_NAME`: the name of the crate being compiled.
+    pub crate_name: Option<String>,
+
+    /// `$CARGO_PKG_NAME`: the name of the package.
+    pub pkg_name: Option<String>,
+
+    /// `$CARGO_PKG_VERSION`: the full version of the package.
+    pub pkg_version: Option<String>,
+
+    /// `$CARGO_MANIFEST_DIR`: the directory containing the package's manifest.
+    pub manifest_dir: Option<PathBuf>,
+
+    /// `$CARGO_MANIFEST_PATH`: the path to the package's manifest.
+    pub manifest_path: Option<PathBuf>,
+
+    /// `$CARGO_BIN_NAME`: the name of the binary being compiled, only set for `bin` targets.
+    pub bin_name: Option<String>,
+
+    /// `$CARGO_PRIMARY_PACKAGE`: set if the package was selected on the command line.
+    pub primary_package: bool,
+
+    /// `$OUT_DIR`: the build script's output directory, only set if the package has a build script.
+    pub out_dir: Option<PathBuf>,
+
+    /// `$CARGO_TARGET_TMPDIR`: a scratch directory, only set for integration tests and benches.
+    pub target_tmpdir: Option<PathBuf>,
+}
+
+/// The identity of a package, `{name}@{version}`, like `cargo pkgid` specs.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PackageId {
+    pub name: String,
+    pub version: String,
+}
+
+impl Display for PackageId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let Self { name, version } = self;
+        write!(f, "{name}@{version}")
+    }
+}
+
+impl PackageId {
+    /// Parse a fully qualified package ID from `cargo`'s JSON output,
+    /// either a spec like `registry+https://github.com/rust-lang/crates.io-index#serde@1.0.0`
+    /// (where the `{name}@` is omitted if it's the last path segment),
+    /// or the older `serde 1.0.0 (registry+https://github.com/rust-lang/crates.io-index)`.
+    pub(crate) fn parse_qualified(id: &str) -> Option<Self> {
+        if let Some((name_version, _source)) = id.split_once(" (") {
+            let (name, version) = name_version.split_once(' ')?;
+            return Some(Self {
+                name: name.into(),
+                version: version.into(),
+            });
+        }
+        let (url, fragment) = id.rsplit_once('#')?;
+        let (name, version) = match fragment.split_once('@') {
+            Some((name, version)) => (name, version),
+            None => {
+                let path = url.split(['?', '#']).next()?;
+                (path.trim_end_matches('/').rsplit('/').next()?, fragment)
+            }
+        };
+        Some(Self {
+            name: name.into(),
+            version: version.into(),
+        })
+    }
+}
+
+fn var(key: &'static str) -> anyhow::Result<Option<String>> {
+    match EnvVar::get(key) {
+        Ok(var) => Ok(Some(var.value)),
+        Err(env::VarError::NotPresent) => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("invalid `${key}`")),
+    }
+}
+
+fn path_var(key: &'static str) -> Option<PathBuf> {
+    EnvVar::get_path(key).map(|var| var.value)
+}
+
+impl CargoEnv {
+    /// Read the `CARGO_*` env vars of the current process.
+    pub fn from_env() -> anyhow::Result<Self> {
+        Ok(Self {
+            crate_name: var("CARGO_CRATE_NAME")?,
+            pkg_name: var("CARGO_PKG_NAME")?,
+            pkg_version: var("CARGO_PKG_VERSION")?,
+            manifest_dir: path_var("CARGO_MANIFEST_DIR"),
+            manifest_path: path_var("CARGO_MANIFEST_PATH"),
+            bin_name: var("CARGO_BIN_NAME")?,
+            primary_package: EnvVar::get_os("CARGO_PRIMARY_PACKAGE").is_some(),
+            out_dir: path_var("OUT_DIR"),
+            target_tmpdir: path_var("CARGO_TARGET_TMPDIR"),
+        })
+    }
+
+    /// The [`PackageId`] from `$CARGO_PKG_NAME` and `$CARGO_PKG_VERSION`.
+    pub fn package_id(&self) -> Option<PackageId> {
+        Some(PackageId {
+            name: self.pkg_name.clone()?,
+            version: self.pkg_version.clone()?,
+        })
+    }
+}
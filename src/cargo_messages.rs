@@ -0,0 +1,177 @@
+//! `cargo`'s `--message-format=json` messages.
+//!
+//! See <https://doc.rust-lang.org/cargo/reference/external-tools.html#json-messages>.
+
+use std::io;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::Command;
+use std::process::ExitStatus;
+use std::process::Stdio;
+
+use anyhow::Context;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Map;
+use serde_json::Value;
+
+use crate::diagnostics::Diagnostic;
+use crate::signals;
+
+/// A JSON message from `cargo`, one per line of its stdout.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "reason", rename_all = "kebab-case")]
+pub enum CargoMessage {
+    CompilerArtifact(Artifact),
+    CompilerMessage(CompilerMessage),
+    BuildScriptExecuted(BuildScript),
+    BuildFinished(BuildFinished),
+    /// A message emitted by the `rustc` wrapper with [`crate::RustcWrapper::emit_message`],
+    /// or any other message with a `reason` not known here.
+    #[serde(untagged)]
+    Custom(CustomMessage),
+}
+
+/// The `target` of a [`CargoMessage`], like a lib, bin, test, or build script.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ArtifactTarget {
+    pub name: String,
+    /// Like `lib`, `bin`, `test`, or `custom-build`.
+    pub kind: Vec<String>,
+    pub crate_types: Vec<String>,
+    pub src_path: PathBuf,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// The profile settings an [`Artifact`] was built with.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ArtifactProfile {
+    /// Whether it was built with `--test`, i.e. is a test binary.
+    pub test: bool,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// A compiled artifact (`compiler-artifact`).
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Artifact {
+    pub package_id: String,
+    pub manifest_path: PathBuf,
+    pub target: ArtifactTarget,
+    pub profile: ArtifactProfile,
+    pub features: Vec<String>,
+    pub filenames: Vec<PathBuf>,
+    pub executable: Option<PathBuf>,
+    /// If it was up to date and not rebuilt.
+    pub fresh: bool,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// A `rustc` diagnostic (`compiler-message`).
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct CompilerMessage {
+    pub package_id: String,
+    pub manifest_path: PathBuf,
+    pub target: ArtifactTarget,
+    pub message: Diagnostic,
+}
+
+/// A build script's output (`build-script-executed`).
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct BuildScript {
+    pub package_id: String,
+    pub linked_libs: Vec<String>,
+    pub linked_paths: Vec<String>,
+    pub cfgs: Vec<String>,
+    pub env: Vec<(String, String)>,
+    pub out_dir: PathBuf,
+}
+
+/// The end of the build (`build-finished`).
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct BuildFinished {
+    pub success: bool,
+}
+
+/// A tool-specific message interleaved into `cargo`'s JSON messages
+/// by the `rustc` wrapper with [`crate::RustcWrapper::emit_message`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CustomMessage {
+    /// Should be namespaced, like `c2rust-instrument:instrumented`, so as to not clash with `cargo`'s.
+    pub reason: String,
+    /// The `{name}@{version}` of the package of the crate being compiled.
+    #[serde(default)]
+    pub package_id: Option<String>,
+    #[serde(default)]
+    pub crate_name: Option<String>,
+    #[serde(default)]
+    pub data: Value,
+}
+
+/// Run `cmd` (a `cargo` invocation with `--message-format=json*`), parsing its stdout messages.
+///
+/// Each message is passed to `on_message`,
+/// and [`CargoMessage::CompilerMessage`]s are then rendered to stderr.
+/// Anything else on stdout (like from `cargo run`) is passed through as is.
+pub(crate) fn run_streaming(
+    cmd: &mut Command,
+    mut on_message: impl FnMut(&CargoMessage) -> anyhow::Result<()>,
+) -> anyhow::Result<ExitStatus> {
+    let (mut child, _forward) =
+        signals::spawn(cmd.stdout(Stdio::piped())).with_context(|| format!("spawn {cmd:?}"))?;
+    let stdout = child.stdout.take().expect("stdout was piped");
+    let mut stdout = BufReader::new(stdout);
+    let mut line = Vec::new();
+    let result = loop {
+        line.clear();
+        match stdout.read_until(b'\n', &mut line) {
+            Ok(0) => break Ok(()),
+            Ok(_) => {}
+            Err(e) => break Err(anyhow::Error::new(e).context("read `cargo` stdout")),
+        }
+        let message = match line.first() {
+            Some(b'{') => serde_json::from_slice::<CargoMessage>(&line).ok(),
+            _ => None,
+        };
+        let Some(message) = message else {
+            if let Err(e) = io::stdout().write_all(&line) {
+                break Err(anyhow::Error::new(e).context("echo `cargo` stdout"));
+            }
+            continue;
+        };
+        if let Err(e) = on_message(&message) {
+            break Err(e);
+        }
+        if let CargoMessage::CompilerMessage(CompilerMessage { message, .. }) = &message {
+            let rendered = match &message.rendered {
+                Some(rendered) => rendered.clone(),
+                None => message.render_simple(),
+            };
+            if let Err(e) = io::stderr().write_all(rendered.as_bytes()) {
+                break Err(anyhow::Error::new(e).context("render `cargo` diagnostic"));
+            }
+        }
+    };
+    if result.is_err() {
+        // Don't leave `cargo` blocked on a full pipe.
+        let _ = child.kill();
+    }
+    // Report the failure that made us kill `cargo` over any from waiting.
+    let status = signals::wait(&mut child);
+    result?;
+    Ok(status?)
+}
+
+/// Write `message` as a line to stdout, which `cargo` passes through to its own stdout,
+/// interleaving it into its JSON messages.
+pub(crate) fn emit(message: &CustomMessage) -> anyhow::Result<()> {
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    // Write it all at once so it isn't interleaved with other output.
+    io::stdout().lock().write_all(&line)?;
+    Ok(())
+}
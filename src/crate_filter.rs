@@ -0,0 +1,74 @@
+use crate::cargo_env::CargoEnv;
+use crate::util::glob_match;
+
+/// A filter restricting which crates a `rustc` wrapper should wrap,
+/// set in the `cargo` phase (see [`crate::CargoWrapper::set_crate_filter`])
+/// and checked in the `rustc` phase (see [`crate::RustcWrapper::matches_crate_filter`]).
+///
+/// Patterns are globs (supporting `*` and `?`) matched against the crate name,
+/// the package name, and the package id (`{name}@{version}`).
+/// A crate matches if it matches any allowed pattern (or there are none)
+/// and doesn't match any blocked pattern.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CrateFilter {
+    allow: Vec<String>,
+    block: Vec<String>,
+}
+
+impl CrateFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only allow crates matching `pattern` (or any other allowed pattern).
+    pub fn allow(mut self, pattern: impl Into<String>) -> Self {
+        self.allow.push(pattern.into());
+        self
+    }
+
+    /// Block crates matching `pattern`, even if they're allowed.
+    pub fn block(mut self, pattern: impl Into<String>) -> Self {
+        self.block.push(pattern.into());
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.block.is_empty()
+    }
+
+    /// Whether the crate described by `cargo_env` matches the filter.
+    pub fn matches(&self, cargo_env: &CargoEnv) -> bool {
+        let pkg_id = cargo_env.package_id().map(|id| id.to_string());
+        let names = [
+            cargo_env.crate_name.as_deref(),
+            cargo_env.pkg_name.as_deref(),
+            pkg_id.as_deref(),
+        ];
+        let matches_any = |patterns: &[String]| {
+            patterns
+                .iter()
+                .any(|pattern| names.iter().flatten().any(|name| glob_match(pattern, name)))
+        };
+        (self.allow.is_empty() || matches_any(&self.allow)) && !matches_any(&self.block)
+    }
+
+    /// Serialize to pass from the `cargo` phase to the `rustc` phase in an env var.
+    pub(crate) fn to_env_value(&self) -> String {
+        let allow = self.allow.iter().map(|pattern| format!("+{pattern}"));
+        let block = self.block.iter().map(|pattern| format!("-{pattern}"));
+        allow.chain(block).collect::<Vec<_>>().join("\n")
+    }
+
+    /// The inverse of [`Self::to_env_value`].
+    pub(crate) fn from_env_value(value: &str) -> Self {
+        let mut filter = Self::default();
+        for line in value.lines() {
+            if let Some(pattern) = line.strip_prefix('+') {
+                filter.allow.push(pattern.to_owned());
+            } else if let Some(pattern) = line.strip_prefix('-') {
+                filter.block.push(pattern.to_owned());
+            }
+        }
+        filter
+    }
+}
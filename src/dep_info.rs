@@ -0,0 +1,69 @@
+//! Parsing `rustc`'s Makefile-style dep-info (`.d`) files.
+
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::Context;
+
+/// Split a space-separated list of dep-info paths, unescaping `\ ` into ` `.
+///
+/// Other backslashes are kept as is, since they're path separators on Windows.
+fn split_paths(s: &str) -> impl Iterator<Item = String> + '_ {
+    let mut chars = s.chars().peekable();
+    std::iter::from_fn(move || {
+        while chars.next_if_eq(&' ').is_some() {}
+        chars.peek()?;
+        let mut path = String::new();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' if chars.peek() == Some(&' ') => path.push(chars.next()?),
+                ' ' => break,
+                c => path.push(c),
+            }
+        }
+        Some(path)
+    })
+}
+
+/// Find the `:` separating a rule's targets from its deps,
+/// skipping escaped spaces and Windows drive letters like `C:\`.
+fn find_rule_separator(line: &str) -> Option<usize> {
+    line.match_indices(':')
+        .map(|(i, _)| i)
+        .find(|&i| matches!(line[i + 1..].chars().next(), None | Some(' ')))
+}
+
+/// Parse the contents of a dep-info file, returning all the input paths in order, deduplicated.
+pub fn parse_dep_info(contents: &str) -> Vec<PathBuf> {
+    let mut inputs = Vec::<PathBuf>::new();
+    let mut logical_line = String::new();
+    for line in contents.lines() {
+        // Not emitted by `rustc`, but valid Makefile syntax.
+        if let Some(line) = line.strip_suffix('\\').filter(|line| !line.ends_with('\\')) {
+            logical_line.push_str(line);
+            logical_line.push(' ');
+            continue;
+        }
+        logical_line.push_str(line);
+        let line = std::mem::take(&mut logical_line);
+        if line.starts_with('#') {
+            continue;
+        }
+        let Some(i) = find_rule_separator(&line) else {
+            continue;
+        };
+        for path in split_paths(&line[i + 1..]) {
+            let path = PathBuf::from(path);
+            if !inputs.contains(&path) {
+                inputs.push(path);
+            }
+        }
+    }
+    inputs
+}
+
+/// Read and parse the dep-info file at `path`, returning all the input paths.
+pub fn read_dep_info(path: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let contents = fs_err::read_to_string(path).context("read dep-info file")?;
+    Ok(parse_dep_info(&contents))
+}
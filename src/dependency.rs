@@ -0,0 +1,120 @@
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+
+use anyhow::anyhow;
+use anyhow::bail;
+
+use crate::cargo_args::LockfilePolicy;
+
+/// Where to get a dependency injected with [`crate::CargoWrapper::add_runtime_dependency`] from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DependencySource {
+    /// The latest version from crates.io.
+    Latest,
+    /// A version requirement on crates.io, like `0.1`.
+    Version(String),
+    /// A local crate, which doesn't need network access.
+    Path(PathBuf),
+}
+
+/// Add the args to `cargo add` dependency `name` from `source`, following the lockfile `policy`.
+pub(crate) fn add_dependency_args(
+    cmd: &mut Command,
+    name: &str,
+    source: &DependencySource,
+    optional: bool,
+    features: &[&str],
+    policy: LockfilePolicy,
+) -> anyhow::Result<()> {
+    let policy = match source {
+        DependencySource::Path(_) => policy.offline(),
+        DependencySource::Latest | DependencySource::Version(_) => policy,
+    };
+    cmd.args(policy.cargo_flag());
+    match source {
+        DependencySource::Latest => {
+            cmd.arg(name);
+        }
+        DependencySource::Version(version) => {
+            cmd.arg(format!("{name}@{version}"));
+        }
+        DependencySource::Path(path) => {
+            // `cargo add` may run on a different `--manifest-path`, so don't depend on the current dir.
+            let path = fs_err::canonicalize(path)?;
+            cmd.arg(name).arg("--path").arg(path);
+        }
+    }
+    if optional {
+        cmd.arg("--optional");
+    }
+    if !features.is_empty() {
+        cmd.args(["--features", &features.join(",")]);
+    }
+    Ok(())
+}
+
+/// The `--config` value patching dependency `name` from `registry` (like `crates-io`) with `source`.
+///
+/// A patch must point to a different source, so `registry` can't be patched with a
+/// [`DependencySource::Version`] (which is from crates.io) if it's crates.io itself.
+pub(crate) fn patch_config(
+    registry: &str,
+    name: &str,
+    source: &DependencySource,
+) -> anyhow::Result<String> {
+    let (key, value) = match source {
+        DependencySource::Latest => bail!("can't patch `{name}` with the latest version of itself"),
+        DependencySource::Version(version) if registry == "crates-io" => bail!(
+            "can't patch `{name}` from crates.io with version `{version}` from crates.io, \
+            patches must point to a different source, like a path"
+        ),
+        DependencySource::Version(version) => ("version", version.clone()),
+        DependencySource::Path(path) => {
+            let path = fs_err::canonicalize(path)?;
+            let path = path.into_os_string().into_string().map_err(|path| {
+                anyhow!("non-UTF-8 path can't be put in `cargo` config: {path:?}")
+            })?;
+            ("path", path)
+        }
+    };
+    let key = [registry, name, key].map(|part| toml_edit::Key::new(part).to_string());
+    let value = toml_edit::Value::from(value);
+    Ok(format!("patch.{}={value}", key.join(".")))
+}
+
+/// The original contents of a file (or that it didn't exist), to restore after modifying it.
+pub(crate) struct FileBackup {
+    path: PathBuf,
+    contents: Option<Vec<u8>>,
+}
+
+impl FileBackup {
+    pub fn new(path: &Path) -> anyhow::Result<Self> {
+        let contents = match fs_err::read(path) {
+            Ok(contents) => Some(contents),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            path: path.to_owned(),
+            contents,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn restore(self) -> anyhow::Result<()> {
+        match &self.contents {
+            Some(contents) => fs_err::write(&self.path, contents)?,
+            None => match fs_err::remove_file(&self.path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            },
+        }
+        Ok(())
+    }
+}
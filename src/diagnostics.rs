@@ -0,0 +1,242 @@
+//! `rustc`'s `--error-format=json` diagnostics.
+//!
+//! See <https://doc.rust-lang.org/rustc/json.html>.
+
+use std::io;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Write;
+use std::process::Command;
+use std::process::ExitStatus;
+use std::process::Stdio;
+use std::str;
+use std::time::Duration;
+
+use anyhow::Context;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Map;
+use serde_json::Value;
+
+use crate::ice::IceReport;
+use crate::signals;
+use crate::signals::Waiter;
+
+/// A `rustc` JSON diagnostic.
+///
+/// Unknown fields are kept in [`Self::extra`], so that it round trips losslessly.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Diagnostic {
+    pub message: String,
+    pub code: Option<DiagnosticCode>,
+    /// `error`, `warning`, `note`, `help`, `failure-note`, or `error: internal compiler error`.
+    pub level: String,
+    pub spans: Vec<DiagnosticSpan>,
+    pub children: Vec<Diagnostic>,
+    /// The diagnostic as `rustc` would render it to a terminal.
+    pub rendered: Option<String>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DiagnosticCode {
+    /// Like `E0308` or `unused_variables`.
+    pub code: String,
+    pub explanation: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DiagnosticSpan {
+    pub file_name: String,
+    pub byte_start: u32,
+    pub byte_end: u32,
+    /// 1-based.
+    pub line_start: usize,
+    pub line_end: usize,
+    /// 1-based, in chars.
+    pub column_start: usize,
+    pub column_end: usize,
+    pub is_primary: bool,
+    pub label: Option<String>,
+    pub suggested_replacement: Option<String>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl Diagnostic {
+    /// Parse a line of `rustc`'s stderr as a diagnostic,
+    /// or [`None`] if it's something else (like an artifact notification or plain text).
+    pub fn parse_line(line: &str) -> Option<Self> {
+        if !line.starts_with('{') {
+            return None;
+        }
+        let value = serde_json::from_str::<Value>(line).ok()?;
+        match value.get("$message_type").and_then(Value::as_str) {
+            None | Some("diagnostic") => {}
+            Some(_) => return None,
+        }
+        serde_json::from_value(value).ok()
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.level.starts_with("error")
+    }
+
+    pub fn is_warning(&self) -> bool {
+        self.level == "warning"
+    }
+
+    /// Apply `f` to all spans, including those of children,
+    /// e.g. to remap spans in instrumented sources back to the originals.
+    pub fn for_each_span_mut(&mut self, f: &mut impl FnMut(&mut DiagnosticSpan)) {
+        self.spans.iter_mut().for_each(&mut *f);
+        for child in &mut self.children {
+            child.for_each_span_mut(f);
+        }
+    }
+
+    /// Only keep the children (notes, helps, etc.) for which `f` returns `true`.
+    pub fn retain_children(&mut self, f: impl FnMut(&Diagnostic) -> bool) {
+        self.children.retain(f);
+    }
+
+    /// Render the diagnostic in a simplified version of `rustc`'s human-readable format,
+    /// without source snippets.
+    ///
+    /// This is used in place of [`Self::rendered`] when a diagnostic is modified,
+    /// as `rustc`'s rendering would then be stale.
+    pub fn render_simple(&self) -> String {
+        let mut rendered = String::new();
+        self.render_simple_into(&mut rendered, "");
+        rendered
+    }
+
+    fn render_simple_into(&self, out: &mut String, indent: &str) {
+        use std::fmt::Write;
+
+        let Self {
+            level,
+            message,
+            code,
+            ..
+        } = self;
+        let _ = match code {
+            Some(DiagnosticCode { code, .. }) => {
+                writeln!(out, "{indent}{level}[{code}]: {message}")
+            }
+            None => writeln!(out, "{indent}{level}: {message}"),
+        };
+        for span in self.spans.iter().filter(|span| span.is_primary) {
+            let DiagnosticSpan {
+                file_name,
+                line_start,
+                column_start,
+                ..
+            } = span;
+            let _ = writeln!(out, "{indent} --> {file_name}:{line_start}:{column_start}");
+        }
+        for child in &self.children {
+            child.render_simple_into(out, &format!("{indent}  = "));
+        }
+    }
+}
+
+/// The result of running `rustc` with its diagnostics captured.
+#[derive(Debug)]
+pub struct CapturedRustc {
+    pub status: ExitStatus,
+    pub diagnostics: Vec<Diagnostic>,
+    /// The report if `rustc` ICEd, when collecting them with [`crate::CargoWrapper::set_ice_dir`].
+    pub ice: Option<IceReport>,
+}
+
+impl CapturedRustc {
+    pub fn error_count(&self) -> usize {
+        self.diagnostics
+            .iter()
+            .filter(|diag| diag.is_error())
+            .count()
+    }
+
+    pub fn warning_count(&self) -> usize {
+        self.diagnostics
+            .iter()
+            .filter(|diag| diag.is_warning())
+            .count()
+    }
+}
+
+/// Run `cmd` (a `rustc` invocation with `--error-format=json`), parsing its stderr diagnostics.
+///
+/// Each diagnostic is passed through `transform` (dropped if it returns [`None`]),
+/// with [`Diagnostic::rendered`] replaced by [`Diagnostic::render_simple`] if it was modified,
+/// and then either re-emitted as JSON
+/// (if `emit_json`, as `cargo` expects), or rendered for a terminal.
+/// Anything else on stderr is passed through as is.
+///
+/// If `cmd` is still running after `timeout`, it's killed.
+pub(crate) fn run_captured(
+    cmd: &mut Command,
+    emit_json: bool,
+    timeout: Option<Duration>,
+    mut transform: impl FnMut(Diagnostic) -> Option<Diagnostic>,
+) -> anyhow::Result<CapturedRustc> {
+    let (mut child, _forward) =
+        signals::spawn(cmd.stderr(Stdio::piped())).with_context(|| format!("spawn {cmd:?}"))?;
+    let stderr = child.stderr.take().expect("stderr was piped");
+    // Reading ends once it exits, even if it's killed.
+    let mut child = Waiter::new(child, timeout);
+    let mut stderr = BufReader::new(stderr);
+    let mut diagnostics = Vec::new();
+    let mut out = io::stderr().lock();
+    let mut line = Vec::new();
+    let mut pass_through = || -> anyhow::Result<()> {
+        loop {
+            line.clear();
+            if stderr
+                .read_until(b'\n', &mut line)
+                .context("read `rustc` stderr")?
+                == 0
+            {
+                return Ok(());
+            }
+            // Non-UTF-8 output (e.g. from paths) can't be a diagnostic, but is still passed through.
+            let diagnostic = str::from_utf8(&line)
+                .ok()
+                .and_then(|line| Diagnostic::parse_line(line.trim_end()));
+            let Some(diagnostic) = diagnostic else {
+                out.write_all(&line)?;
+                continue;
+            };
+            let original = diagnostic.clone();
+            let Some(mut diagnostic) = transform(diagnostic) else {
+                continue;
+            };
+            if diagnostic != original && diagnostic.rendered == original.rendered {
+                diagnostic.rendered = Some(diagnostic.render_simple());
+            }
+            if emit_json {
+                let json = serde_json::to_string(&diagnostic)?;
+                writeln!(out, "{json}")?;
+            } else if let Some(rendered) = &diagnostic.rendered {
+                write!(out, "{rendered}")?;
+            }
+            diagnostics.push(diagnostic);
+        }
+    };
+    let result = pass_through();
+    // Don't leave `rustc` running (blocked on a full pipe) or unreaped if passing through failed,
+    // but report that failure over any from waiting.
+    if result.is_err() {
+        child.kill();
+    }
+    let status = child.wait();
+    result?;
+    let status = status?;
+    Ok(CapturedRustc {
+        status,
+        diagnostics,
+        ice: None,
+    })
+}
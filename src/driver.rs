@@ -0,0 +1,53 @@
+//! In-process `rustc_driver` execution, behind the `rustc-driver` feature.
+//!
+//! This requires a nightly toolchain with the `rustc-dev` component.
+
+extern crate rustc_driver;
+
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::process::ExitCode;
+
+use crate::ice::ICE_EXIT_CODE;
+use crate::util::EnvGuard;
+use crate::CommandFailed;
+use crate::RustcWrapper;
+
+pub use rustc_driver::Callbacks;
+
+impl RustcWrapper {
+    /// Run `rustc` in-process through `rustc_driver` with `callbacks`,
+    /// instead of spawning the real `rustc`.
+    ///
+    /// The args are prepared like [`Self::rustc_args`] (with `--sysroot` and arg transforms),
+    /// and a failing compilation fails with [`CommandFailed`] with `rustc`'s exit code
+    /// (101 if it ICEd), like [`Self::run_rustc`].
+    ///
+    /// The env vars from [`Self::set_env`] are set in our own env while compiling
+    /// (for `env!` and `$RUSTC_BOOTSTRAP`), and then restored.
+    pub fn run_in_process(self, callbacks: &mut (dyn Callbacks + Send)) -> anyhow::Result<()> {
+        let verbosity = self.verbosity();
+        let _env = EnvGuard::set_all(self.envs.clone());
+        let args = self.rustc_args()?;
+        // An ICE is a panic that `catch_with_exit_code` resumes,
+        // which would exit `rustc` with the default panic exit code.
+        let exit_code = panic::catch_unwind(AssertUnwindSafe(|| {
+            rustc_driver::catch_with_exit_code(|| {
+                rustc_driver::run_compiler(&args, callbacks);
+            })
+        }))
+        .map_or(ICE_EXIT_CODE, exit_code_to_i32);
+        if exit_code != rustc_driver::EXIT_SUCCESS {
+            let command = format!("{args:?} (in-process)");
+            return Err(CommandFailed::with_exit_code(command, exit_code, verbosity).into());
+        }
+        Ok(())
+    }
+}
+
+/// The code of an [`ExitCode`], which is opaque, but can only be made from a [`u8`] portably.
+fn exit_code_to_i32(exit_code: ExitCode) -> i32 {
+    (0..=u8::MAX)
+        .find(|&code| ExitCode::from(code) == exit_code)
+        .map_or(rustc_driver::EXIT_FAILURE, i32::from)
+}
@@ -0,0 +1,22 @@
+//! Putting the sysroot's dylibs (`librustc_driver`, `libLLVM`) on the dynamic loader's path,
+//! which tools linking `rustc_private` crates need to even start.
+
+use std::path::Path;
+use std::path::PathBuf;
+
+/// The env var the dynamic loader searches for dylibs on this platform.
+pub fn dylib_path_var() -> &'static str {
+    if cfg!(windows) {
+        "PATH"
+    } else if cfg!(target_os = "macos") {
+        "DYLD_FALLBACK_LIBRARY_PATH"
+    } else {
+        "LD_LIBRARY_PATH"
+    }
+}
+
+/// The directory in `sysroot` containing its dylibs.
+pub fn sysroot_dylib_dir(sysroot: &Path) -> PathBuf {
+    // Windows looks for DLLs next to executables (and on `$PATH`), so they're in `bin`.
+    sysroot.join(if cfg!(windows) { "bin" } else { "lib" })
+}
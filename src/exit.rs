@@ -0,0 +1,359 @@
+//! Failed commands, returned as errors so the application decides when to exit.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::error::Error;
+use std::ffi::OsString;
+use std::fmt;
+use std::fmt::Display;
+use std::fmt::Formatter;
+use std::io::Write;
+use std::path::Path;
+use std::process;
+use std::process::Command;
+use std::process::ExitStatus;
+use std::process::Output;
+
+use anyhow::ensure;
+use anyhow::Context;
+
+use crate::cargo_args::Verbosity;
+use crate::util::shell_command_line;
+use crate::util::shell_command_line_inheriting;
+
+/// A command run by the wrapper (like `cargo`, `rustc`, or a test binary) that failed.
+///
+/// This is returned (in an [`anyhow::Error`]) instead of exiting right away,
+/// so that destructors (like for temp files and manifest backups) still run.
+/// The application should then exit with the same status, like with [`exit_with`].
+#[derive(Debug, Clone)]
+pub struct CommandFailed {
+    command: String,
+    status: ExitStatus,
+    verbosity: Verbosity,
+}
+
+impl CommandFailed {
+    pub(crate) fn new(cmd: &Command, status: ExitStatus, verbosity: Verbosity) -> Self {
+        Self {
+            command: shell_command_line(cmd),
+            status,
+            verbosity,
+        }
+    }
+
+    /// Like [`Self::new`], but for a `rustc` or `rustdoc` run by the wrapper,
+    /// also including the working directory and env vars (see [`cargo_env_delta`])
+    /// it inherits from `cargo`, so that it can be re-run outside of `cargo` and the wrapper.
+    fn inheriting_cargo_env(cmd: &Command, status: ExitStatus, verbosity: Verbosity) -> Self {
+        let dir = env::current_dir().ok();
+        Self {
+            command: shell_command_line_inheriting(cmd, dir.as_deref(), &cargo_env_delta()),
+            status,
+            verbosity,
+        }
+    }
+
+    /// For something that failed with exit `code` without running a [`Command`],
+    /// like `rustc` run in-process.
+    #[cfg_attr(not(feature = "rustc-driver"), allow(dead_code))]
+    pub(crate) fn with_exit_code(command: String, code: i32, verbosity: Verbosity) -> Self {
+        #[cfg(unix)]
+        let status = {
+            use std::os::unix::process::ExitStatusExt;
+            ExitStatus::from_raw(code << 8)
+        };
+        #[cfg(windows)]
+        let status = {
+            use std::os::windows::process::ExitStatusExt;
+            ExitStatus::from_raw(code as u32)
+        };
+        Self {
+            command,
+            status,
+            verbosity,
+        }
+    }
+
+    /// The failing command, as a copy-pastable POSIX shell command line
+    /// with its working directory and the env vars it was run with (besides what it inherited).
+    pub fn command(&self) -> &str {
+        &self.command
+    }
+
+    pub fn status(&self) -> ExitStatus {
+        self.status
+    }
+
+    /// The signal that killed the command, if it was killed by one (only on Unix).
+    pub fn signal(&self) -> Option<i32> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::ExitStatusExt;
+            self.status.signal()
+        }
+        #[cfg(not(unix))]
+        None
+    }
+
+    /// The code to exit with to propagate [`Self::status`],
+    /// which is `128 + {signal}` if it was killed by a signal, like shells report.
+    pub fn exit_code(&self) -> i32 {
+        match self.signal() {
+            Some(signal) => 128 + signal,
+            None => self.status.code().unwrap_or(1),
+        }
+    }
+
+    /// Append the status and then the command to `log`,
+    /// in one write so that concurrent `rustc` wrappers don't interleave.
+    fn append_to_log(&self, log: &Path) -> anyhow::Result<()> {
+        let entry = format!("# {}\n{}\n\n", self.status, self.command);
+        let mut log = fs_err::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log)?;
+        log.write_all(entry.as_bytes())?;
+        Ok(())
+    }
+}
+
+/// The env vars `cargo` set for `rustc` (and `rustdoc`), which it needs to be re-run by itself,
+/// like `$CARGO_PKG_NAME` and `$OUT_DIR`.
+///
+/// The wrapper's own vars and `$CARGO_MAKEFLAGS` (for `cargo`'s jobserver) are left out,
+/// as they don't apply outside of `cargo`.
+fn cargo_env_delta() -> Vec<(OsString, OsString)> {
+    env::vars_os()
+        .filter(|(key, _)| {
+            let Some(key) = key.to_str() else {
+                return false;
+            };
+            let from_cargo =
+                key.starts_with("CARGO") || matches!(key, "OUT_DIR" | "RUSTC_BOOTSTRAP");
+            from_cargo && !key.starts_with("CARGO_RUSTC_WRAPPER_") && key != "CARGO_MAKEFLAGS"
+        })
+        .collect()
+}
+
+impl Display for CommandFailed {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let Self {
+            command, status, ..
+        } = self;
+        write!(f, "error ({status}) running: {command}")
+    }
+}
+
+impl Error for CommandFailed {}
+
+/// Fail with [`CommandFailed`] unless `status` (of `cmd`) is successful,
+/// also appending it to the `failure_log` if there is one
+/// (see [`crate::CargoWrapper::set_failure_log`]).
+pub(crate) fn check_status(
+    cmd: &Command,
+    status: ExitStatus,
+    verbosity: Verbosity,
+    failure_log: Option<&Path>,
+) -> anyhow::Result<()> {
+    if status.success() {
+        return Ok(());
+    }
+    fail(CommandFailed::new(cmd, status, verbosity), failure_log)
+}
+
+/// Like [`check_status`], but for a `rustc` or `rustdoc` run by the wrapper,
+/// see [`CommandFailed::inheriting_cargo_env`].
+pub(crate) fn check_compiler_status(
+    cmd: &Command,
+    status: ExitStatus,
+    verbosity: Verbosity,
+    failure_log: Option<&Path>,
+) -> anyhow::Result<()> {
+    if status.success() {
+        return Ok(());
+    }
+    fail(
+        CommandFailed::inheriting_cargo_env(cmd, status, verbosity),
+        failure_log,
+    )
+}
+
+/// Run `cmd`, capturing its output, and fail with its stderr if it fails,
+/// for queries (like `rustc --print cfg`) whose output is needed, unlike with [`check_status`].
+pub(crate) fn checked_output(cmd: &mut Command) -> anyhow::Result<Output> {
+    let output = cmd
+        .output()
+        .with_context(|| format!("could not run {cmd:?}"))?;
+    check_output(cmd, output)
+}
+
+/// Like [`checked_output`], but for the `output` of `cmd` that's already been run.
+pub(crate) fn check_output(cmd: &Command, output: Output) -> anyhow::Result<Output> {
+    ensure!(
+        output.status.success(),
+        "{cmd:?} failed ({}): {}",
+        output.status,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    Ok(output)
+}
+
+fn fail(failed: CommandFailed, failure_log: Option<&Path>) -> anyhow::Result<()> {
+    // Still fail with the command's status if logging it fails.
+    if let Some(log) = failure_log {
+        if let Err(e) = failed.append_to_log(log) {
+            eprintln!(
+                "warning: could not append to failure log {}: {e:#}",
+                log.display()
+            );
+        }
+    }
+    Err(failed.into())
+}
+
+/// A kind of failure of the wrapper itself, rather than of a command it ran,
+/// like a misconfigured toolchain, attached as context to the [`anyhow::Error`],
+/// so that it can be given its own exit code with [`ExitCodes`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[non_exhaustive]
+pub enum FailureKind {
+    /// The sysroot couldn't be resolved, or has no `std` for a target being built.
+    Sysroot,
+    /// `rustc` or `cargo` isn't a version (or commit) the application needs or supports.
+    Toolchain,
+    /// Configuration like `rust-toolchain.toml` or `cargo` config couldn't be read or is invalid.
+    Config,
+}
+
+impl Display for FailureKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            Self::Sysroot => "could not resolve the sysroot",
+            Self::Toolchain => "incompatible toolchain",
+            Self::Config => "invalid configuration",
+        };
+        f.write_str(message)
+    }
+}
+
+/// The exit codes for [`ExitCodes::exit_with`],
+/// so that CI can tell failures of the wrapper itself (see [`FailureKind`])
+/// and of the application apart from a command (like `rustc`) failing.
+///
+/// These only take effect for the `cargo` wrapper,
+/// as `cargo` exits with its own code whenever the `rustc` wrapper fails.
+#[derive(Debug, Clone)]
+pub struct ExitCodes {
+    kinds: BTreeMap<FailureKind, i32>,
+    errors: Vec<(IsError, i32)>,
+    default: i32,
+}
+
+/// Whether an error is one passed to [`ExitCodes::set_for`].
+type IsError = fn(&anyhow::Error) -> bool;
+
+impl Default for ExitCodes {
+    fn default() -> Self {
+        Self {
+            kinds: BTreeMap::new(),
+            errors: Vec::new(),
+            default: 1,
+        }
+    }
+}
+
+impl ExitCodes {
+    /// Exit with `code` for failures of `kind`.
+    pub fn set(&mut self, kind: FailureKind, code: i32) {
+        self.kinds.insert(kind, code);
+    }
+
+    /// Exit with `code` for errors with an `E` (as the error or as context),
+    /// like the application's own error type for failing to finalize its output.
+    ///
+    /// These are checked in the order they're added, before [`FailureKind`]s.
+    pub fn set_for<E>(&mut self, code: i32)
+    where
+        E: Display + fmt::Debug + Send + Sync + 'static,
+    {
+        self.errors
+            .push((|e| e.downcast_ref::<E>().is_some(), code));
+    }
+
+    /// Exit with `code` for any other error, instead of 1.
+    pub fn set_default(&mut self, code: i32) {
+        self.default = code;
+    }
+
+    /// The code to exit with for `e`.
+    ///
+    /// A [`CommandFailed`] that isn't also a [`FailureKind`] or one of [`Self::set_for`]'s errors
+    /// exits with [`CommandFailed::exit_code`].
+    pub fn exit_code(&self, e: &anyhow::Error) -> i32 {
+        if let Some((_, code)) = self.errors.iter().find(|(is, _)| is(e)) {
+            return *code;
+        }
+        if let Some(code) = e
+            .downcast_ref::<FailureKind>()
+            .and_then(|kind| self.kinds.get(kind))
+        {
+            return *code;
+        }
+        match command_failed(e) {
+            Some(failed) => failed.exit_code(),
+            None => self.default,
+        }
+    }
+
+    /// Like [`exit_with`], but with these exit codes.
+    pub fn exit_with(&self, result: anyhow::Result<()>) -> ! {
+        let Err(e) = result else {
+            process::exit(0);
+        };
+        let code = self.exit_code(&e);
+        match command_failed(&e) {
+            Some(failed) if code == failed.exit_code() => {
+                if failed.verbosity > Verbosity::Quiet {
+                    eprintln!("{e:#}");
+                }
+                if let Some(signal) = failed.signal() {
+                    raise(signal);
+                }
+            }
+            _ => eprintln!("Error: {e:?}"),
+        }
+        process::exit(code)
+    }
+}
+
+fn command_failed(e: &anyhow::Error) -> Option<&CommandFailed> {
+    e.chain().find_map(|e| e.downcast_ref::<CommandFailed>())
+}
+
+/// Exit like returning `result` from `main` would, printing the error if there is one,
+/// except that if a command failed ([`CommandFailed`]), exit with its status instead,
+/// so that `cargo` (or whatever ran the wrapper) sees the same failure.
+///
+/// If the command was killed by a signal, that signal is re-raised (with its default handler)
+/// so that we're killed by it too, or else we exit with [`CommandFailed::exit_code`].
+///
+/// Call this at the end of `main`, after everything else has been dropped,
+/// like `exit_with(wrap_cargo_or_rustc::<Tool>())`.
+/// To exit with other codes for other failures, use [`ExitCodes::exit_with`].
+pub fn exit_with(result: anyhow::Result<()>) -> ! {
+    ExitCodes::default().exit_with(result)
+}
+
+/// Raise `signal` with its default handler, which usually kills the process.
+#[cfg(unix)]
+fn raise(signal: i32) {
+    // SAFETY: resetting a signal's handler and raising it have no memory safety preconditions.
+    unsafe {
+        libc::signal(signal, libc::SIG_DFL);
+        libc::raise(signal);
+    }
+}
+
+#[cfg(not(unix))]
+fn raise(_signal: i32) {}
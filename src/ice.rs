@@ -0,0 +1,121 @@
+//! Detecting `rustc` internal compiler errors (ICEs) and collecting their reports.
+
+use std::fmt;
+use std::fmt::Display;
+use std::fmt::Formatter;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::process::ExitStatus;
+
+use anyhow::Context;
+use tempfile::TempDir;
+
+/// The exit code `rustc` exits with when it panics.
+pub(crate) const ICE_EXIT_CODE: i32 = 101;
+
+/// Where `rustc` writes `rustc-ice-*.txt` reports, instead of the current directory.
+const RUSTC_ICE_VAR: &str = "RUSTC_ICE";
+
+/// An internal compiler error (ICE) in `rustc`,
+/// with its report saved by the `rustc` wrapper to a directory under the one set by
+/// [`crate::CargoWrapper::set_ice_dir`].
+///
+/// It's returned in the [`anyhow::Error`] (along with the [`crate::CommandFailed`]) when `rustc` ICEs,
+/// or in [`crate::CapturedRustc::ice`].
+#[derive(Debug, Clone)]
+pub struct IceReport {
+    crate_name: String,
+    dir: PathBuf,
+    files: Vec<PathBuf>,
+}
+
+impl IceReport {
+    /// The crate `rustc` was compiling.
+    pub fn crate_name(&self) -> &str {
+        &self.crate_name
+    }
+
+    /// The directory with the report, containing the `rustc-ice-*.txt` files
+    /// and `command.txt` with the failing command line (and env vars set for it).
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The `rustc-ice-*.txt` files written by `rustc`,
+    /// which older `rustc`s (before 1.74) don't write.
+    pub fn files(&self) -> &[PathBuf] {
+        &self.files
+    }
+}
+
+impl Display for IceReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let Self {
+            crate_name, dir, ..
+        } = self;
+        write!(
+            f,
+            "internal compiler error in crate `{crate_name}`, report saved to {}",
+            dir.display()
+        )
+    }
+}
+
+/// Whether a line of `rustc`'s stderr reports an ICE,
+/// like `error: internal compiler error: ...` or its JSON diagnostic.
+pub(crate) fn is_ice_message(line: &[u8]) -> bool {
+    const ICE: &[u8] = b"internal compiler error";
+    line.windows(ICE.len()).any(|window| window == ICE)
+}
+
+/// A directory for the ICE report of a single `rustc` invocation,
+/// deleted unless it ICEs.
+pub(crate) struct IceCapture {
+    crate_name: String,
+    dir: TempDir,
+}
+
+impl IceCapture {
+    /// Create the directory under `ice_dir` and have `cmd` (`rustc`) write ICE reports there.
+    pub(crate) fn new(ice_dir: &Path, crate_name: &str, cmd: &mut Command) -> anyhow::Result<Self> {
+        fs_err::create_dir_all(ice_dir)?;
+        let dir = tempfile::Builder::new()
+            .prefix(&format!("{crate_name}."))
+            .tempdir_in(ice_dir)
+            .with_context(|| format!("create ICE report dir in {}", ice_dir.display()))?;
+        cmd.env(RUSTC_ICE_VAR, dir.path());
+        Ok(Self {
+            crate_name: crate_name.to_owned(),
+            dir,
+        })
+    }
+
+    /// If `cmd` ICEd, as shown by its `status` and either an ICE message on its stderr
+    /// (`saw_ice_message`, if it was read) or an ICE report file, keep the report
+    /// and save the command with it.
+    pub(crate) fn finish(
+        self,
+        cmd: &Command,
+        status: ExitStatus,
+        saw_ice_message: bool,
+    ) -> anyhow::Result<Option<IceReport>> {
+        if status.code() != Some(ICE_EXIT_CODE) {
+            return Ok(None);
+        }
+        let mut files = fs_err::read_dir(self.dir.path())?
+            .map(|entry| Ok(entry?.path()))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        files.sort();
+        if files.is_empty() && !saw_ice_message {
+            return Ok(None);
+        }
+        let dir = self.dir.keep();
+        fs_err::write(dir.join("command.txt"), format!("{cmd:?}\n"))?;
+        Ok(Some(IceReport {
+            crate_name: self.crate_name,
+            dir,
+            files,
+        }))
+    }
+}
@@ -0,0 +1,38 @@
+//! Limiting the `rustc` wrapper's own parallelism with `cargo`'s jobserver.
+//!
+//! See <https://doc.rust-lang.org/cargo/reference/build-scripts.html#jobserver>.
+
+use std::process::Command;
+
+use anyhow::Context;
+use jobserver::Acquired;
+use jobserver::Client;
+
+/// A jobserver token, held (limiting how many jobs run at once) until this is dropped.
+///
+/// See [`crate::RustcWrapper::acquire_job`].
+#[derive(Debug)]
+pub struct JobToken {
+    _acquired: Acquired,
+}
+
+/// The jobserver `cargo` passed to `rustc` (through `$CARGO_MAKEFLAGS`), if any.
+pub(crate) fn jobserver_from_env() -> Option<Client> {
+    // SAFETY: The jobserver's fds were inherited from `cargo`, and we never close them,
+    // so they're still the jobserver's.
+    unsafe { Client::from_env_ext(true) }.client.ok()
+}
+
+/// Block until a token from `jobserver` is available.
+pub(crate) fn acquire(jobserver: &Client) -> anyhow::Result<JobToken> {
+    let acquired = jobserver.acquire().context("acquire jobserver token")?;
+    Ok(JobToken {
+        _acquired: acquired,
+    })
+}
+
+/// Pass `jobserver` on to `cmd`,
+/// as connecting to it stops its fds from being inherited by default.
+pub(crate) fn configure(jobserver: &Client, cmd: &mut Command) {
+    jobserver.configure(cmd);
+}
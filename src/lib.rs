@@ -1,36 +1,207 @@
+#![cfg_attr(feature = "rustc-driver", feature(rustc_private))]
+
+use std::cell::Cell;
+use std::cell::OnceCell;
+use std::cell::Ref;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
 use std::env;
 use std::ffi::OsStr;
 use std::ffi::OsString;
+use std::io;
+use std::mem;
 use std::path::Path;
 use std::path::PathBuf;
-use std::process;
 use std::process::Command;
 use std::process::ExitStatus;
+use std::process::Output;
+use std::process::Stdio;
+use std::time::Duration;
 
 use anyhow::anyhow;
+use anyhow::bail;
 use anyhow::ensure;
 use anyhow::Context;
 use clap::Parser;
+use tempfile::NamedTempFile;
 
+pub use crate::cargo_args::split_cargo_args;
+use crate::cargo_args::strip_cargo_subcommand;
+pub use crate::cargo_args::CargoArgs;
+pub use crate::cargo_args::ColorChoice;
+pub use crate::cargo_args::LockfilePolicy;
+pub use crate::cargo_args::Verbosity;
+pub use crate::cargo_config::target_cfgs;
+pub use crate::cargo_config::CargoConfig;
+pub use crate::cargo_env::CargoEnv;
+pub use crate::cargo_env::PackageId;
+pub use crate::cargo_messages::Artifact;
+pub use crate::cargo_messages::ArtifactProfile;
+pub use crate::cargo_messages::ArtifactTarget;
+pub use crate::cargo_messages::BuildFinished;
+pub use crate::cargo_messages::BuildScript;
+pub use crate::cargo_messages::CargoMessage;
+pub use crate::cargo_messages::CompilerMessage;
+pub use crate::cargo_messages::CustomMessage;
+pub use crate::crate_filter::CrateFilter;
+pub use crate::dep_info::parse_dep_info;
+pub use crate::dep_info::read_dep_info;
+use crate::dependency::add_dependency_args;
+use crate::dependency::patch_config;
+pub use crate::dependency::DependencySource;
+use crate::dependency::FileBackup;
+pub use crate::diagnostics::CapturedRustc;
+pub use crate::diagnostics::Diagnostic;
+pub use crate::diagnostics::DiagnosticCode;
+pub use crate::diagnostics::DiagnosticSpan;
+#[cfg(feature = "rustc-driver")]
+pub use crate::driver::Callbacks;
+use crate::dylib_path::dylib_path_var;
+use crate::dylib_path::sysroot_dylib_dir;
+pub use crate::exit::exit_with;
+pub use crate::exit::CommandFailed;
+pub use crate::exit::ExitCodes;
+pub use crate::exit::FailureKind;
+use crate::ice::IceCapture;
+pub use crate::ice::IceReport;
+pub use crate::jobs::JobToken;
+pub use crate::limits::ResourceLimits;
+pub use crate::line_stream::OutputStream;
+pub use crate::lock_retry::LockRetry;
+pub use crate::manifest::ManifestGuard;
+#[cfg(feature = "metadata")]
+pub use crate::metadata::Dependency;
+#[cfg(feature = "metadata")]
+pub use crate::metadata::Metadata;
+#[cfg(feature = "metadata")]
+pub use crate::metadata::Node;
+#[cfg(feature = "metadata")]
+pub use crate::metadata::NodeDep;
+#[cfg(feature = "metadata")]
+pub use crate::metadata::Package;
+#[cfg(feature = "metadata")]
+pub use crate::metadata::Target;
+pub use crate::package_selection::PackageSelection;
+pub use crate::path_list::PathListVar;
+pub use crate::rustc_args::CrateType;
+pub use crate::rustc_args::Edition;
+pub use crate::rustc_args::Emit;
+pub use crate::rustc_args::EmitKind;
+pub use crate::rustc_args::Extern;
+pub use crate::rustc_args::OutputPath;
+pub use crate::rustc_args::Profile;
+pub use crate::rustdoc::RustdocWrapper;
+pub use crate::rustflags::RustFlags;
+pub use crate::stdio::CommandKind;
+pub use crate::stdio::CommandStdio;
+pub use crate::stdio::OutputPolicy;
+pub use crate::stdio::StdinPolicy;
+pub use crate::unit_graph::Unit;
+pub use crate::unit_graph::UnitDependency;
+pub use crate::unit_graph::UnitGraph;
+pub use crate::unit_graph::UnitProfile;
+use crate::util::codegen_option_values;
+use crate::util::command_with_args;
+use crate::util::env_key_glob_match;
+use crate::util::flag_values;
 use crate::util::os_str_from_bytes;
+pub use crate::util::os_str_split;
+use crate::util::os_str_strip_prefix;
+use crate::util::remove_codegen_option;
+use crate::util::remove_flag;
+pub use crate::util::set_non_utf8_strategy;
+use crate::util::shell_command_line;
 use crate::util::EnvVar;
+pub use crate::util::NonUtf8Error;
+pub use crate::util::NonUtf8Strategy;
+pub use crate::util::OsStringJoin;
+pub use crate::util::TypedEnvVar;
+pub use crate::version::Channel;
+pub use crate::version::ToolVersion;
 
+mod argfile;
+mod cargo_args;
+mod cargo_config;
+mod cargo_env;
+mod cargo_messages;
+mod crate_filter;
+mod dep_info;
+mod dependency;
+mod diagnostics;
+#[cfg(feature = "rustc-driver")]
+mod driver;
+mod dylib_path;
+mod exit;
+mod ice;
+mod jobs;
+mod limits;
+mod line_stream;
+mod lock_retry;
+mod manifest;
+#[cfg(feature = "metadata")]
+mod metadata;
+mod package_selection;
+mod path_list;
+mod rustc_args;
+mod rustdoc;
+mod rustflags;
+mod signals;
+mod stdio;
+mod unit_graph;
 mod util;
+mod version;
 
 type RustcWrapperEnvVar = EnvVar<PathBuf>;
 type SysrootEnvVar = EnvVar<PathBuf>;
 type ToolchainEnvVar = EnvVar<String>;
 
 const RUSTC_WRAPPER_VAR: &str = "RUSTC_WRAPPER";
+const RUSTC_WORKSPACE_WRAPPER_VAR: &str = "RUSTC_WORKSPACE_WRAPPER";
+const RUSTC_VAR: &str = "RUSTC";
+const RUSTDOC_VAR: &str = "RUSTDOC";
 const SYSROOT_VAR: &str = "RUST_SYSROOT";
 const TOOLCHAIN_VAR: &str = "RUSTUP_TOOLCHAIN";
+const TARGETS_VAR: &str = "CARGO_RUSTC_WRAPPER_TARGETS";
+const CRATE_FILTER_VAR: &str = "CARGO_RUSTC_WRAPPER_CRATE_FILTER";
+const REMAP_PATH_PREFIX_VAR: &str = "CARGO_RUSTC_WRAPPER_REMAP_PATH_PREFIX";
+const COLOR_VAR: &str = "CARGO_RUSTC_WRAPPER_COLOR";
+const VERBOSITY_VAR: &str = "CARGO_RUSTC_WRAPPER_VERBOSITY";
+const PREVIOUS_WRAPPER_VAR: &str = "CARGO_RUSTC_WRAPPER_PREVIOUS_WRAPPER";
+const PACKAGE_SELECTION_VAR: &str = "CARGO_RUSTC_WRAPPER_PACKAGE_SELECTION";
+const JSON_MESSAGES_VAR: &str = "CARGO_RUSTC_WRAPPER_JSON_MESSAGES";
+/// In seconds.
+const RUSTC_TIMEOUT_VAR: &str = "CARGO_RUSTC_WRAPPER_RUSTC_TIMEOUT";
+const RUSTC_LIMITS_VAR: &str = "CARGO_RUSTC_WRAPPER_RUSTC_LIMITS";
+const ICE_DIR_VAR: &str = "CARGO_RUSTC_WRAPPER_ICE_DIR";
+const FAILURE_LOG_VAR: &str = "CARGO_RUSTC_WRAPPER_FAILURE_LOG";
+/// The real `rustdoc` when we're `$RUSTDOC`, see [`CargoWrapper::set_wrap_rustdoc`].
+const REAL_RUSTDOC_VAR: &str = "CARGO_RUSTC_WRAPPER_RUSTDOC";
+/// Our `$RUSTDOC`, this binary spelled with an extra `.` path component (see [`rustdoc_wrapper_path`]),
+/// as `cargo` passes it as `argv[0]` only when running `rustdoc`, which otherwise looks like `$RUSTC`.
+const RUSTDOC_WRAPPER_VAR: &str = "CARGO_RUSTC_WRAPPER_RUSTDOC_WRAPPER";
+#[cfg(feature = "metadata")]
+const METADATA_VAR: &str = "CARGO_RUSTC_WRAPPER_METADATA";
 
-fn exit_with_status(status: ExitStatus) {
-    process::exit(status.code().unwrap_or(1))
-}
+/// The env vars always kept by [`CargoWrapper::set_clean_env`].
+pub const DEFAULT_ENV_ALLOWLIST: &[&str] = &[
+    "PATH",
+    "HOME",
+    "CARGO_*",
+    "RUSTUP_*",
+    "TMPDIR",
+    // Needed for processes to work at all on Windows.
+    "SystemRoot",
+    "USERPROFILE",
+    "TEMP",
+    "TMP",
+];
+
+/// Separates list items in env vars, like `$CARGO_ENCODED_RUSTFLAGS`.
+const ENV_LIST_SEPARATOR: u8 = 0x1f;
 
 struct WrappedCommand {
     path: PathBuf,
+    current_dir: Option<PathBuf>,
 }
 
 impl WrappedCommand {
@@ -38,22 +209,24 @@ impl WrappedCommand {
         let path = env::var_os(env_var)
             .map(PathBuf::from)
             .unwrap_or_else(|| program.into());
-        Self { path }
+        Self {
+            path,
+            current_dir: None,
+        }
     }
 
-    pub fn command(&self) -> Command {
-        Command::new(&self.path)
+    /// Run the command in `dir` instead of the current directory.
+    pub fn current_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.current_dir = Some(dir.into());
+        self
     }
 
-    pub fn run(&self, f: impl FnOnce(&mut Command) -> anyhow::Result<()>) -> anyhow::Result<()> {
-        let mut cmd = self.command();
-        f(&mut cmd)?;
-        let status = cmd.status()?;
-        if !status.success() {
-            eprintln!("error ({status}) running: {cmd:?}");
-            exit_with_status(status);
+    pub fn command(&self) -> Command {
+        let mut cmd = Command::new(&self.path);
+        if let Some(dir) = &self.current_dir {
+            cmd.current_dir(dir);
         }
-        Ok(())
+        cmd
     }
 
     pub fn cargo() -> Self {
@@ -61,15 +234,21 @@ impl WrappedCommand {
     }
 
     pub fn rustc() -> Self {
-        Self::new("rustc", "RUSTC")
+        Self::new("rustc", RUSTC_VAR)
     }
 }
 
-fn resolve_sysroot() -> anyhow::Result<PathBuf> {
-    let rustc = WrappedCommand::rustc();
+fn resolve_sysroot(rustc: &WrappedCommand) -> anyhow::Result<PathBuf> {
+    resolve_sysroot_with(rustc.command())
+}
+
+fn resolve_sysroot_with(rustc: Command) -> anyhow::Result<PathBuf> {
+    query_sysroot(rustc).context(FailureKind::Sysroot)
+}
+
+fn query_sysroot(mut rustc: Command) -> anyhow::Result<PathBuf> {
     let output = rustc
-        .command()
-        .args(&["--print", "sysroot"])
+        .args(["--print", "sysroot"])
         .output()
         .context("could not invoke `rustc` to find rust sysroot")?;
     let path = output
@@ -79,8 +258,7 @@ fn resolve_sysroot() -> anyhow::Result<PathBuf> {
         .split(|c| c.is_ascii_whitespace())
         .next()
         .unwrap_or_default();
-    let path = os_str_from_bytes(path)?;
-    let path = Path::new(path).to_owned();
+    let path = PathBuf::from(os_str_from_bytes(path)?.into_owned());
     // `rustc` reports a million errors if the sysroot is wrong, so try to check first.
     ensure!(
         path.is_dir(),
@@ -90,48 +268,690 @@ fn resolve_sysroot() -> anyhow::Result<PathBuf> {
     Ok(path)
 }
 
-/// `cargo` args that we intercept.
-#[derive(Debug, Parser)]
-// #[clap(setting = AppSettings::IgnoreErrors)]
-struct InterceptedCargoArgs {
-    #[clap(long, value_parser)]
-    manifest_path: Option<PathBuf>,
+/// The parts of `cargo metadata --no-deps` used without the `metadata` feature.
+#[derive(serde::Deserialize)]
+struct WorkspaceMetadata {
+    /// Only the workspace members because of `--no-deps`.
+    packages: Vec<WorkspaceMember>,
+    target_directory: PathBuf,
+}
+
+#[derive(serde::Deserialize)]
+struct WorkspaceMember {
+    name: String,
+    manifest_path: PathBuf,
+}
 
-    /// Need this so `--` is allowed.
-    /// Not actually used.
-    _extra_args: Vec<OsString>,
+/// Set the env vars `cargo test` runs `test` with, like the ones it was compiled with:
+/// its package's dir (as the working directory too), name, and version, its crate name,
+/// the package's binaries (`CARGO_BIN_EXE_{name}`, from `artifacts`),
+/// and its build script's `OUT_DIR` (from `out_dirs`, by package ID).
+fn set_test_env(
+    cmd: &mut Command,
+    test: &Artifact,
+    artifacts: &[Artifact],
+    out_dirs: &BTreeMap<String, PathBuf>,
+) {
+    if let Some(dir) = test.manifest_path.parent() {
+        cmd.current_dir(dir).env("CARGO_MANIFEST_DIR", dir);
+    }
+    cmd.env("CARGO_MANIFEST_PATH", &test.manifest_path);
+    if let Some(PackageId { name, version }) = PackageId::parse_qualified(&test.package_id) {
+        let core = version.split('+').next().unwrap_or_default();
+        let (core, pre) = core.split_once('-').unwrap_or((core, ""));
+        let mut parts = core.splitn(3, '.');
+        for key in ["MAJOR", "MINOR", "PATCH"] {
+            let part = parts.next().unwrap_or_default();
+            cmd.env(format!("CARGO_PKG_VERSION_{key}"), part);
+        }
+        cmd.env("CARGO_PKG_VERSION_PRE", pre);
+        cmd.env("CARGO_PKG_NAME", name);
+        cmd.env("CARGO_PKG_VERSION", version);
+    }
+    cmd.env("CARGO_CRATE_NAME", test.target.name.replace('-', "_"));
+    let binaries = artifacts.iter().filter(|artifact| {
+        artifact.package_id == test.package_id
+            && !artifact.profile.test
+            && artifact.target.kind.iter().any(|kind| kind == "bin")
+    });
+    for binary in binaries {
+        if let Some(executable) = &binary.executable {
+            cmd.env(format!("CARGO_BIN_EXE_{}", binary.target.name), executable);
+        }
+    }
+    if let Some(out_dir) = out_dirs.get(&test.package_id) {
+        cmd.env("OUT_DIR", out_dir);
+    }
 }
 
+type BuildHook = Box<dyn FnOnce(&CargoWrapper) -> anyhow::Result<()>>;
+
 pub struct CargoWrapper {
     rustc_wrapper: RustcWrapperEnvVar,
     sysroot: SysrootEnvVar,
     toolchain: Option<ToolchainEnvVar>,
-    cargo_args: InterceptedCargoArgs,
+    cargo_args: CargoArgs,
+    rustflags: Vec<OsString>,
+    rustdocflags: Vec<OsString>,
+    /// Whether to also wrap `rustdoc`, see [`Self::set_wrap_rustdoc`].
+    wrap_rustdoc: bool,
+    crate_filter: CrateFilter,
+    /// `--remap-path-prefix` values, `{from}={to}`.
+    remap_path_prefixes: Vec<OsString>,
+    /// The concrete toolchain `rustc` to pass as `$RUSTC`, bypassing the `rustup` proxy.
+    resolved_rustc: Option<PathBuf>,
+    /// The working directory to run `cargo` (and `rustc` probes) in, see [`Self::set_current_dir`].
+    current_dir: Option<PathBuf>,
+    /// Env var patterns to keep when running `cargo`, see [`Self::set_clean_env`].
+    env_allowlist: Option<Vec<String>>,
+    /// Nest `cargo`'s target dir under this, see [`Self::set_target_dir_suffix`].
+    target_dir_suffix: Option<PathBuf>,
+    /// Queried lazily from `cargo metadata`.
+    #[cfg(feature = "metadata")]
+    metadata: OnceCell<Metadata>,
+    /// Queried lazily with `--unit-graph`, see [`Self::unit_graph`].
+    unit_graph: OnceCell<UnitGraph>,
+    /// See [`Self::set_dry_run`].
+    dry_run: bool,
+    /// See [`Self::set_rustc_timeout`].
+    rustc_timeout: Option<Duration>,
+    /// See [`Self::set_rustc_limits`].
+    rustc_limits: ResourceLimits,
+    /// See [`Self::set_lock_retry`].
+    lock_retry: Option<LockRetry>,
+    /// See [`Self::set_ice_dir`].
+    ice_dir: Option<PathBuf>,
+    /// See [`Self::set_stdio`].
+    stdio: BTreeMap<CommandKind, CommandStdio>,
+    /// See [`Self::set_failure_log`].
+    failure_log: Option<PathBuf>,
+    /// Queried lazily from `cargo -vV`.
+    cargo_version: OnceCell<ToolVersion>,
+    /// Whether to restore manifests modified by the wrapper, see [`Self::set_restore_manifests`].
+    restore_manifests: bool,
+    /// The original `Cargo.toml`s and `Cargo.lock`s, before the wrapper modified them.
+    manifest_backups: RefCell<Vec<FileBackup>>,
+    /// Whether a [`Self::run_cargo_matrix`] is running,
+    /// so manifests are only restored once it's done, not after each build.
+    in_matrix: Cell<bool>,
+    /// The artifacts reported by the last [`Self::run_cargo_json`].
+    artifacts: RefCell<Vec<Artifact>>,
+    /// See [`Self::set_pre_build`].
+    pre_build: RefCell<Option<BuildHook>>,
+    /// See [`Self::set_post_build`].
+    post_build: RefCell<Option<BuildHook>>,
 }
 
 impl CargoWrapper {
-    fn new(rustc_wrapper: RustcWrapperEnvVar, cargo_args: Vec<OsString>) -> anyhow::Result<Self> {
+    fn new(rustc_wrapper: RustcWrapperEnvVar, cargo_args: CargoArgs) -> anyhow::Result<Self> {
         Ok(Self {
             rustc_wrapper,
             sysroot: SysrootEnvVar {
                 key: SYSROOT_VAR,
-                value: resolve_sysroot()?,
+                value: resolve_sysroot(&WrappedCommand::rustc())?,
             },
             toolchain: None,
-            cargo_args: InterceptedCargoArgs::try_parse_from(
-                ["cargo".into()].into_iter().chain(cargo_args),
-            )?,
+            cargo_args,
+            rustflags: Vec::new(),
+            rustdocflags: Vec::new(),
+            wrap_rustdoc: false,
+            crate_filter: CrateFilter::default(),
+            remap_path_prefixes: Vec::new(),
+            resolved_rustc: None,
+            current_dir: None,
+            env_allowlist: None,
+            target_dir_suffix: None,
+            #[cfg(feature = "metadata")]
+            metadata: OnceCell::new(),
+            unit_graph: OnceCell::new(),
+            dry_run: false,
+            rustc_timeout: None,
+            rustc_limits: ResourceLimits::default(),
+            lock_retry: None,
+            ice_dir: None,
+            stdio: BTreeMap::new(),
+            failure_log: None,
+            cargo_version: OnceCell::new(),
+            restore_manifests: false,
+            manifest_backups: RefCell::new(Vec::new()),
+            in_matrix: Cell::new(false),
+            artifacts: RefCell::new(Vec::new()),
+            pre_build: RefCell::new(None),
+            post_build: RefCell::new(None),
+        })
+    }
+
+    /// Run `cargo` in `dir` instead of the current directory,
+    /// e.g. when invoked from outside the project or to build a generated project in a temp dir.
+    ///
+    /// Relative paths in the `cargo` args, like `--manifest-path`, are then relative to `dir`.
+    /// This also applies to `rustc` and `rustup` queries, as `rustup` picks toolchains by directory.
+    pub fn set_current_dir(&mut self, dir: impl Into<PathBuf>) {
+        self.current_dir = Some(dir.into());
+    }
+
+    /// The directory `cargo` is run in, see [`Self::set_current_dir`].
+    pub fn current_dir(&self) -> anyhow::Result<PathBuf> {
+        let cwd = env::current_dir()?;
+        Ok(match &self.current_dir {
+            Some(dir) => cwd.join(dir),
+            None => cwd,
         })
     }
 
+    /// Run `cargo` (and `rustc` and `rustup` queries) with only the env vars matching
+    /// [`DEFAULT_ENV_ALLOWLIST`] or `allowed`,
+    /// so that hermetic or CI builds aren't affected by the invoking environment.
+    ///
+    /// Patterns are globs, like `CARGO_*` (see [`CrateFilter`] for the syntax),
+    /// matched case-insensitively on Windows, where env var names are case-insensitive.
+    /// Env vars set by the wrapper itself and by `f` in [`Self::run_cargo`] and friends are still set.
+    pub fn set_clean_env(&mut self, allowed: impl IntoIterator<Item = impl Into<String>>) {
+        let allowlist = DEFAULT_ENV_ALLOWLIST
+            .iter()
+            .map(|&pattern| pattern.to_owned())
+            .chain(allowed.into_iter().map(Into::into))
+            .collect();
+        self.env_allowlist = Some(allowlist);
+    }
+
+    /// Set the toolchain, working directory, and env on a `cargo`, `rustc`, or `rustup` `cmd`.
+    fn configure_command(&self, cmd: &mut Command) {
+        if let Some(allowlist) = &self.env_allowlist {
+            // Remove vars individually rather than with `env_clear`,
+            // so that `command_env` still sees that they're unset.
+            for (key, _) in env::vars_os() {
+                let allowed = key.to_str().is_some_and(|key| {
+                    allowlist
+                        .iter()
+                        .any(|pattern| env_key_glob_match(pattern, key))
+                });
+                if !allowed {
+                    cmd.env_remove(key);
+                }
+            }
+        }
+        if let Some(toolchain) = &self.toolchain {
+            toolchain.set_on(cmd);
+        }
+        if let Some(dir) = &self.current_dir {
+            cmd.current_dir(dir);
+        }
+    }
+
+    /// A `cargo` or `rustc` command for a query, like `cargo metadata` or `rustc -vV`,
+    /// configured by [`Self::configure_command`], but without any inherited `rustc` wrapper,
+    /// which queries don't need, and which may be this binary without the env it needs.
+    fn query_command(&self, wrapped: WrappedCommand) -> Command {
+        let mut cmd = wrapped.command();
+        self.configure_command(&mut cmd);
+        for key in [RUSTC_WRAPPER_VAR, RUSTC_WORKSPACE_WRAPPER_VAR] {
+            EnvVar::<Option<OsString>>::unset(key).set_on(&mut cmd);
+        }
+        cmd
+    }
+
+    /// The `Cargo.toml` that `cargo` is run on,
+    /// either from the intercepted `--manifest-path` or [`Self::set_manifest_path`].
+    ///
+    /// If this is [`None`], `cargo` searches for it from the current directory.
     pub fn manifest_path(&self) -> Option<&Path> {
-        self.cargo_args.manifest_path.as_deref()
+        self.cargo_args.manifest_path()
+    }
+
+    /// The directory containing [`Self::manifest_path`].
+    pub fn manifest_dir(&self) -> Option<&Path> {
+        let dir = self.manifest_path()?.parent()?;
+        Some(if dir.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            dir
+        })
+    }
+
+    /// The root directory of the workspace containing [`Self::manifest_path`],
+    /// from `cargo locate-project --workspace`,
+    /// e.g. for placing target dirs, metadata files, and caches.
+    pub fn workspace_root(&self) -> anyhow::Result<PathBuf> {
+        let manifest_path = self.locate_project(true)?;
+        let root = manifest_path.parent().ok_or_else(|| {
+            anyhow!(
+                "workspace manifest has no parent dir: {}",
+                manifest_path.display()
+            )
+        })?;
+        Ok(root.to_owned())
+    }
+
+    /// The `Cargo.toml` of the package (or if `workspace`, the workspace) that `cargo` is run on.
+    fn locate_project(&self, workspace: bool) -> anyhow::Result<PathBuf> {
+        let mut cmd = self.query_command(WrappedCommand::cargo());
+        cmd.args(["locate-project", "--message-format", "plain"]);
+        if workspace {
+            cmd.arg("--workspace");
+        }
+        self.forward_manifest_path(&mut cmd)?;
+        let output = exit::checked_output(&mut cmd)?;
+        let manifest_path = os_str_from_bytes(output.stdout.trim_ascii_end())?;
+        Ok(manifest_path.into_owned().into())
+    }
+
+    /// The workspace graph from `cargo metadata`, queried once and cached.
+    ///
+    /// If this is queried before [`Self::run_cargo_with_rustc_wrapper`],
+    /// a snapshot of it is also passed to the `rustc` wrapper (see [`RustcWrapper::cargo_metadata`]).
+    #[cfg(feature = "metadata")]
+    pub fn cargo_metadata(&self) -> anyhow::Result<&Metadata> {
+        if let Some(metadata) = self.metadata.get() {
+            return Ok(metadata);
+        }
+        let mut cmd = self.query_command(WrappedCommand::cargo());
+        cmd.arg("metadata");
+        self.forward_manifest_path(&mut cmd)?;
+        let metadata = Metadata::query(cmd)?;
+        Ok(self.metadata.get_or_init(|| metadata))
+    }
+
+    /// The units the build would run, queried (once) from `cargo` with `--unit-graph`
+    /// without building anything,
+    /// so tools can know ahead of time which `rustc` invocations will happen.
+    ///
+    /// `--unit-graph` is unstable, so this enables it with `$RUSTC_BOOTSTRAP` for the query.
+    /// Changes to [`Self::cargo_args_mut`] after the first call aren't reflected.
+    pub fn unit_graph(&self) -> anyhow::Result<&UnitGraph> {
+        if let Some(unit_graph) = self.unit_graph.get() {
+            return Ok(unit_graph);
+        }
+        let unit_graph = self.query_unit_graph(self.cargo_args.clone())?;
+        Ok(self.unit_graph.get_or_init(|| unit_graph))
+    }
+
+    fn query_unit_graph(&self, mut cargo_args: CargoArgs) -> anyhow::Result<UnitGraph> {
+        // `cargo run --unit-graph` panics, but builds the same units as `cargo build`.
+        if matches!(
+            cargo_args.subcommand().and_then(OsStr::to_str),
+            Some("run" | "r")
+        ) {
+            cargo_args.set_subcommand("build");
+            cargo_args.take_trailing_args();
+        }
+        cargo_args.add_args(["--unit-graph", "-Z", "unstable-options"]);
+        let (cmd, output) = self.cargo_output(|cmd| {
+            cmd.args(cargo_args.to_args());
+            cmd.env("RUSTC_BOOTSTRAP", "1");
+            Ok(())
+        })?;
+        let output = exit::check_output(&cmd, output)?;
+        serde_json::from_slice(&output.stdout).context("parse `cargo --unit-graph`")
+    }
+
+    /// Only print the `cargo` commands that would be run
+    /// (as copy-pastable POSIX shell command lines, with their env vars),
+    /// and for wrapped builds, which units the `rustc` wrapper would wrap
+    /// (see [`Self::unit_graph`] and [`Self::set_crate_filter`]), without running them,
+    /// e.g. for debugging or checking in CI what a wrapper would do.
+    ///
+    /// This covers [`Self::run_cargo`] too, so `cargo add`s (like [`Self::add_runtime_dependency`])
+    /// and `cargo clean`s (like [`Self::force_rebuild`]) are only printed.
+    /// Queries like [`Self::run_cargo_output`] still run, as the plan depends on them,
+    /// but the [pre-](Self::set_pre_build) and [post-build](Self::set_post_build) hooks don't.
+    /// Builds then succeed without producing any [`Self::built_artifacts`],
+    /// so nothing is run after them (like by [`Self::run_cargo_test`]).
+    pub fn set_dry_run(&mut self, dry_run: bool) {
+        self.dry_run = dry_run;
+    }
+
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// Kill each `rustc` invocation run by the `rustc` wrapper (like with [`RustcWrapper::run_rustc`])
+    /// if it's still running after `timeout`, failing with an error naming the crate,
+    /// e.g. for analysis passes that might hang.
+    ///
+    /// See [`RustcWrapper::timeout`].
+    pub fn set_rustc_timeout(&mut self, timeout: Option<Duration>) {
+        self.rustc_timeout = timeout;
+    }
+
+    /// Limit the memory and CPU time of each `rustc` invocation run by the `rustc` wrapper
+    /// (on Unix), see [`ResourceLimits`] and [`RustcWrapper::limits`].
+    pub fn set_rustc_limits(&mut self, limits: ResourceLimits) {
+        self.rustc_limits = limits;
+    }
+
+    /// Collect a report for each internal compiler error (ICE) in `rustc` run by the `rustc` wrapper
+    /// into a directory under `dir`, with `rustc`'s `rustc-ice-*.txt` files and the failing command,
+    /// and fail with a summary of where it is (see [`IceReport`]).
+    ///
+    /// `dir` is relative to the current directory.
+    pub fn set_ice_dir(&mut self, dir: Option<PathBuf>) -> anyhow::Result<()> {
+        self.ice_dir = dir.map(std::path::absolute).transpose()?;
+        Ok(())
+    }
+
+    /// Also append each failed command (see [`CommandFailed::command`]) to the file `log`,
+    /// including each failing `rustc` run by the `rustc` wrapper,
+    /// with the working directory and env vars it inherited from `cargo`,
+    /// so that it can be re-run outside of `cargo` and the wrapper.
+    ///
+    /// `log` is relative to the current directory.
+    pub fn set_failure_log(&mut self, log: Option<PathBuf>) -> anyhow::Result<()> {
+        self.failure_log = log.map(std::path::absolute).transpose()?;
+        Ok(())
+    }
+
+    /// Fail with [`CommandFailed`] unless `status` (of `cmd`) is successful,
+    /// logging it to the [`Self::set_failure_log`] if there is one.
+    fn check_status(&self, cmd: &Command, status: ExitStatus) -> anyhow::Result<()> {
+        exit::check_status(cmd, status, self.verbosity(), self.failure_log.as_deref())
+    }
+
+    /// Retry [`Self::run_cargo`] (like for `cargo add`) and [`Self::run_cargo_with_rustc_wrapper`]
+    /// with backoff when another `cargo` holds a lock they need, instead of blocking indefinitely,
+    /// saying so, and eventually failing (see [`LockRetry`]).
+    ///
+    /// This pipes `cargo`'s stderr to watch for it waiting, so `cargo` won't show a progress bar.
+    /// With `--quiet`, `cargo` doesn't say it's waiting, so it just waits.
+    pub fn set_lock_retry(&mut self, retry: Option<LockRetry>) {
+        self.lock_retry = retry;
+    }
+
+    /// Set how to handle the stdin, stdout, and stderr of a `kind` of command,
+    /// e.g. to silence `cargo add` unless it fails (with [`CommandStdio::quiet`]),
+    /// while still showing the build's output.
+    ///
+    /// By default, they're all inherited.
+    pub fn set_stdio(&mut self, kind: CommandKind, stdio: CommandStdio) {
+        self.stdio.insert(kind, stdio);
+    }
+
+    pub fn stdio(&self, kind: CommandKind) -> &CommandStdio {
+        static INHERIT: CommandStdio = CommandStdio {
+            stdin: StdinPolicy::Inherit,
+            stdout: OutputPolicy::Inherit,
+            stderr: OutputPolicy::Inherit,
+        };
+        self.stdio.get(&kind).unwrap_or(&INHERIT)
+    }
+
+    /// Run `cargo` (configured by [`Self::cargo_status_with`]) with the [`Self::stdio`] for `kind`,
+    /// retrying on lock contention if [`Self::set_lock_retry`] is set.
+    fn cargo_status_as(&self, kind: CommandKind, cmd: &mut Command) -> anyhow::Result<ExitStatus> {
+        let stdio = self.stdio(kind);
+        match self.lock_retry {
+            Some(retry) => lock_retry::status(cmd, stdio, retry),
+            None => Ok(stdio::status(cmd, stdio)?),
+        }
+    }
+
+    /// Print `cmd` as a shell command line instead of running it, for [`Self::set_dry_run`].
+    fn print_dry_run(&self, cmd: &Command) -> ExitStatus {
+        eprintln!("   Would run {}", shell_command_line(cmd));
+        ExitStatus::default()
+    }
+
+    /// Print which units of a wrapped build with `cargo_args` the `rustc` wrapper would wrap,
+    /// for [`Self::set_dry_run`].
+    fn print_dry_run_units(&self, cargo_args: CargoArgs) {
+        let unit_graph = match self.query_unit_graph(cargo_args) {
+            Ok(unit_graph) => unit_graph,
+            Err(e) => {
+                eprintln!("warning: could not determine which units would be wrapped: {e:#}");
+                return;
+            }
+        };
+        for unit in &unit_graph.units {
+            let action = if unit.is_rustc() {
+                if self.crate_filter.matches(&unit_graph.cargo_env(unit)) {
+                    "Would wrap"
+                } else {
+                    "Won't wrap"
+                }
+            } else if unit.mode == "doc" || unit.mode == "doctest" {
+                if self.wrap_rustdoc {
+                    "Would wrap"
+                } else {
+                    continue;
+                }
+            } else {
+                continue;
+            };
+            let package = match unit.package_id() {
+                Some(package_id) => package_id.to_string(),
+                None => unit.pkg_id.clone(),
+            };
+            let platform = unit.platform.as_deref().unwrap_or("host");
+            eprintln!(
+                "{action:>12} {package} {} `{}` ({}, {platform})",
+                unit.mode,
+                unit.target.name,
+                unit.target.kind.join(", "),
+            );
+        }
+    }
+
+    /// Build in an isolated target dir, `{target_dir}/{suffix}`,
+    /// so that wrapped builds don't invalidate or get mixed up with normal ones.
+    ///
+    /// `{target_dir}` is the one `cargo` would otherwise use,
+    /// respecting `--target-dir`, `$CARGO_TARGET_DIR`, and `build.target-dir` in `cargo` config.
+    pub fn set_target_dir_suffix(&mut self, suffix: impl Into<PathBuf>) {
+        self.target_dir_suffix = Some(suffix.into());
+    }
+
+    /// Make the next [`Self::run_cargo_with_rustc_wrapper`] rebuild `packages`
+    /// even if `cargo` considers them fresh, so that the `rustc` wrapper runs on them again,
+    /// by running `cargo clean -p` on them in [`Self::target_dir`] for the profile and targets being built.
+    pub fn force_rebuild(
+        &self,
+        packages: impl IntoIterator<Item = impl AsRef<str>>,
+    ) -> anyhow::Result<()> {
+        let packages = packages.into_iter().collect::<Vec<_>>();
+        if packages.is_empty() {
+            return Ok(());
+        }
+        let target_dir = self.target_dir()?;
+        self.run_cargo(|cmd| {
+            cmd.arg("clean");
+            for package in &packages {
+                cmd.args(["--package", package.as_ref()]);
+            }
+            cmd.arg("--target-dir").arg(target_dir);
+            if let Some(profile) = self.cargo_args.profile() {
+                cmd.arg("--profile").arg(profile);
+            }
+            for target in self.targets()? {
+                cmd.args(["--target", &target]);
+            }
+            cmd.args(self.lockfile_policy().cargo_flag());
+            Ok(())
+        })
+    }
+
+    /// The target dir that [`Self::run_cargo_with_rustc_wrapper`] builds in,
+    /// including any [`Self::set_target_dir_suffix`].
+    pub fn target_dir(&self) -> anyhow::Result<PathBuf> {
+        let target_dir = match self.cargo_args.target_dir() {
+            Some(target_dir) => target_dir.to_owned(),
+            None => self.query_target_dir()?,
+        };
+        Ok(match &self.target_dir_suffix {
+            Some(suffix) => target_dir.join(suffix),
+            None => target_dir,
+        })
+    }
+
+    /// The target dir from `cargo metadata`, which accounts for `$CARGO_TARGET_DIR` and `cargo` config.
+    fn query_target_dir(&self) -> anyhow::Result<PathBuf> {
+        #[cfg(feature = "metadata")]
+        if let Some(metadata) = self.metadata.get() {
+            return Ok(metadata.target_directory.clone());
+        }
+        Ok(self.query_workspace_metadata()?.target_directory)
+    }
+
+    /// The parts of `cargo metadata --no-deps` we need without the `metadata` feature.
+    fn query_workspace_metadata(&self) -> anyhow::Result<WorkspaceMetadata> {
+        let mut cmd = self.query_command(WrappedCommand::cargo());
+        cmd.args(["metadata", "--no-deps", "--format-version", "1"]);
+        self.forward_manifest_path(&mut cmd)?;
+        let output = exit::checked_output(&mut cmd)?;
+        serde_json::from_slice(&output.stdout).context("invalid `cargo metadata` output")
+    }
+
+    /// Set the `Cargo.toml` that `cargo` is run on.
+    ///
+    /// [`Self::run_cargo`] and [`Self::run_cargo_with_rustc_wrapper`] forward this as `--manifest-path`
+    /// unless one was already passed.
+    pub fn set_manifest_path(&mut self, manifest_path: impl Into<PathBuf>) {
+        self.cargo_args
+            .set_manifest_path(manifest_path.into().into_os_string());
+    }
+
+    /// The verbosity from the `cargo` args (see [`CargoArgs::verbosity`]),
+    /// which is also passed to the `rustc` wrapper (see [`RustcWrapper::verbosity`]).
+    ///
+    /// When quiet, the wrapper doesn't print the commands that failed.
+    pub fn verbosity(&self) -> Verbosity {
+        self.cargo_args.verbosity()
+    }
+
+    /// The [`LockfilePolicy`] from the `cargo` args (see [`CargoArgs::lockfile_policy`]),
+    /// which is followed both by the build and by `cargo add` in [`Self::add_runtime_dependency`],
+    /// so that the wrapper never silently updates `Cargo.lock` with `--locked`
+    /// (`cargo add` then fails instead, so the dependency has to already be declared).
+    pub fn lockfile_policy(&self) -> LockfilePolicy {
+        self.cargo_args.lockfile_policy()
+    }
+
+    /// Override the [`Self::lockfile_policy`], e.g. to always use [`LockfilePolicy::Locked`] in CI.
+    pub fn set_lockfile_policy(&mut self, policy: LockfilePolicy) {
+        self.cargo_args.set_lockfile_policy(policy);
+    }
+
+    /// The color choice from `--color`, or else `term.color` in `cargo` config (or `$CARGO_TERM_COLOR`).
+    pub fn color_choice(&self) -> ColorChoice {
+        self.cargo_args.color().unwrap_or_else(|| {
+            self.cargo_config()
+                .ok()
+                .and_then(|config| config.get_str(&["term", "color"]))
+                .and_then(|color| ColorChoice::parse(&color))
+                .unwrap_or_default()
+        })
+    }
+
+    /// Whether to use colors (see [`Self::color_choice`]),
+    /// which is passed on to `cargo` and the `rustc` wrapper (see [`RustcWrapper::should_color`]),
+    /// so that output piped to a file (e.g. CI logs) doesn't contain escape codes.
+    pub fn should_color(&self) -> bool {
+        self.color_choice().should_color()
+    }
+
+    /// The `cargo` args after `--` (see [`split_cargo_args`]),
+    /// passed to `cargo` by [`Self::run_cargo_with_rustc_wrapper`].
+    pub fn cargo_args(&self) -> &CargoArgs {
+        &self.cargo_args
+    }
+
+    pub fn cargo_args_mut(&mut self) -> &mut CargoArgs {
+        &mut self.cargo_args
+    }
+
+    /// The sysroot's dylib directory (`lib`, or `bin` on Windows),
+    /// which is prepended to the dynamic loader's path
+    /// (`$LD_LIBRARY_PATH`, `$DYLD_FALLBACK_LIBRARY_PATH`, or `$PATH`)
+    /// for [`Self::run_cargo_with_rustc_wrapper`].
+    pub fn sysroot_dylib_dir(&self) -> PathBuf {
+        sysroot_dylib_dir(&self.sysroot.value)
+    }
+
+    /// The explicit target triples `cargo` is building for,
+    /// from `--target`s, or else `$CARGO_BUILD_TARGET` or `build.target` in `cargo` config.
+    ///
+    /// These are passed to the `rustc` wrapper as [`RustcWrapper::cargo_targets`].
+    pub fn targets(&self) -> anyhow::Result<Vec<String>> {
+        let targets = self
+            .cargo_args
+            .targets()
+            .map(|target| {
+                target
+                    .to_str()
+                    .map(ToOwned::to_owned)
+                    .ok_or_else(|| anyhow!("non-UTF-8 `--target`: {target:?}"))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        if !targets.is_empty() {
+            return Ok(targets);
+        }
+        // `cargo_config` also checks `$CARGO_BUILD_TARGET`.
+        let target = self.cargo_config()?.get_str(&["build", "target"]);
+        Ok(target.into_iter().collect())
+    }
+
+    /// Whether `cargo` is cross compiling, i.e. building for explicit [`Self::targets`]
+    /// other than the host (see [`Self::rustc_version`]), so `--target {host}` is still native.
+    ///
+    /// With any explicit targets, though, `cargo` builds host units without `--target`
+    /// (see [`RustcWrapper::build_kind`]).
+    pub fn is_cross_compiling(&self) -> anyhow::Result<bool> {
+        let targets = self.targets()?;
+        if targets.is_empty() {
+            return Ok(false);
+        }
+        let host = self.rustc_version()?.host;
+        Ok(targets.iter().any(|target| *target != host))
+    }
+
+    /// Which packages `cargo` was asked to build (see [`CargoArgs::package_selection`]),
+    /// which is passed to the `rustc` wrapper (see [`RustcWrapper::is_selected_package`]).
+    pub fn package_selection(&self) -> PackageSelection {
+        self.cargo_args.package_selection()
+    }
+
+    /// Whether `std` is built from source, with `-Z build-std` or `unstable.build-std` in `cargo` config.
+    ///
+    /// The `std` crates are then also compiled through the `rustc` wrapper,
+    /// but aren't wrapped by default (see [`RustcWrapper::is_build_std_crate`]).
+    pub fn is_build_std(&self) -> anyhow::Result<bool> {
+        if self.cargo_args.is_build_std() {
+            return Ok(true);
+        }
+        Ok(self.cargo_config()?.contains(&["unstable", "build-std"]))
+    }
+
+    /// Check that the sysroot passed to the `rustc` wrapper has a `std` for each of [`Self::targets`],
+    /// as otherwise every crate fails to compile with a confusing error.
+    ///
+    /// With [`Self::is_build_std`], `std` doesn't need to be prebuilt,
+    /// and the `--sysroot` is then only used to find the `std` sources and `rustc`'s own dylibs.
+    fn check_target_sysroots(&self) -> anyhow::Result<()> {
+        if self.is_build_std()? {
+            return Ok(());
+        }
+        for target in self.targets()? {
+            // Custom target specs don't have a prebuilt `std`.
+            if target.ends_with(".json") {
+                continue;
+            }
+            let dir = self.sysroot.value.join("lib").join("rustlib").join(&target);
+            if !dir.is_dir() {
+                return Err(anyhow!(
+                    "the sysroot {} has no `std` for target `{target}`, try `rustup target add {target}`",
+                    self.sysroot.value.display()
+                )
+                .context(FailureKind::Sysroot));
+            }
+        }
+        Ok(())
     }
 
     /// Set `$RUSTUP_TOOLCHAIN` to the toolchain channel specified in `rust-toolchain.toml`.
     /// This ensures that we use a toolchain compatible with the `rustc` private crates that we linked to.
     pub fn set_rustup_toolchain(&mut self, rust_toolchain_toml_str: &str) -> anyhow::Result<()> {
-        let doc = rust_toolchain_toml_str.parse::<toml_edit::Document>()?;
+        let doc = rust_toolchain_toml_str
+            .parse::<toml_edit::Document>()
+            .context(FailureKind::Config)?;
         let channel = doc["toolchain"]["channel"].as_str();
         if let Some(toolchain) = channel {
             self.toolchain = Some(ToolchainEnvVar {
@@ -142,120 +962,2363 @@ impl CargoWrapper {
         Ok(())
     }
 
-    pub fn run_cargo(
+    /// The `$RUSTC_WRAPPER` (or `build.rustc-wrapper`) that registering ourselves as `$RUSTC_WRAPPER`
+    /// would clobber, like `sccache`, which the `rustc` wrapper then chains to
+    /// (see [`RustcWrapper::previous_wrapper`]).
+    ///
+    /// This is [`None`] with [`Self::use_workspace_wrapper`], as `cargo` chains them itself.
+    pub fn previous_rustc_wrapper(&self) -> anyhow::Result<Option<PathBuf>> {
+        if self.rustc_wrapper.key != RUSTC_WRAPPER_VAR {
+            return Ok(None);
+        }
+        let previous = self.cargo_config()?.rustc_wrapper();
+        Ok(previous.filter(|previous| *previous != self.rustc_wrapper.value))
+    }
+
+    /// Resolve `rustc` through the `rustup` proxy to the concrete toolchain binary (once, here),
+    /// and pass that as `$RUSTC` to [`Self::run_cargo_with_rustc_wrapper`],
+    /// avoiding the per-invocation proxy overhead and the proxy picking a different toolchain.
+    ///
+    /// Uses `rustup which rustc`, or if `rustup` isn't available, the `rustc` in the toolchain's sysroot.
+    /// Does nothing if `$RUSTC` is already set.
+    /// Call this after [`Self::set_rustup_toolchain`] so that toolchain is resolved.
+    pub fn resolve_rustc_proxy(&mut self) -> anyhow::Result<()> {
+        if env::var_os(RUSTC_VAR).is_some() {
+            return Ok(());
+        }
+        let mut rustup = Command::new("rustup");
+        rustup.args(["which", "rustc"]);
+        self.configure_command(&mut rustup);
+        let rustc = match rustup.output() {
+            Ok(output) if output.status.success() => {
+                let path = output.stdout.trim_ascii_end();
+                PathBuf::from(os_str_from_bytes(path)?.into_owned())
+            }
+            _ => {
+                let rustc = self.query_command(WrappedCommand::rustc());
+                resolve_sysroot_with(rustc)?
+                    .join("bin")
+                    .join(format!("rustc{}", env::consts::EXE_SUFFIX))
+            }
+        };
+        ensure!(
+            rustc.is_file(),
+            "resolved `rustc` does not exist: {}",
+            rustc.display()
+        );
+        self.resolved_rustc = Some(rustc);
+        Ok(())
+    }
+
+    /// The concrete `rustc` found by [`Self::resolve_rustc_proxy`], if it was called.
+    pub fn resolved_rustc(&self) -> Option<&Path> {
+        self.resolved_rustc.as_deref()
+    }
+
+    /// Register as `$RUSTC_WORKSPACE_WRAPPER` instead of `$RUSTC_WRAPPER`,
+    /// so that only workspace members are wrapped,
+    /// and any existing `$RUSTC_WRAPPER` (like `sccache`) keeps working for dependencies.
+    pub fn use_workspace_wrapper(&mut self, workspace_only: bool) {
+        self.rustc_wrapper.key = if workspace_only {
+            RUSTC_WORKSPACE_WRAPPER_VAR
+        } else {
+            RUSTC_WRAPPER_VAR
+        };
+    }
+
+    /// The version of the `rustc` that `cargo` will use (with the toolchain from [`Self::set_rustup_toolchain`]).
+    pub fn rustc_version(&self) -> anyhow::Result<ToolVersion> {
+        let rustc = self.query_command(WrappedCommand::rustc());
+        ToolVersion::query(rustc, "rustc")
+    }
+
+    /// The version of `cargo` (with the toolchain from [`Self::set_rustup_toolchain`]),
+    /// queried once, e.g. to adapt to what older `cargo`s support.
+    pub fn cargo_version(&self) -> anyhow::Result<&ToolVersion> {
+        if let Some(version) = self.cargo_version.get() {
+            return Ok(version);
+        }
+        let cargo = self.query_command(WrappedCommand::cargo());
+        let version = ToolVersion::query(cargo, "cargo")?;
+        Ok(self.cargo_version.get_or_init(|| version))
+    }
+
+    /// Fail early with a clear error unless `cargo` is at least version `min`, like `1.70.0`.
+    pub fn require_cargo_version(&self, min: &str) -> anyhow::Result<()> {
+        self.cargo_version()?
+            .ensure_at_least(min)
+            .context(FailureKind::Toolchain)
+    }
+
+    /// Fail early with a clear error if `cargo` doesn't support `--config`
+    /// (used by [`Self::patch_dependency`] and [`Self::set_custom_profile`]),
+    /// or `flags` need `$CARGO_ENCODED_RUSTFLAGS` and `cargo` doesn't support it.
+    ///
+    /// `cargo` is only queried if one of these is needed.
+    fn check_cargo_support(
         &self,
-        f: impl FnOnce(&mut Command) -> anyhow::Result<()>,
+        cargo_args: &CargoArgs,
+        flags: &[RustFlags],
     ) -> anyhow::Result<()> {
-        WrappedCommand::cargo().run(|cmd| {
-            if let Some(toolchain) = &self.toolchain {
-                toolchain.set_on(cmd);
-            }
-            f(cmd)?;
-            Ok(())
-        })
+        if cargo_args.configs().next().is_some() {
+            let version = self.cargo_version()?;
+            ensure!(
+                version.supports_config_flag(),
+                "{version} doesn't support `--config`, need at least 1.63"
+            );
+        }
+        if flags.iter().any(|flags| flags.needs_encoding()) {
+            let version = self.cargo_version()?;
+            ensure!(
+                version.supports_encoded_rustflags(),
+                "{version} doesn't support flags with spaces (`$CARGO_ENCODED_RUSTFLAGS`), need at least 1.55"
+            );
+        }
+        Ok(())
     }
 
-    pub fn run_cargo_with_rustc_wrapper(
+    /// Fail early with a clear error unless `rustc` is exactly the commit `commit_hash`
+    /// (which may be abbreviated), i.e. the one whose `rustc_private` crates this tool was built against.
+    pub fn require_rustc_commit(&self, commit_hash: &str) -> anyhow::Result<()> {
+        self.rustc_version()?
+            .ensure_commit(commit_hash)
+            .context(FailureKind::Toolchain)
+    }
+
+    /// Fail early with a clear error unless `rustc` is at least version `min`, like `1.70.0`.
+    pub fn require_rustc_version(&self, min: &str) -> anyhow::Result<()> {
+        self.rustc_version()?
+            .ensure_at_least(min)
+            .context(FailureKind::Toolchain)
+    }
+
+    /// Add a dependency that wrapped crates need at runtime, like an instrumentation runtime,
+    /// to the package at [`Self::manifest_path`] with `cargo add`.
+    ///
+    /// If `optional`, it's only used when enabled as a feature (see [`CargoArgs::add_features`]).
+    ///
+    /// See [`Self::set_restore_manifests`] for undoing this afterwards.
+    pub fn add_runtime_dependency(
         &self,
-        f: impl FnOnce(&mut Command) -> anyhow::Result<()>,
+        name: &str,
+        source: DependencySource,
+        optional: bool,
+        features: &[&str],
     ) -> anyhow::Result<()> {
+        if self.restore_manifests {
+            let manifest_path = self.locate_project(false)?;
+            self.back_up(&manifest_path)?;
+            let lock_path = self.workspace_root()?.join("Cargo.lock");
+            self.back_up(&lock_path)?;
+        }
         self.run_cargo(|cmd| {
-            self.rustc_wrapper.set_on(cmd);
-            self.sysroot.set_on(cmd);
-            f(cmd)
+            cmd.arg("add");
+            add_dependency_args(
+                cmd,
+                name,
+                &source,
+                optional,
+                features,
+                self.lockfile_policy(),
+            )
         })
     }
-}
 
-fn os_string_utf8_error(s: OsString) -> anyhow::Error {
-    anyhow!("non-UTF-8 OsString: {s:?}")
-}
+    /// Like [`Self::add_runtime_dependency`], but for every workspace member,
+    /// or if `packages` is [`Some`], only the members with those names,
+    /// so that every instrumented crate of a workspace build can use it.
+    pub fn add_workspace_runtime_dependency(
+        &self,
+        name: &str,
+        source: DependencySource,
+        optional: bool,
+        features: &[&str],
+        packages: Option<&[&str]>,
+    ) -> anyhow::Result<()> {
+        let members = self.query_workspace_metadata()?.packages;
+        if let Some(packages) = packages {
+            for package in packages {
+                ensure!(
+                    members.iter().any(|member| member.name == *package),
+                    "`{package}` is not a workspace member"
+                );
+            }
+        }
+        let selected = members.iter().filter(|member| {
+            packages.is_none_or(|packages| packages.contains(&member.name.as_str()))
+        });
+        if self.restore_manifests {
+            let lock_path = self.workspace_root()?.join("Cargo.lock");
+            self.back_up(&lock_path)?;
+        }
+        for member in selected {
+            if self.restore_manifests {
+                self.back_up(&member.manifest_path)?;
+            }
+            self.run_cargo(|cmd| {
+                cmd.arg("add");
+                add_dependency_args(
+                    cmd,
+                    name,
+                    &source,
+                    optional,
+                    features,
+                    self.lockfile_policy(),
+                )?;
+                cmd.arg("--manifest-path").arg(&member.manifest_path);
+                Ok(())
+            })?;
+        }
+        Ok(())
+    }
 
-pub struct RustcWrapper {
-    args: Vec<OsString>,
-    sysroot: EnvVar<PathBuf>,
-}
+    /// Temporarily modify the package's `Cargo.toml` (see [`ManifestGuard`]),
+    /// snapshotting it and the workspace's `Cargo.lock` to restore afterwards.
+    pub fn manifest_guard(&self) -> anyhow::Result<ManifestGuard> {
+        let manifest_path = self.locate_project(false)?;
+        let lock_path = self.workspace_root()?.join("Cargo.lock");
+        ManifestGuard::new(manifest_path, &lock_path)
+    }
 
-impl RustcWrapper {
-    fn new() -> anyhow::Result<Self> {
-        let args = env::args_os().skip(1).collect::<Vec<_>>();
-        let sysroot = SysrootEnvVar::get_path(SYSROOT_VAR).ok_or_else(|| {
-            anyhow!("the `cargo` wrapper should've set `${SYSROOT_VAR}` for the `rustc` wrapper")
-        })?;
-        Ok(Self { args, sysroot })
+    /// Redirect the dependency `name` from `registry` (`crates-io` if [`None`]) to `source`
+    /// with a `[patch]` table passed as `--config` to [`Self::run_cargo_with_rustc_wrapper`],
+    /// so that the user's `Cargo.toml` isn't modified, unlike [`Self::add_runtime_dependency`].
+    ///
+    /// This is only a redirect, not an injection: `cargo` can't add dependencies from config,
+    /// so `name` must already be declared by a package, like an optional runtime dependency
+    /// enabled with [`CargoArgs::add_features`].
+    /// Use [`Self::add_runtime_dependency`] to add a dependency that isn't declared.
+    ///
+    /// As `[patch]`es must point to a different source,
+    /// crates.io can't be patched with a [`DependencySource::Version`].
+    pub fn patch_dependency(
+        &mut self,
+        name: &str,
+        source: DependencySource,
+        registry: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let config = patch_config(registry.unwrap_or("crates-io"), name, &source)?;
+        self.cargo_args.add_config(config);
+        Ok(())
     }
 
-    pub fn is_primary_package(&self) -> bool {
-        EnvVar::get_os("CARGO_PRIMARY_PACKAGE").is_some()
+    /// Build with a dedicated `cargo` profile `name`, defined with `--config`
+    /// as inheriting from `inherits` (like `dev`) with `settings` (like `("debug", true.into())`),
+    /// so that wrapped builds have predictable codegen settings
+    /// and don't thrash the user's `dev` or `release` outputs.
+    ///
+    /// This replaces any `--profile` or `--release` in [`Self::cargo_args`].
+    pub fn set_custom_profile(
+        &mut self,
+        name: &str,
+        inherits: &str,
+        settings: impl IntoIterator<Item = (impl AsRef<str>, toml_edit::Value)>,
+    ) {
+        let inherits = toml_edit::Value::from(inherits);
+        self.cargo_args
+            .add_config(format!("profile.{name}.inherits={inherits}"));
+        for (key, value) in settings {
+            let key = key.as_ref();
+            self.cargo_args
+                .add_config(format!("profile.{name}.{key}={value}"));
+        }
+        self.cargo_args.set_profile(name);
     }
 
-    pub fn is_bin_crate(&self) -> anyhow::Result<bool> {
-        todo!()
+    /// Restore the `Cargo.toml`s and `Cargo.lock` modified by [`Self::add_runtime_dependency`]
+    /// once [`Self::run_cargo_with_rustc_wrapper`] finishes, whether the build succeeds or fails,
+    /// (or when this is dropped if it never runs), so that user manifests aren't left dirty.
+    pub fn set_restore_manifests(&mut self, restore: bool) {
+        self.restore_manifests = restore;
     }
 
-    pub fn bin_crate_name(&self) -> Option<PathBuf> {
-        EnvVar::get_path("CARGO_BIN_NAME").map(|var| var.value)
+    /// Back up `path` (once) for [`Self::restore_manifests`].
+    fn back_up(&self, path: &Path) -> anyhow::Result<()> {
+        let mut backups = self.manifest_backups.borrow_mut();
+        if !backups.iter().any(|backup| backup.path() == path) {
+            backups.push(FileBackup::new(path)?);
+        }
+        Ok(())
     }
 
-    pub fn is_build_script(&self) -> anyhow::Result<bool> {
-        Ok(self.bin_crate_name().is_none() && self.is_bin_crate()?)
+    /// [`Self::restore_manifests`] after a build with `result`,
+    /// reporting a failure to restore only as a warning if the build failed,
+    /// so that the build's error (and exit code, see [`CommandFailed`]) is kept.
+    fn restore_manifests_after<T>(&self, result: anyhow::Result<T>) -> anyhow::Result<T> {
+        let restored = self.restore_manifests();
+        match (result, restored) {
+            (Err(e), Err(restore_error)) => {
+                eprintln!("warning: could not restore manifests: {restore_error:#}");
+                Err(e)
+            }
+            (result, restored) => {
+                restored?;
+                result
+            }
+        }
     }
 
-    pub fn rustc_args_os(self) -> Vec<OsString> {
-        let Self { mut args, sysroot } = self;
-        let sysroot = sysroot.value;
-        args.extend(["--sysroot".into(), sysroot.into()]);
-        args
+    /// Restore all manifests backed up because of [`Self::set_restore_manifests`],
+    /// even if an earlier one fails, and report the first error.
+    pub fn restore_manifests(&self) -> anyhow::Result<()> {
+        let backups = mem::take(&mut *self.manifest_backups.borrow_mut());
+        let mut result = Ok(());
+        for backup in backups.into_iter().rev() {
+            let restored = backup.restore();
+            if result.is_ok() {
+                result = restored;
+            }
+        }
+        result
     }
 
-    pub fn rustc_args(self) -> anyhow::Result<Vec<String>> {
-        let Self { args, sysroot } = self;
-        let mut args = args
-            .into_iter()
-            .map(|arg| arg.into_string())
-            .collect::<Result<Vec<_>, _>>()
-            .map_err(os_string_utf8_error)?;
-        let sysroot = sysroot
-            .value
-            .into_os_string()
-            .into_string()
-            .map_err(os_string_utf8_error)?;
-        args.extend(["--sysroot".into(), sysroot.into()]);
-        Ok(args)
+    /// The `cargo` config that `cargo` will use, including any `--config` args.
+    pub fn cargo_config(&self) -> anyhow::Result<CargoConfig> {
+        let cli_configs = self.cargo_args.configs().collect::<Vec<_>>();
+        CargoConfig::discover(&self.current_dir()?, &cli_configs).context(FailureKind::Config)
     }
 
-    pub fn run_rustc(self) -> anyhow::Result<()> {
-        WrappedCommand::rustc().run(|cmd| {
-            cmd.args(self.args);
-            Ok(())
-        })
+    /// The rustflags from [`Self::cargo_config`] for the (first) target being built.
+    fn config_rustflags(&self) -> anyhow::Result<RustFlags> {
+        let config = self.cargo_config()?;
+        let target = match self.cargo_args.targets().next() {
+            Some(target) => target
+                .to_str()
+                .ok_or_else(|| anyhow!("non-UTF-8 `--target`: {target:?}"))?
+                .to_owned(),
+            None => match config.get_str(&["build", "target"]) {
+                Some(target) => target,
+                None => self.rustc_version()?.host,
+            },
+        };
+        let rustc = self.query_command(WrappedCommand::rustc());
+        let cfgs = target_cfgs(rustc, Some(&target))?;
+        config.rustflags(&target, &cfgs)
     }
-}
 
-pub trait CargoRustcWrapper: Parser {
-    fn take_cargo_args(&mut self) -> Vec<OsString>;
+    /// Add `flags` to the `$RUSTFLAGS` (or `$CARGO_ENCODED_RUSTFLAGS`) for [`Self::run_cargo_with_rustc_wrapper`],
+    /// after any already set, so that they apply to all crates, not just wrapped ones.
+    ///
+    /// Flags can contain spaces, in which case `$CARGO_ENCODED_RUSTFLAGS` is used.
+    pub fn add_rustflags(&mut self, flags: impl IntoIterator<Item = impl Into<OsString>>) {
+        self.rustflags.extend(flags.into_iter().map(Into::into));
+    }
 
-    /// Run as a `cargo` wrapper/plugin, the default invocation.
-    fn wrap_cargo(self, wrapper: CargoWrapper) -> anyhow::Result<()>;
+    /// Add `--cfg {cfg}` to `$RUSTFLAGS` for [`Self::run_cargo_with_rustc_wrapper`],
+    /// so that all crates (not just wrapped ones) can conditionally compile against it.
+    ///
+    /// `cfg` is either a name or `name="value"`.
+    /// See [`RustcWrapper::add_cfg`] for only adding it to specific `rustc` invocations.
+    pub fn add_cfg(&mut self, cfg: impl Into<OsString>) {
+        self.rustflags.extend(["--cfg".into(), cfg.into()]);
+    }
 
-    /// Run as a `rustc` wrapper (a la `$RUSTC_WRAPPER`/[`RUSTC_WRAPPER_VAR`]).
-    fn wrap_rustc(wrapper: RustcWrapper) -> anyhow::Result<()>;
-}
+    /// Add `flags` to the `$RUSTDOCFLAGS` (or `$CARGO_ENCODED_RUSTDOCFLAGS`)
+    /// for [`Self::run_cargo_with_rustc_wrapper`], after any already set,
+    /// e.g. to document with the same `--cfg`s added with [`Self::add_cfg`].
+    pub fn add_rustdocflags(&mut self, flags: impl IntoIterator<Item = impl Into<OsString>>) {
+        self.rustdocflags.extend(flags.into_iter().map(Into::into));
+    }
 
-/// Run the current binary as either a `cargo` or `rustc` wrapper.
-pub fn wrap_cargo_or_rustc<T: CargoRustcWrapper>() -> anyhow::Result<()> {
-    let own_rustc_wrapper = RustcWrapperEnvVar {
-        key: RUSTC_WRAPPER_VAR,
-        value: env::current_exe()?,
-    };
-    let current_rustc_wrapper = EnvVar::get_path(own_rustc_wrapper.key);
+    /// Also run `rustdoc` through this binary (as `$RUSTDOC`) for `cargo doc` and doctests,
+    /// calling [`CargoRustcWrapper::wrap_rustdoc`], which passes it through by default.
+    ///
+    /// `cargo` runs `rustdoc` directly, not through `$RUSTC_WRAPPER`, so it's otherwise never seen.
+    pub fn set_wrap_rustdoc(&mut self, wrap: bool) {
+        self.wrap_rustdoc = wrap;
+    }
 
-    let wrapping_rustc = current_rustc_wrapper.as_ref() == Some(&own_rustc_wrapper);
-    if wrapping_rustc {
-        T::wrap_rustc(RustcWrapper::new()?)
-    } else {
-        let mut args = T::try_parse()?;
-        let cargo_args = args.take_cargo_args();
-        args.wrap_cargo(CargoWrapper::new(own_rustc_wrapper, cargo_args)?)
+    /// The real `rustdoc` for the `rustdoc` wrapper to run:
+    /// the one `cargo` would've used (see [`CargoConfig::rustdoc`]), or else the sysroot's.
+    fn real_rustdoc(&self) -> anyhow::Result<PathBuf> {
+        let own_exe = &self.rustc_wrapper.value;
+        if let Some(rustdoc) = self.cargo_config()?.rustdoc() {
+            if rustdoc != *own_exe {
+                return Ok(rustdoc);
+            }
+        }
+        let rustdoc = self
+            .sysroot
+            .value
+            .join("bin")
+            .join(format!("rustdoc{}", env::consts::EXE_SUFFIX));
+        Ok(if rustdoc.is_file() {
+            rustdoc
+        } else {
+            "rustdoc".into()
+        })
+    }
+
+    /// Add `--remap-path-prefix {from}={to}` to every wrapped `rustc` invocation,
+    /// e.g. to remap a temporary instrumented source dir back to the original project dir,
+    /// so that debuginfo and diagnostics point at the real files.
+    ///
+    /// Unlike `$RUSTFLAGS`, this works with paths containing spaces.
+    /// It's not applied to `rustc` invocations that are passed straight through
+    /// (see [`CargoRustcWrapper::wrap_rustc`]).
+    pub fn add_remap_path_prefix(&mut self, from: impl AsRef<Path>, to: impl AsRef<Path>) {
+        let mut mapping = from.as_ref().as_os_str().to_owned();
+        mapping.push("=");
+        mapping.push(to.as_ref());
+        self.remap_path_prefixes.push(mapping);
+    }
+
+    /// Restrict which crates the `rustc` wrapper should wrap,
+    /// checked with [`RustcWrapper::matches_crate_filter`].
+    pub fn set_crate_filter(&mut self, filter: CrateFilter) {
+        self.crate_filter = filter;
+    }
+
+    /// Add `--manifest-path` to `cmd` if it's known and `cmd` doesn't already have one,
+    /// before any `--`, as trailing args (e.g. for `cargo run`) aren't `cargo`'s.
+    ///
+    /// If there is a `--`, `cmd` is rebuilt (see [`command_with_args`]),
+    /// so its stdio should be set afterwards.
+    fn forward_manifest_path(&self, cmd: &mut Command) -> anyhow::Result<()> {
+        let Some(manifest_path) = self.manifest_path() else {
+            return Ok(());
+        };
+        let mut args = cmd.get_args().map(ToOwned::to_owned).collect::<Vec<_>>();
+        let end = args.iter().position(|arg| arg == "--");
+        let cargo_args = &args[..end.unwrap_or(args.len())];
+        if flag_values(cargo_args, "--manifest-path").next().is_some() {
+            return Ok(());
+        }
+        let forwarded = ["--manifest-path".into(), manifest_path.into()];
+        match end {
+            None => {
+                cmd.args(forwarded);
+            }
+            Some(end) => {
+                args.splice(end..end, forwarded);
+                *cmd = command_with_args(cmd, args);
+            }
+        }
+        Ok(())
+    }
+
+    /// Run `cargo`, configured by `f`, forwarding [`Self::verbosity`].
+    ///
+    /// [`Self::manifest_path`] is forwarded too, before any `--` that `f` adds,
+    /// in which case the stdio `f` sets isn't kept.
+    ///
+    /// If `cargo` fails, this fails with [`CommandFailed`] (see [`exit_with`]).
+    pub fn run_cargo(
+        &self,
+        f: impl FnOnce(&mut Command) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        let (cmd, status) = self.cargo_status(|cmd| {
+            // Global flags go before the subcommand, which `f` adds.
+            cmd.args(self.verbosity().cargo_flag());
+            f(cmd)
+        })?;
+        self.check_status(&cmd, status)
+    }
+
+    /// Like [`Self::run_cargo`], but capture `cargo`'s stdout and stderr
+    /// and return them instead of failing with [`CommandFailed`],
+    /// e.g. for `cargo pkgid` or other queries.
+    pub fn run_cargo_output(
+        &self,
+        f: impl FnOnce(&mut Command) -> anyhow::Result<()>,
+    ) -> anyhow::Result<Output> {
+        let (_, output) = self.cargo_output(f)?;
+        Ok(output)
+    }
+
+    /// Like [`Self::run_cargo_output`], but also return the `cargo` command that was run.
+    fn cargo_output(
+        &self,
+        f: impl FnOnce(&mut Command) -> anyhow::Result<()>,
+    ) -> anyhow::Result<(Command, Output)> {
+        self.cargo_status_with(
+            |cmd| {
+                cmd.args(self.verbosity().cargo_flag());
+                f(cmd)
+            },
+            |cmd| Ok(signals::output(cmd)?),
+        )
+    }
+
+    fn cargo_status(
+        &self,
+        f: impl FnOnce(&mut Command) -> anyhow::Result<()>,
+    ) -> anyhow::Result<(Command, ExitStatus)> {
+        self.cargo_status_with(f, |cmd| {
+            if self.dry_run {
+                return Ok(self.print_dry_run(cmd));
+            }
+            self.cargo_status_as(CommandKind::Cargo, cmd)
+        })
+    }
+
+    /// Like [`Self::cargo_status`], but `run` runs the configured `cmd`.
+    fn cargo_status_with<R>(
+        &self,
+        f: impl FnOnce(&mut Command) -> anyhow::Result<()>,
+        run: impl FnOnce(&mut Command) -> anyhow::Result<R>,
+    ) -> anyhow::Result<(Command, R)> {
+        let color = if self.should_color() {
+            ColorChoice::Always
+        } else {
+            ColorChoice::Never
+        };
+        let mut cmd = WrappedCommand::cargo().command();
+        self.configure_command(&mut cmd);
+        cmd.env("CARGO_TERM_COLOR", color.name());
+        TypedEnvVar::new(COLOR_VAR).set_on(&mut cmd, &color);
+        f(&mut cmd)?;
+        self.forward_manifest_path(&mut cmd)?;
+        let status = run(&mut cmd)?;
+        Ok((cmd, status))
+    }
+
+    /// Set a `hook` to run once right before `cargo` is spawned
+    /// by the next [`Self::run_cargo_with_rustc_wrapper`] (or [`Self::run_cargo_json`]),
+    /// replacing any previous one.
+    ///
+    /// The sysroot and toolchain are already resolved by then,
+    /// so this is the place for preparing files the `rustc` wrapper reads,
+    /// cleaning stale outputs, or validating the environment.
+    pub fn set_pre_build(&mut self, hook: impl FnOnce(&Self) -> anyhow::Result<()> + 'static) {
+        *self.pre_build.get_mut() = Some(Box::new(hook));
+    }
+
+    /// Set a `hook` to run once after the next [`Self::run_cargo_with_rustc_wrapper`]
+    /// (or [`Self::run_cargo_json`]) succeeds, replacing any previous one.
+    ///
+    /// This is the place for finalization steps over the whole build,
+    /// like aggregating per-crate outputs, rather than in each `rustc` invocation.
+    /// `hook` can use [`Self::built_artifacts`], and `Self::cargo_metadata` with the `metadata` feature.
+    pub fn set_post_build(&mut self, hook: impl FnOnce(&Self) -> anyhow::Result<()> + 'static) {
+        *self.post_build.get_mut() = Some(Box::new(hook));
+    }
+
+    /// Run `cargo` with [`Self::cargo_args`] and this binary as the `rustc` wrapper.
+    ///
+    /// `f` can further configure `cmd`, but args should be added through [`Self::cargo_args_mut`],
+    /// since anything `f` adds comes after the `cargo` args, including any trailing `--` ones.
+    ///
+    /// Without a subcommand, like for `-- --help` or `-- --version`,
+    /// this just runs `cargo` with its global args, as nothing is built.
+    pub fn run_cargo_with_rustc_wrapper(
+        &self,
+        f: impl FnOnce(&mut Command) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        if self.cargo_args.subcommand().is_none() && !self.cargo_args.global_args().is_empty() {
+            return self.run_cargo(|cmd| {
+                cmd.args(self.cargo_args.global_args());
+                Ok(())
+            });
+        }
+        self.run_cargo_with_rustc_wrapper_as(self.cargo_args.clone(), f, |cmd| {
+            self.cargo_status_as(CommandKind::Build, cmd)
+        })
+    }
+
+    /// Like [`Self::run_cargo_with_rustc_wrapper`],
+    /// but parse `cargo`'s `--message-format=json` messages and pass each to `on_message`.
+    ///
+    /// Like with `--message-format=json-render-diagnostics`,
+    /// diagnostics are still rendered to the terminal (after `on_message`),
+    /// but `cargo` doesn't emit `compiler-message`s for those, so a JSON format is used instead.
+    /// Any `--message-format` in [`Self::cargo_args`] is replaced.
+    pub fn run_cargo_json(
+        &self,
+        f: impl FnOnce(&mut Command) -> anyhow::Result<()>,
+        on_message: impl FnMut(&CargoMessage) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        self.artifacts.borrow_mut().clear();
+        self.run_cargo_json_as(self.cargo_args.clone(), f, on_message)
+    }
+
+    /// [`Self::run_cargo_json`] with `cargo_args`, without clearing [`Self::built_artifacts`] first.
+    fn run_cargo_json_as(
+        &self,
+        mut cargo_args: CargoArgs,
+        f: impl FnOnce(&mut Command) -> anyhow::Result<()>,
+        mut on_message: impl FnMut(&CargoMessage) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        cargo_args.set_message_format(if self.should_color() {
+            "json-diagnostic-rendered-ansi"
+        } else {
+            "json"
+        });
+        let f = |cmd: &mut Command| {
+            cmd.env(JSON_MESSAGES_VAR, "1");
+            f(cmd)
+        };
+        self.run_cargo_with_rustc_wrapper_as(cargo_args, f, |cmd| {
+            cargo_messages::run_streaming(cmd, |message| {
+                if let CargoMessage::CompilerArtifact(artifact) = message {
+                    self.artifacts.borrow_mut().push(artifact.clone());
+                }
+                on_message(message)
+            })
+        })
+    }
+
+    /// Run `cargo test` with [`Self::cargo_args`] by building the tests
+    /// with `cargo test --no-run` through the `rustc` wrapper (like [`Self::run_cargo_json`]),
+    /// and then running each test binary here, so that running them can be wrapped too.
+    ///
+    /// `wrap_test` gets each test binary's [`Artifact`] (with its package and target)
+    /// and the `cmd` to run it with the test name filter and test args (after `--`)
+    /// in its package's dir, like `cargo test`,
+    /// and returns the command to actually run, e.g. under a profiler or with extra env vars.
+    /// Like `cargo test`, `cmd` has the `CARGO_MANIFEST_DIR`, `CARGO_PKG_*`, `CARGO_CRATE_NAME`,
+    /// `CARGO_BIN_EXE_*`, and `OUT_DIR` env vars the test was compiled with.
+    ///
+    /// Like `cargo test`, this stops at the first failing test binary unless `--no-fail-fast` is passed,
+    /// and then fails with its status (see [`CommandFailed`]).
+    /// Doc tests aren't run, as `--no-run` doesn't build them.
+    ///
+    /// For `cargo nextest run`, which runs the test binaries itself,
+    /// the tests are built and run by `cargo nextest` through the `rustc` wrapper,
+    /// so `wrap_test` isn't called.
+    pub fn run_cargo_test(
+        &self,
+        f: impl FnOnce(&mut Command) -> anyhow::Result<()>,
+        mut wrap_test: impl FnMut(&Artifact, Command) -> anyhow::Result<Command>,
+    ) -> anyhow::Result<()> {
+        if self.cargo_args.subcommand() == Some(OsStr::new("nextest")) {
+            self.artifacts.borrow_mut().clear();
+            return self.run_cargo_with_rustc_wrapper(f);
+        }
+        let mut cargo_args = self.cargo_args.clone();
+        cargo_args.set_subcommand("test");
+        let test_name = cargo_args.positional_args().next().map(ToOwned::to_owned);
+        let test_args = cargo_args.take_trailing_args().unwrap_or_default();
+        let no_run = cargo_args.has_flag("--no-run");
+        if !no_run {
+            cargo_args.add_args(["--no-run"]);
+        }
+        let no_fail_fast = cargo_args.has_flag("--no-fail-fast");
+        self.artifacts.borrow_mut().clear();
+        let mut out_dirs = BTreeMap::new();
+        self.run_cargo_json_as(cargo_args, f, |message| {
+            if let CargoMessage::BuildScriptExecuted(script) = message {
+                out_dirs.insert(script.package_id.clone(), script.out_dir.clone());
+            }
+            Ok(())
+        })?;
+        if no_run || self.dry_run {
+            return Ok(());
+        }
+        let artifacts = self.built_artifacts().to_vec();
+        let tests = artifacts
+            .iter()
+            .filter(|artifact| artifact.profile.test && artifact.executable.is_some());
+        let mut failure = None;
+        for test in tests {
+            let executable = test.executable.as_deref().expect("filtered above");
+            if self.verbosity() > Verbosity::Quiet {
+                eprintln!("     Running {}", executable.display());
+            }
+            let mut cmd = Command::new(executable);
+            cmd.args(&test_name).args(&test_args);
+            set_test_env(&mut cmd, test, &artifacts, &out_dirs);
+            let mut cmd = wrap_test(test, cmd)?;
+            let status = stdio::status(&mut cmd, self.stdio(CommandKind::Run))
+                .with_context(|| format!("could not run {cmd:?}"))?;
+            if !status.success() {
+                if !no_fail_fast {
+                    return self.check_status(&cmd, status);
+                }
+                failure.get_or_insert((cmd, status));
+            }
+        }
+        match failure {
+            Some((cmd, status)) => self.check_status(&cmd, status),
+            None => Ok(()),
+        }
+    }
+
+    /// Run `cargo run` with [`Self::cargo_args`] by building the binary
+    /// with `cargo build` through the `rustc` wrapper (like [`Self::run_cargo_json`]),
+    /// and then running it here, so that it can be intercepted in between.
+    /// Like `cargo run`, a package with several binaries runs its `default-run` one.
+    ///
+    /// `wrap_run` gets the binary's [`Artifact`] (see [`Artifact::executable`])
+    /// and the `cmd` to run it with the program args (after `--`), like `cargo run`,
+    /// and returns the command to actually run (e.g. with extra args or env vars),
+    /// or [`None`] if it replaced running the binary entirely.
+    pub fn run_cargo_run(
+        &self,
+        f: impl FnOnce(&mut Command) -> anyhow::Result<()>,
+        wrap_run: impl FnOnce(&Artifact, Command) -> anyhow::Result<Option<Command>>,
+    ) -> anyhow::Result<()> {
+        let mut cargo_args = self.cargo_args.clone();
+        cargo_args.set_subcommand("build");
+        let program_args = cargo_args.take_trailing_args().unwrap_or_default();
+        self.artifacts.borrow_mut().clear();
+        self.run_cargo_json_as(cargo_args, f, |_| Ok(()))?;
+        if self.dry_run {
+            return Ok(());
+        }
+        let binaries = self
+            .built_artifacts()
+            .iter()
+            .filter(|artifact| {
+                let kinds = &artifact.target.kind;
+                !artifact.profile.test
+                    && artifact.executable.is_some()
+                    && kinds.iter().any(|kind| kind == "bin" || kind == "example")
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        let binary = match binaries.as_slice() {
+            [binary] => binary,
+            [] => bail!("`cargo run` built no binary to run"),
+            [first, rest @ ..] => {
+                // Like `cargo run`, use the package's `default-run` if it has several binaries.
+                let default_run = if rest
+                    .iter()
+                    .all(|binary| binary.manifest_path == first.manifest_path)
+                {
+                    default_run(&first.manifest_path)?
+                } else {
+                    None
+                };
+                let default_binary = default_run.and_then(|default_run| {
+                    binaries.iter().find(|binary| {
+                        binary.target.name == default_run
+                            && binary.target.kind.iter().any(|kind| kind == "bin")
+                    })
+                });
+                match default_binary {
+                    Some(binary) => binary,
+                    None => bail!(
+                        "`cargo run` could not determine which binary to run, \
+                        pass `--bin` or `--example`, or set `default-run` in `Cargo.toml`"
+                    ),
+                }
+            }
+        };
+        let executable = binary.executable.as_deref().expect("filtered above");
+        if self.verbosity() > Verbosity::Quiet {
+            eprintln!("     Running `{}`", executable.display());
+        }
+        let mut cmd = Command::new(executable);
+        cmd.args(program_args);
+        if let Some(dir) = &self.current_dir {
+            cmd.current_dir(dir);
+        }
+        let Some(mut cmd) = wrap_run(binary, cmd)? else {
+            return Ok(());
+        };
+        let status = stdio::status(&mut cmd, self.stdio(CommandKind::Run))
+            .with_context(|| format!("could not run {cmd:?}"))?;
+        self.check_status(&cmd, status)
+    }
+
+    /// Run a wrapped build (like [`Self::run_cargo_json`]) for each of `variants`,
+    /// like for different targets or feature sets,
+    /// sharing the resolved sysroot and toolchain and stopping at the first failure.
+    ///
+    /// Each variant modifies a copy of [`Self::cargo_args`] for its build,
+    /// and `f` configures each build's `cmd` like in [`Self::run_cargo_with_rustc_wrapper`].
+    /// [`Self::built_artifacts`] then has the artifacts of all the builds,
+    /// and the [post-build hook](Self::set_post_build) runs once after the last one,
+    /// which is where to aggregate the tool's own per-build outputs.
+    /// With [`Self::set_restore_manifests`], manifests are restored once after all the builds.
+    pub fn run_cargo_matrix(
+        &mut self,
+        variants: impl IntoIterator<Item = impl FnOnce(&mut CargoArgs)>,
+        mut f: impl FnMut(&mut Command) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        self.artifacts.get_mut().clear();
+        let post_build = self.post_build.take();
+        let original_args = self.cargo_args.clone();
+        let in_matrix = InMatrix::enter(&self.in_matrix);
+        let mut result = Ok(());
+        for variant in variants {
+            variant(&mut self.cargo_args);
+            result = self.run_cargo_json_as(self.cargo_args.clone(), &mut f, |_| Ok(()));
+            self.cargo_args = original_args.clone();
+            if result.is_err() {
+                break;
+            }
+        }
+        drop(in_matrix);
+        self.restore_manifests_after(result)?;
+        if let Some(hook) = post_build.filter(|_| !self.dry_run) {
+            hook(self)?;
+        }
+        Ok(())
+    }
+
+    /// The artifacts built (or already fresh) by the last [`Self::run_cargo_json`]
+    /// (or all of [`Self::run_cargo_matrix`]'s builds),
+    /// for post-build steps like patching or packaging binaries.
+    ///
+    /// See [`Artifact::filenames`] and [`Artifact::executable`] for the produced files.
+    pub fn built_artifacts(&self) -> Ref<'_, [Artifact]> {
+        Ref::map(self.artifacts.borrow(), Vec::as_slice)
+    }
+
+    /// Like [`Self::run_cargo_with_rustc_wrapper`], but pass each line of `cargo`'s stdout and stderr
+    /// (including from the `rustc` wrapper) to `on_line` as it comes,
+    /// e.g. to watch for specific warnings or errors.
+    ///
+    /// The output is still echoed to the terminal, before `on_line` sees it.
+    pub fn run_cargo_streaming(
+        &self,
+        f: impl FnOnce(&mut Command) -> anyhow::Result<()>,
+        on_line: impl FnMut(OutputStream, &[u8]) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        self.run_cargo_with_rustc_wrapper_as(self.cargo_args.clone(), f, |cmd| {
+            line_stream::run(cmd, None, on_line)
+        })
+    }
+
+    fn run_cargo_with_rustc_wrapper_as(
+        &self,
+        mut cargo_args: CargoArgs,
+        f: impl FnOnce(&mut Command) -> anyhow::Result<()>,
+        run: impl FnOnce(&mut Command) -> anyhow::Result<ExitStatus>,
+    ) -> anyhow::Result<()> {
+        ensure!(
+            cargo_args.subcommand().is_some(),
+            "no `cargo` subcommand given after `--`, like `-- build`"
+        );
+        // Taken first so it runs once and so `hook` can borrow `self`.
+        let pre_build = self.pre_build.take();
+        if let Some(hook) = pre_build.filter(|_| !self.dry_run) {
+            hook(self)?;
+        }
+        if self.dry_run {
+            self.print_dry_run_units(cargo_args.clone());
+        }
+        // Kept alive until `cargo` is done.
+        #[cfg(feature = "metadata")]
+        let metadata_snapshot = self
+            .metadata
+            .get()
+            .map(Metadata::write_snapshot)
+            .transpose()?;
+        let target_dir = match &self.target_dir_suffix {
+            Some(_) => {
+                let target_dir = self.target_dir()?;
+                // `--target-dir` takes precedence over `$CARGO_TARGET_DIR`.
+                cargo_args.remove_flag("--target-dir", None);
+                Some(target_dir)
+            }
+            None => None,
+        };
+        let previous_wrapper = self.previous_rustc_wrapper()?;
+        let real_rustdoc = self.wrap_rustdoc.then(|| self.real_rustdoc()).transpose()?;
+        let targets = self.targets()?;
+        self.check_target_sysroots()?;
+        let result = self.cargo_status_with(
+            |cmd| {
+                cmd.args(cargo_args.to_args());
+                if let Some(target_dir) = &target_dir {
+                    cmd.env("CARGO_TARGET_DIR", target_dir);
+                }
+                self.rustc_wrapper.set_on(cmd);
+                if let Some(previous_wrapper) = &previous_wrapper {
+                    cmd.env(PREVIOUS_WRAPPER_VAR, previous_wrapper);
+                }
+                TypedEnvVar::new(VERBOSITY_VAR).set_on(cmd, &self.verbosity());
+                TypedEnvVar::new(RUSTC_TIMEOUT_VAR).set_or_unset_on(
+                    cmd,
+                    self.rustc_timeout
+                        .map(|timeout| timeout.as_secs_f64())
+                        .as_ref(),
+                );
+                if !self.rustc_limits.is_empty() {
+                    cmd.env(RUSTC_LIMITS_VAR, self.rustc_limits.to_env_value());
+                }
+                if let Some(ice_dir) = &self.ice_dir {
+                    cmd.env(ICE_DIR_VAR, ice_dir);
+                }
+                if let Some(failure_log) = &self.failure_log {
+                    cmd.env(FAILURE_LOG_VAR, failure_log);
+                }
+                #[cfg(feature = "metadata")]
+                if let Some(snapshot) = &metadata_snapshot {
+                    cmd.env(METADATA_VAR, snapshot.path());
+                }
+                if let Some(rustc) = &self.resolved_rustc {
+                    cmd.env(RUSTC_VAR, rustc);
+                }
+                if let Some(real_rustdoc) = &real_rustdoc {
+                    let rustdoc_wrapper = rustdoc_wrapper_path(&self.rustc_wrapper.value);
+                    cmd.env(RUSTDOC_VAR, &rustdoc_wrapper);
+                    cmd.env(RUSTDOC_WRAPPER_VAR, &rustdoc_wrapper);
+                    cmd.env(REAL_RUSTDOC_VAR, real_rustdoc);
+                }
+                self.sysroot.set_on(cmd);
+                // The `rustc` wrapper (this binary) may link to `rustc_private` dylibs.
+                let mut dylib_path = PathListVar::from_command(cmd, dylib_path_var());
+                dylib_path.prepend(self.sysroot_dylib_dir());
+                dylib_path.set_on(cmd)?;
+                if !targets.is_empty() {
+                    cmd.env(
+                        TARGETS_VAR,
+                        targets.join(&char::from(ENV_LIST_SEPARATOR).to_string()),
+                    );
+                }
+                let package_selection = self.package_selection();
+                if package_selection != PackageSelection::Default {
+                    cmd.env(PACKAGE_SELECTION_VAR, package_selection.to_env_value());
+                }
+                if !self.crate_filter.is_empty() {
+                    cmd.env(CRATE_FILTER_VAR, self.crate_filter.to_env_value());
+                }
+                if !self.remap_path_prefixes.is_empty() {
+                    let separator = OsString::from(char::from(ENV_LIST_SEPARATOR).to_string());
+                    cmd.env(
+                        REMAP_PATH_PREFIX_VAR,
+                        self.remap_path_prefixes.join(separator.as_os_str()),
+                    );
+                }
+                f(cmd)?;
+                let mut flags = Vec::new();
+                if !self.rustflags.is_empty() {
+                    // Append after `f` so we don't clobber or get clobbered by its `$RUSTFLAGS`.
+                    // Setting either var makes `cargo` ignore the config's rustflags, so start with those.
+                    let mut rustflags = match RustFlags::from_command(cmd) {
+                        Some(rustflags) => rustflags,
+                        None => self.config_rustflags()?,
+                    };
+                    rustflags.extend(self.rustflags.iter().cloned());
+                    flags.push(rustflags);
+                }
+                if !self.rustdocflags.is_empty() {
+                    let mut rustdocflags = match RustFlags::rustdoc_from_command(cmd) {
+                        Some(rustdocflags) => rustdocflags,
+                        None => self.cargo_config()?.rustdocflags(),
+                    };
+                    rustdocflags.extend(self.rustdocflags.iter().cloned());
+                    flags.push(rustdocflags);
+                }
+                self.check_cargo_support(&cargo_args, &flags)
+                    .context(FailureKind::Toolchain)?;
+                for flags in &flags {
+                    flags.set_on(cmd);
+                }
+                Ok(())
+            },
+            |cmd| {
+                if self.dry_run {
+                    return Ok(self.print_dry_run(cmd));
+                }
+                run(cmd)
+            },
+        );
+        let result = result.and_then(|(cmd, status)| self.check_status(&cmd, status));
+        if self.in_matrix.get() {
+            result?;
+        } else {
+            self.restore_manifests_after(result)?;
+        }
+        let post_build = self.post_build.take();
+        if let Some(hook) = post_build.filter(|_| !self.dry_run) {
+            hook(self)?;
+        }
+        Ok(())
+    }
+}
+
+/// Marks a [`CargoWrapper::run_cargo_matrix`] as running until dropped,
+/// even if a variant panics.
+struct InMatrix<'a>(&'a Cell<bool>);
+
+impl<'a> InMatrix<'a> {
+    fn enter(in_matrix: &'a Cell<bool>) -> Self {
+        in_matrix.set(true);
+        Self(in_matrix)
+    }
+}
+
+impl Drop for InMatrix<'_> {
+    fn drop(&mut self) {
+        self.0.set(false);
+    }
+}
+
+impl Drop for CargoWrapper {
+    fn drop(&mut self) {
+        if let Err(e) = self.restore_manifests() {
+            eprintln!("error restoring manifests: {e:#}");
+        }
+    }
+}
+
+fn os_string_utf8_error(s: OsString) -> anyhow::Error {
+    anyhow!("non-UTF-8 OsString: {s:?}")
+}
+
+/// What a `rustc` invocation is compiling for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildKind {
+    /// Compiled for and run on the host, like build scripts, proc macros, and their dependencies.
+    Host,
+    /// Compiled for the target being built.
+    Target,
+}
+
+/// What to do with our `--sysroot` if `rustc` was already passed one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SysrootPolicy {
+    /// Keep the existing `--sysroot` and don't add ours.
+    #[default]
+    KeepExisting,
+    /// Replace the existing `--sysroot` with ours.
+    Override,
+}
+
+/// How the current binary was invoked in place of `rustc`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RustcInvocation {
+    /// As `$RUSTC_WRAPPER` or `$RUSTC_WORKSPACE_WRAPPER`,
+    /// where the path to the real `rustc` is passed as the first arg.
+    Wrapper,
+    /// As `$RUSTC` itself, as some build systems do,
+    /// where the args are just the `rustc` args.
+    Rustc,
+}
+
+type ArgTransform = Box<dyn FnOnce(&mut Vec<OsString>)>;
+
+pub struct RustcWrapper {
+    invocation: RustcInvocation,
+    /// The real `rustc` that `cargo` passed as the first argument to `$RUSTC_WRAPPER`.
+    rustc: PathBuf,
+    args: Vec<OsString>,
+    /// From `$RUST_SYSROOT`, set by [`CargoWrapper::run_cargo_with_rustc_wrapper`],
+    /// or else queried lazily from [`Self::rustc_path`] (only as `$RUSTC` outside of it).
+    sysroot: OnceCell<SysrootEnvVar>,
+    sysroot_policy: SysrootPolicy,
+    cargo_env: CargoEnv,
+    arg_transforms: Vec<ArgTransform>,
+    /// Extra env vars to set for [`Self::run_rustc`].
+    envs: Vec<(OsString, OsString)>,
+    /// Another `$RUSTC_WRAPPER` to run `rustc` through.
+    previous_wrapper: Option<PathBuf>,
+    /// See [`Self::current_dir`].
+    current_dir: Option<PathBuf>,
+    verbosity: Verbosity,
+    /// Whether to use colors, as resolved by the `cargo` wrapper.
+    color: bool,
+    /// Whether the `cargo` wrapper is parsing `cargo`'s JSON messages.
+    json_messages: bool,
+    /// See [`Self::timeout`].
+    timeout: Option<Duration>,
+    /// See [`Self::limits`].
+    limits: ResourceLimits,
+    /// See [`Self::ice_dir`].
+    ice_dir: Option<PathBuf>,
+    /// See [`CargoWrapper::set_failure_log`].
+    failure_log: Option<PathBuf>,
+    /// Connected to lazily for [`Self::acquire_job`].
+    jobserver: OnceCell<Option<jobserver::Client>>,
+    /// Queried lazily from `rustc -vV`.
+    version: OnceCell<ToolVersion>,
+    /// Read lazily from the snapshot passed by the `cargo` wrapper.
+    #[cfg(feature = "metadata")]
+    metadata: OnceCell<Option<Metadata>>,
+}
+
+impl RustcWrapper {
+    fn new(invocation: RustcInvocation) -> anyhow::Result<Self> {
+        let mut args = env::args_os().skip(1);
+        let sysroot = SysrootEnvVar::get_path(SYSROOT_VAR);
+        let (rustc, sysroot) = match invocation {
+            RustcInvocation::Wrapper => {
+                let rustc = args.next().map(PathBuf::from).ok_or_else(|| {
+                    anyhow!("`cargo` should've passed the `rustc` path to `${RUSTC_WRAPPER_VAR}`")
+                })?;
+                let sysroot = sysroot.ok_or_else(|| {
+                    anyhow!(
+                        "the `cargo` wrapper should've set `${SYSROOT_VAR}` for the `rustc` wrapper"
+                    )
+                })?;
+                (rustc, Some(sysroot))
+            }
+            RustcInvocation::Rustc => {
+                // We are `$RUSTC`, so the real `rustc` is the sysroot's,
+                // or if that's not known, the one on `$PATH`,
+                // whose sysroot is only queried if needed (see `Self::sysroot`),
+                // so that every invocation doesn't pay for it.
+                let rustc = match &sysroot {
+                    Some(sysroot) => sysroot
+                        .value
+                        .join("bin")
+                        .join(format!("rustc{}", env::consts::EXE_SUFFIX)),
+                    None => PathBuf::from("rustc"),
+                };
+                (rustc, sysroot)
+            }
+        };
+        let args = argfile::expand(args.collect())?;
+        let mut this = Self::from_parts(invocation, rustc, args, sysroot, CargoEnv::from_env()?);
+        this.previous_wrapper = EnvVar::get_path(PREVIOUS_WRAPPER_VAR).map(|var| var.value);
+        if let Some(verbosity) = TypedEnvVar::<Verbosity>::new(VERBOSITY_VAR).get()? {
+            this.verbosity = verbosity;
+        }
+        this.color = TypedEnvVar::<ColorChoice>::new(COLOR_VAR)
+            .get()?
+            .unwrap_or_default()
+            .should_color();
+        this.json_messages = EnvVar::get_os(JSON_MESSAGES_VAR).is_some();
+        if let Some(timeout) = TypedEnvVar::<f64>::new(RUSTC_TIMEOUT_VAR).get()? {
+            let timeout = Duration::try_from_secs_f64(timeout)
+                .with_context(|| format!("invalid `${RUSTC_TIMEOUT_VAR}`"))?;
+            this.timeout = Some(timeout);
+        }
+        if let Ok(var) = EnvVar::get(RUSTC_LIMITS_VAR) {
+            this.limits = ResourceLimits::from_env_value(&var.value)
+                .with_context(|| format!("invalid `${RUSTC_LIMITS_VAR}`"))?;
+        }
+        this.ice_dir = EnvVar::get_path(ICE_DIR_VAR).map(|var| var.value);
+        this.failure_log = EnvVar::get_path(FAILURE_LOG_VAR).map(|var| var.value);
+        Ok(this)
+    }
+
+    fn from_parts(
+        invocation: RustcInvocation,
+        rustc: PathBuf,
+        args: Vec<OsString>,
+        sysroot: Option<SysrootEnvVar>,
+        cargo_env: CargoEnv,
+    ) -> Self {
+        Self {
+            invocation,
+            rustc,
+            args,
+            sysroot: sysroot.map(OnceCell::from).unwrap_or_default(),
+            sysroot_policy: Default::default(),
+            cargo_env,
+            arg_transforms: Vec::new(),
+            envs: Vec::new(),
+            previous_wrapper: None,
+            current_dir: None,
+            verbosity: Verbosity::default(),
+            color: false,
+            json_messages: false,
+            timeout: None,
+            limits: ResourceLimits::default(),
+            ice_dir: None,
+            failure_log: None,
+            jobserver: OnceCell::new(),
+            version: OnceCell::new(),
+            #[cfg(feature = "metadata")]
+            metadata: OnceCell::new(),
+        }
+    }
+
+    /// How the current binary was invoked in place of `rustc`.
+    pub fn invocation(&self) -> RustcInvocation {
+        self.invocation
+    }
+
+    /// The path to the real `rustc` being wrapped.
+    ///
+    /// For [`RustcInvocation::Wrapper`], this is the first arg passed by `cargo`.
+    /// For [`RustcInvocation::Rustc`], this is the sysroot's `rustc`.
+    pub fn rustc_path(&self) -> &Path {
+        &self.rustc
+    }
+
+    /// The sysroot's dylib directory (`lib`, or `bin` on Windows).
+    ///
+    /// [`CargoWrapper::run_cargo_with_rustc_wrapper`] already put this
+    /// on the dynamic loader's path, so it's inherited by anything spawned from here.
+    pub fn sysroot_dylib_dir(&self) -> anyhow::Result<PathBuf> {
+        Ok(sysroot_dylib_dir(self.sysroot()?))
+    }
+
+    /// The sysroot of [`Self::rustc_path`], passed from the `cargo` wrapper,
+    /// or else queried (once) from `rustc`.
+    fn sysroot(&self) -> anyhow::Result<&Path> {
+        if let Some(sysroot) = self.sysroot.get() {
+            return Ok(&sysroot.value);
+        }
+        let sysroot = SysrootEnvVar {
+            key: SYSROOT_VAR,
+            value: resolve_sysroot(&WrappedCommand {
+                path: self.rustc.clone(),
+                current_dir: None,
+            })?,
+        };
+        Ok(&self.sysroot.get_or_init(|| sysroot).value)
+    }
+
+    /// The `CARGO_*` env vars `cargo` set for this `rustc` invocation.
+    pub fn cargo_env(&self) -> &CargoEnv {
+        &self.cargo_env
+    }
+
+    pub fn is_primary_package(&self) -> bool {
+        self.cargo_env.primary_package
+    }
+
+    /// The package that the crate being compiled belongs to,
+    /// so that multi-package workspaces can apply per-package policies.
+    pub fn package_id(&self) -> Option<PackageId> {
+        self.cargo_env.package_id()
+    }
+
+    /// The name of the package that the crate being compiled belongs to.
+    pub fn package_name(&self) -> Option<&str> {
+        self.cargo_env.pkg_name.as_deref()
+    }
+
+    /// The version of the package that the crate being compiled belongs to.
+    pub fn package_version(&self) -> Option<&str> {
+        self.cargo_env.pkg_version.as_deref()
+    }
+
+    /// The [`CrateFilter`] set by [`CargoWrapper::set_crate_filter`].
+    pub fn crate_filter(&self) -> anyhow::Result<CrateFilter> {
+        let filter = match EnvVar::get(CRATE_FILTER_VAR) {
+            Ok(var) => CrateFilter::from_env_value(&var.value),
+            Err(env::VarError::NotPresent) => CrateFilter::default(),
+            Err(e) => return Err(e).context(format!("invalid `${CRATE_FILTER_VAR}`")),
+        };
+        Ok(filter)
+    }
+
+    /// Whether the crate being compiled matches the [`CrateFilter`]
+    /// set by [`CargoWrapper::set_crate_filter`] (trivially true if none was set).
+    ///
+    /// This is independent of [`Self::is_primary_package`],
+    /// but never matches [`Self::is_build_std_crate`]s, as wrapping `std` itself is rarely wanted.
+    pub fn matches_crate_filter(&self) -> anyhow::Result<bool> {
+        if self.is_build_std_crate() {
+            return Ok(false);
+        }
+        Ok(self.crate_filter()?.matches(&self.cargo_env))
+    }
+
+    /// The [`PackageSelection`] from [`CargoWrapper::package_selection`].
+    pub fn package_selection(&self) -> PackageSelection {
+        match EnvVar::get(PACKAGE_SELECTION_VAR) {
+            Ok(var) => PackageSelection::from_env_value(&var.value),
+            Err(_) => PackageSelection::Default,
+        }
+    }
+
+    /// Whether the crate being compiled belongs to exactly the packages the user asked to build
+    /// (see [`Self::package_selection`]), e.g. to only instrument those.
+    ///
+    /// This fails on package specs it can't check, like URL specs (see [`PackageSelection::matches`]).
+    pub fn is_selected_package(&self) -> anyhow::Result<bool> {
+        self.package_selection().matches(&self.cargo_env)
+    }
+
+    /// Whether this is a `std` crate (like `core`, `alloc`, or `std`) being built from source
+    /// because of `-Z build-std` (see [`CargoWrapper::is_build_std`]).
+    pub fn is_build_std_crate(&self) -> bool {
+        // `cargo` only passes this for `std` crates.
+        flag_values(&self.args, "-Z")
+            .chain(
+                self.args
+                    .iter()
+                    .filter_map(|arg| os_str_strip_prefix(arg, "-Z")),
+            )
+            .any(|flag| flag == "force-unstable-if-unmarked")
+    }
+
+    /// The `--crate-type`s passed to `rustc` (a crate can have more than one).
+    ///
+    /// If empty, `rustc` defaults to [`CrateType::Bin`] (unless there's a `#![crate_type]`),
+    /// but `cargo` always passes them for real compilations.
+    pub fn crate_types(&self) -> anyhow::Result<Vec<CrateType>> {
+        rustc_args::crate_types(&self.args)
+    }
+
+    /// The `--crate-name` passed to `rustc`, or else `$CARGO_CRATE_NAME`.
+    pub fn crate_name(&self) -> Option<String> {
+        flag_values(&self.args, "--crate-name")
+            .next()
+            .and_then(|name| name.to_str())
+            .map(|name| name.to_owned())
+            .or_else(|| self.cargo_env.crate_name.clone())
+    }
+
+    /// The `--edition` of the crate being compiled, defaulting to 2015 like `rustc` does.
+    pub fn edition(&self) -> anyhow::Result<Edition> {
+        let Some(edition) = flag_values(&self.args, "--edition").last() else {
+            return Ok(Edition::default());
+        };
+        edition
+            .to_str()
+            .ok_or_else(|| anyhow!("non-UTF-8 `--edition`: {edition:?}"))?
+            .parse()
+    }
+
+    /// The version of the real `rustc`, queried once from `rustc -vV` and cached.
+    pub fn version(&self) -> anyhow::Result<&ToolVersion> {
+        if let Some(version) = self.version.get() {
+            return Ok(version);
+        }
+        let version = ToolVersion::query(Command::new(&self.rustc), "rustc")?;
+        Ok(self.version.get_or_init(|| version))
+    }
+
+    /// The `cargo metadata` workspace graph,
+    /// if the `cargo` wrapper queried it with [`CargoWrapper::cargo_metadata`] before running `cargo`.
+    #[cfg(feature = "metadata")]
+    pub fn cargo_metadata(&self) -> anyhow::Result<Option<&Metadata>> {
+        if let Some(metadata) = self.metadata.get() {
+            return Ok(metadata.as_ref());
+        }
+        let metadata = match EnvVar::get_path(METADATA_VAR) {
+            Some(var) => Some(Metadata::read(&var.value)?),
+            None => None,
+        };
+        Ok(self.metadata.get_or_init(|| metadata).as_ref())
+    }
+
+    /// The [`Package`] of the crate being compiled, found from `$CARGO_MANIFEST_DIR` in [`Self::cargo_metadata`].
+    #[cfg(feature = "metadata")]
+    pub fn package_metadata(&self) -> anyhow::Result<Option<&Package>> {
+        let Some(manifest_dir) = &self.cargo_env.manifest_dir else {
+            return Ok(None);
+        };
+        Ok(self
+            .cargo_metadata()?
+            .and_then(|metadata| metadata.package_in(manifest_dir)))
+    }
+
+    /// The host triple of the real `rustc`, queried once from `rustc -vV` and cached.
+    pub fn host(&self) -> anyhow::Result<&str> {
+        Ok(&self.version()?.host)
+    }
+
+    /// The explicit targets `cargo` is building for (see [`CargoWrapper::targets`]),
+    /// which is empty if `cargo` isn't cross compiling.
+    ///
+    /// Unlike [`Self::target`], this is the same for host units, like build scripts.
+    pub fn cargo_targets(&self) -> Vec<String> {
+        let Some(targets) = EnvVar::get_os(TARGETS_VAR) else {
+            return Vec::new();
+        };
+        os_str_split(&targets.value, ENV_LIST_SEPARATOR)
+            .map(|target| target.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    /// The `--target` triple being compiled for, or the [host](Self::host) triple if there is none.
+    pub fn target(&self) -> anyhow::Result<&str> {
+        match flag_values(&self.args, "--target").last() {
+            Some(target) => target
+                .to_str()
+                .ok_or_else(|| anyhow!("non-UTF-8 `--target`: {target:?}")),
+            None => self.host(),
+        }
+    }
+
+    /// Whether the crate is compiled natively for the [host](Self::host),
+    /// either without `--target` or with `--target {host}`.
+    pub fn is_host_target(&self) -> anyhow::Result<bool> {
+        if flag_values(&self.args, "--target").next().is_none() {
+            return Ok(true);
+        }
+        Ok(self.target()? == self.host()?)
+    }
+
+    /// The last value of the `-C`/`--codegen` option `name`, like `3` for `-C opt-level=3`.
+    ///
+    /// Options given without a value (`-C {name}`) have an empty value.
+    pub fn codegen_option(&self, name: &str) -> Option<&OsStr> {
+        codegen_option_values(&self.args, name).last()
+    }
+
+    /// The `-C metadata` values, which `cargo` uses to disambiguate crates
+    /// (like multiple versions of the same crate), and so can key caches and metadata files.
+    ///
+    /// There can be more than one, in which case `rustc` combines them.
+    pub fn metadata(&self) -> anyhow::Result<Vec<&str>> {
+        codegen_option_values(&self.args, "metadata")
+            .map(|metadata| {
+                metadata
+                    .to_str()
+                    .ok_or_else(|| anyhow!("non-UTF-8 `-C metadata`: {metadata:?}"))
+            })
+            .collect()
+    }
+
+    /// The `-C extra-filename` suffix `cargo` adds to output file names, like `-1a2b3c4d5e6f7a8b`.
+    pub fn extra_filename(&self) -> anyhow::Result<Option<&str>> {
+        self.codegen_option("extra-filename")
+            .map(|extra| {
+                extra
+                    .to_str()
+                    .ok_or_else(|| anyhow!("non-UTF-8 `-C extra-filename`: {extra:?}"))
+            })
+            .transpose()
+    }
+
+    /// The `cargo` profile being built.
+    ///
+    /// This is derived from the target dir structure of `--out-dir` or `$OUT_DIR`
+    /// (`target/[{triple}/]{profile}/...`), falling back to `-C opt-level`
+    /// (an optimized build is considered [`Profile::Release`]).
+    pub fn profile(&self) -> Profile {
+        let out_dir = flag_values(&self.args, "--out-dir").last().map(Path::new);
+        let profile = out_dir
+            .into_iter()
+            .chain(self.cargo_env.out_dir.as_deref())
+            .find_map(Profile::from_target_path);
+        if let Some(profile) = profile {
+            return profile;
+        }
+        match self.codegen_option("opt-level") {
+            None => Profile::Debug,
+            Some(opt_level) if opt_level == "0" => Profile::Debug,
+            Some(_) => Profile::Release,
+        }
+    }
+
+    /// Whether the crate being compiled is a `bin` crate.
+    ///
+    /// This is determined by the `--crate-type`s passed to `rustc`.
+    /// If there are none (`cargo` always passes them for real compilations),
+    /// fallback to `$CARGO_BIN_NAME` and the `build_script_*` crate name convention.
+    pub fn is_bin_crate(&self) -> anyhow::Result<bool> {
+        let crate_types = self.crate_types()?;
+        if !crate_types.is_empty() {
+            return Ok(crate_types.contains(&CrateType::Bin));
+        }
+        let is_build_script = self
+            .crate_name()
+            .is_some_and(|name| name.starts_with("build_script_"));
+        Ok(self.bin_crate_name().is_some() || is_build_script)
+    }
+
+    pub fn bin_crate_name(&self) -> Option<&str> {
+        self.cargo_env.bin_name.as_deref()
+    }
+
+    /// Whether the crate being compiled is a build script.
+    ///
+    /// `cargo` always names the build script crate `build_script_build`
+    /// (or `build_script_*` in general), regardless of its file name (e.g. `build = "gen.rs"`).
+    /// It's also a `bin` crate, but unlike `bin` targets, it doesn't have `$CARGO_BIN_NAME` set.
+    pub fn is_build_script(&self) -> anyhow::Result<bool> {
+        let has_build_script_name = self
+            .crate_name()
+            .is_some_and(|name| name.starts_with("build_script_"));
+        Ok(has_build_script_name
+            && self.bin_crate_name().is_none()
+            && !self.is_test()
+            && self.is_bin_crate()?)
+    }
+
+    /// Whether this is one of `cargo`'s probe invocations rather than a real compilation,
+    /// i.e. `rustc -vV` or the `--crate-name ___ --print ...` target info query
+    /// (or any other `--print` query that isn't compiling a crate).
+    ///
+    /// These are always passed straight through to `rustc` by [`wrap_cargo_or_rustc`].
+    pub fn is_probe(&self) -> bool {
+        let is_version = self
+            .args
+            .iter()
+            .any(|arg| arg == "-vV" || arg == "-V" || arg == "--version");
+        let is_target_info = flag_values(&self.args, "--crate-name").any(|name| name == "___");
+        let is_print_query = flag_values(&self.args, "--print").next().is_some()
+            && flag_values(&self.args, "--crate-type").next().is_none();
+        is_version || is_target_info || is_print_query
+    }
+
+    /// The `--extern`s passed to `rustc`, by crate name.
+    ///
+    /// If a crate name is passed more than once, the last one wins.
+    pub fn externs(&self) -> anyhow::Result<BTreeMap<String, Extern>> {
+        flag_values(&self.args, "--extern")
+            .map(Extern::parse)
+            .collect()
+    }
+
+    /// The files `rustc` will produce, computed from `--out-dir`/`-o`, `--crate-name`,
+    /// `-C extra-filename`, `--crate-type`, `--emit`, and `--target`,
+    /// so post-compile hooks can find the generated `rlib`/`bin`/`dylib`/etc.
+    ///
+    /// Relative paths are relative to `rustc`'s working directory.
+    /// Linked files of [`CrateType::Other`]s are left out, unless named by `-o` or `--emit`,
+    /// as we don't know how `rustc` names them.
+    pub fn output_paths(&self) -> anyhow::Result<Vec<OutputPath>> {
+        rustc_args::output_paths(
+            &self.args,
+            self.crate_name().as_deref(),
+            &self.crate_types()?,
+        )
+    }
+
+    /// The `--emit`s passed to `rustc`, defaulting to just [`EmitKind::Link`] like `rustc` does.
+    pub fn emits(&self) -> anyhow::Result<Vec<Emit>> {
+        rustc_args::emits(&self.args)
+    }
+
+    /// The path of the dep-info (`.d`) file `rustc` will write, if it emits one.
+    ///
+    /// Compute this before [`Self::run_rustc`], then [`read_dep_info`] it afterwards
+    /// to get the source inputs of the crate.
+    pub fn dep_info_path(&self) -> anyhow::Result<Option<PathBuf>> {
+        let path = self
+            .output_paths()?
+            .into_iter()
+            .find(|output| output.kind == EmitKind::DepInfo)
+            .map(|output| output.path);
+        Ok(path)
+    }
+
+    /// The input source file passed to `rustc`, or `-` for stdin.
+    pub fn input(&self) -> Option<&Path> {
+        rustc_args::positional_args(&self.args)
+            .next()
+            .map(Path::new)
+    }
+
+    /// Whether `rustc` is compiling source read from stdin (`rustc -`).
+    ///
+    /// Stdin can only be read once, so it's passed through to [`Self::run_rustc`],
+    /// and analysis code that needs to read the source itself should opt out.
+    pub fn is_stdin_input(&self) -> bool {
+        self.input() == Some(Path::new("-"))
+    }
+
+    /// Whether this is a metadata-only compilation, as for `cargo check` (and IDEs running it),
+    /// i.e. `--emit=metadata` without `link`,
+    /// so expensive instrumentation can be skipped or downgraded.
+    pub fn is_check_build(&self) -> anyhow::Result<bool> {
+        let emits = self.emits()?;
+        let emits = |kind| emits.iter().any(|emit| emit.kind == kind);
+        Ok(emits(EmitKind::Metadata) && !emits(EmitKind::Link))
+    }
+
+    /// Whether `rustc` is building a test harness (`--test`),
+    /// as for `cargo test` and `cargo bench`.
+    pub fn is_test(&self) -> bool {
+        self.args.iter().any(|arg| arg == "--test")
+    }
+
+    /// Whether the crate being compiled is a `proc-macro` crate.
+    ///
+    /// Proc macros are always compiled for the host (without `--target`),
+    /// so they generally can't link against anything built for the target.
+    /// If there are no `--crate-type`s, fallback to checking for `--extern proc_macro`,
+    /// which `cargo` only passes to proc macros, for a crate compiled for the host
+    /// (see [`Self::is_host_target`]).
+    pub fn is_proc_macro(&self) -> anyhow::Result<bool> {
+        let crate_types = self.crate_types()?;
+        if !crate_types.is_empty() {
+            return Ok(crate_types.contains(&CrateType::ProcMacro));
+        }
+        if !self.is_host_target()? {
+            return Ok(false);
+        }
+        Ok(flag_values(&self.args, "--extern").any(|name| name == "proc_macro"))
+    }
+
+    /// Whether this `rustc` invocation is compiling for the host or the target.
+    ///
+    /// When cross compiling, `cargo` only passes `--target` for target units,
+    /// so any unit without one is for the host.
+    /// Otherwise, host and target are the same, and only build scripts and proc macros
+    /// (which are never linked into the final target artifacts) are considered [`BuildKind::Host`].
+    pub fn build_kind(&self) -> anyhow::Result<BuildKind> {
+        if flag_values(&self.args, "--target").next().is_some() {
+            return Ok(BuildKind::Target);
+        }
+        let cross_compiling = !self.cargo_targets().is_empty();
+        if cross_compiling || self.is_proc_macro()? || self.is_build_script()? {
+            return Ok(BuildKind::Host);
+        }
+        Ok(BuildKind::Target)
+    }
+
+    /// Register a transformation of the `rustc` args (not including `argv[0]`),
+    /// applied in order before running `rustc` or returning [`Self::rustc_args_os`].
+    ///
+    /// The accessors like [`Self::crate_name`] still see the original args.
+    pub fn add_arg_transform(&mut self, transform: impl FnOnce(&mut Vec<OsString>) + 'static) {
+        self.arg_transforms.push(Box::new(transform));
+    }
+
+    /// Append extra `args`.
+    pub fn add_args<I, S>(&mut self, args: I)
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<OsString>,
+    {
+        let new_args = args.into_iter().map(Into::into).collect::<Vec<_>>();
+        self.add_arg_transform(move |args| args.extend(new_args));
+    }
+
+    /// Add `--cfg {cfg}` to this `rustc` invocation,
+    /// so the crate can conditionally compile against anything injected into it.
+    ///
+    /// `cfg` is either a name or `name="value"`.
+    pub fn add_cfg(&mut self, cfg: impl Into<OsString>) {
+        self.add_args(["--cfg".into(), cfg.into()]);
+    }
+
+    /// Add an unstable `-Z {flag}` to this `rustc` invocation,
+    /// setting `$RUSTC_BOOTSTRAP=1` for it (and only it) so it works on stable toolchains.
+    pub fn add_unstable_flag(&mut self, flag: impl Into<OsString>) {
+        self.add_args(["-Z".into(), flag.into()]);
+        self.set_env("RUSTC_BOOTSTRAP", "1");
+    }
+
+    /// Set an env var for [`Self::run_rustc`] (or while running [`Self::run_in_process`]).
+    pub fn set_env(&mut self, key: impl Into<OsString>, value: impl Into<OsString>) {
+        let key = key.into();
+        let value = value.into();
+        self.envs.retain(|(k, _)| *k != key);
+        self.envs.push((key, value));
+    }
+
+    /// Add an `--extern`, replacing any existing one with the same `name`.
+    pub fn add_extern(&mut self, name: impl Into<String>, extern_: Extern) {
+        let name = name.into();
+        self.add_arg_transform(move |args| rustc_args::set_extern(args, &name, &extern_));
+    }
+
+    /// Remove all `--extern`s named `name`.
+    pub fn remove_extern(&mut self, name: impl Into<String>) {
+        let name = name.into();
+        self.add_arg_transform(move |args| rustc_args::remove_extern(args, &name));
+    }
+
+    /// Redirect the `--extern` named `name` to a different `path`, keeping its options,
+    /// e.g. to shim a dependency with an instrumented `rlib`.
+    ///
+    /// Errors if there is no such `--extern`.
+    pub fn redirect_extern(
+        &mut self,
+        name: impl Into<String>,
+        path: impl Into<PathBuf>,
+    ) -> anyhow::Result<()> {
+        let name = name.into();
+        let mut extern_ = self
+            .externs()?
+            .remove(&name)
+            .ok_or_else(|| anyhow!("no `--extern {name}` to redirect"))?;
+        extern_.path = Some(path.into());
+        self.add_extern(name, extern_);
+        Ok(())
+    }
+
+    /// Also `--emit` `kind` (to its default path), if it isn't already emitted,
+    /// e.g. to collect `llvm-ir` or `mir` for analysis.
+    ///
+    /// The existing `--emit`s (including the implicit `link`) are kept.
+    pub fn add_emit(&mut self, kind: EmitKind) {
+        self.add_emit_to(kind, None);
+    }
+
+    /// Like [`Self::add_emit`], but to an explicit `path` if [`Some`],
+    /// replacing any existing `--emit` of the same `kind`.
+    pub fn add_emit_to(&mut self, kind: EmitKind, path: Option<PathBuf>) {
+        self.add_arg_transform(move |args| {
+            rustc_args::modify_emits(args, |emits| {
+                if path.is_some() {
+                    emits.retain(|emit| emit.kind != kind);
+                }
+                if !emits.iter().any(|emit| emit.kind == kind) {
+                    emits.push(Emit { kind, path });
+                }
+            })
+        });
+    }
+
+    /// Don't `--emit` `kind`.
+    pub fn remove_emit(&mut self, kind: EmitKind) {
+        self.add_arg_transform(move |args| {
+            rustc_args::modify_emits(args, |emits| emits.retain(|emit| emit.kind != kind))
+        });
+    }
+
+    /// Remove all occurrences of `flag` and its value, like `--edition 2021` or `--edition=2021`.
+    pub fn remove_flag(&mut self, flag: impl Into<String>) {
+        let flag = flag.into();
+        self.add_arg_transform(move |args| remove_flag(args, &flag));
+    }
+
+    /// Replace all occurrences of `flag` with a single `{flag} {value}`.
+    pub fn set_flag(&mut self, flag: impl Into<String>, value: impl Into<OsString>) {
+        let flag = flag.into();
+        let value = value.into();
+        self.add_arg_transform(move |args| {
+            remove_flag(args, &flag);
+            args.extend([flag.into(), value]);
+        });
+    }
+
+    /// Remove all occurrences of the `-C`/`--codegen` option `name`, like `-C embed-bitcode=no`.
+    pub fn remove_codegen_option(&mut self, name: impl Into<String>) {
+        let name = name.into();
+        self.add_arg_transform(move |args| remove_codegen_option(args, &name));
+    }
+
+    /// Add the `--remap-path-prefix`es from [`CargoWrapper::add_remap_path_prefix`].
+    fn add_remap_path_prefixes_from_env(&mut self) {
+        let Some(var) = EnvVar::get_os(REMAP_PATH_PREFIX_VAR) else {
+            return;
+        };
+        let args = os_str_split(&var.value, ENV_LIST_SEPARATOR)
+            .flat_map(|mapping| ["--remap-path-prefix".into(), mapping.to_owned()])
+            .collect::<Vec<OsString>>();
+        self.add_args(args);
+    }
+
+    /// Apply and clear the [`Self::add_arg_transform`]s, returning the transformed args.
+    fn take_transformed_args(&mut self) -> Vec<OsString> {
+        let mut args = mem::take(&mut self.args);
+        for transform in self.arg_transforms.drain(..) {
+            transform(&mut args);
+        }
+        args
+    }
+
+    /// The verbosity `cargo` was run with (see [`CargoWrapper::verbosity`]),
+    /// for deciding how much the wrapper itself should log.
+    pub fn verbosity(&self) -> Verbosity {
+        self.verbosity
+    }
+
+    /// Whether to use colors, as decided by the `cargo` wrapper (see [`CargoWrapper::should_color`]),
+    /// since stderr is piped to `cargo` here.
+    pub fn should_color(&self) -> bool {
+        self.color
+    }
+
+    /// Emit a tool-specific message, like `{"instrumented": "foo", "functions": 3}`,
+    /// into `cargo`'s JSON messages, where it's parsed as a [`CargoMessage::Custom`].
+    ///
+    /// `reason` should be namespaced (see [`CustomMessage::reason`]).
+    /// This does nothing unless the `cargo` wrapper was run with [`CargoWrapper::run_cargo_json`],
+    /// as otherwise `cargo`'s stdout is for humans.
+    pub fn emit_message(&self, reason: &str, data: impl serde::Serialize) -> anyhow::Result<()> {
+        if !self.json_messages {
+            return Ok(());
+        }
+        cargo_messages::emit(&CustomMessage {
+            reason: reason.into(),
+            package_id: self.package_id().map(|id| id.to_string()),
+            crate_name: self.crate_name(),
+            data: serde_json::to_value(data)?,
+        })
+    }
+
+    /// The `$RUSTC_WRAPPER` that was set before ours (see [`CargoWrapper::previous_rustc_wrapper`]),
+    /// which [`Self::run_rustc`] and friends chain to, running `{previous_wrapper} {rustc} {args}`.
+    pub fn previous_wrapper(&self) -> Option<&Path> {
+        self.previous_wrapper.as_deref()
+    }
+
+    /// Set the wrapper to chain to (see [`Self::previous_wrapper`]), or [`None`] to run `rustc` directly.
+    pub fn set_previous_wrapper(&mut self, previous_wrapper: Option<PathBuf>) {
+        self.previous_wrapper = previous_wrapper;
+    }
+
+    /// The directory [`Self::run_rustc`] and friends run `rustc` in,
+    /// or [`None`] for the current directory, the one `cargo` ran the `rustc` wrapper in.
+    pub fn current_dir(&self) -> Option<&Path> {
+        self.current_dir.as_deref()
+    }
+
+    /// Run `rustc` in `dir` instead of the current directory (see [`Self::current_dir`]).
+    ///
+    /// Relative paths in the args, like the crate root source file, are then relative to `dir`.
+    pub fn set_current_dir(&mut self, dir: impl Into<PathBuf>) {
+        self.current_dir = Some(dir.into());
+    }
+
+    /// How long each `rustc` invocation (like by [`Self::run_rustc`]) may run before it's killed,
+    /// as set by [`CargoWrapper::set_rustc_timeout`], or [`None`] for no limit.
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// Override [`Self::timeout`], e.g. for crates known to take longer.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// The resource limits set on each `rustc` invocation (like by [`Self::run_rustc`]),
+    /// as set by [`CargoWrapper::set_rustc_limits`].
+    pub fn limits(&self) -> ResourceLimits {
+        self.limits
+    }
+
+    /// Override [`Self::limits`], e.g. for crates known to need more.
+    pub fn set_limits(&mut self, limits: ResourceLimits) {
+        self.limits = limits;
+    }
+
+    /// Where ICE reports are collected, as set by [`CargoWrapper::set_ice_dir`].
+    pub fn ice_dir(&self) -> Option<&Path> {
+        self.ice_dir.as_deref()
+    }
+
+    /// Override [`Self::ice_dir`].
+    pub fn set_ice_dir(&mut self, ice_dir: Option<PathBuf>) {
+        self.ice_dir = ice_dir;
+    }
+
+    /// Start collecting an ICE report from `rustc` if there's an [`Self::ice_dir`].
+    fn capture_ice(&self, rustc: &mut Command) -> anyhow::Result<Option<IceCapture>> {
+        let Some(ice_dir) = &self.ice_dir else {
+            return Ok(None);
+        };
+        let crate_name = self.crate_name().unwrap_or_else(|| "unknown".into());
+        IceCapture::new(ice_dir, &crate_name, rustc).map(Some)
+    }
+
+    /// Fail with [`CommandFailed`] if `rustc` failed, with the [`IceReport`] if it ICEd
+    /// (see [`IceCapture::finish`]).
+    fn check_rustc_status(
+        &self,
+        rustc: &Command,
+        status: ExitStatus,
+        ice: Option<IceCapture>,
+        saw_ice_message: bool,
+    ) -> anyhow::Result<()> {
+        let result =
+            exit::check_compiler_status(rustc, status, self.verbosity, self.failure_log.as_deref());
+        match ice {
+            Some(ice) => match ice.finish(rustc, status, saw_ice_message)? {
+                Some(report) => result.context(report),
+                None => result,
+            },
+            None => result,
+        }
+    }
+
+    /// Wait for a token from `cargo`'s jobserver (the one limiting it to `-j` jobs),
+    /// holding it until the returned [`JobToken`] is dropped,
+    /// or [`None`] if `cargo` didn't pass one.
+    ///
+    /// This is for limiting extra parallel work, like threads for a heavy analysis,
+    /// so that they don't oversubscribe the machine when `cargo` runs many `rustc`s at once.
+    /// Each `rustc` wrapper already implicitly holds one token for its own `rustc` invocation,
+    /// so acquire one for each job beyond that.
+    pub fn acquire_job(&self) -> anyhow::Result<Option<JobToken>> {
+        self.jobserver
+            .get_or_init(jobs::jobserver_from_env)
+            .as_ref()
+            .map(jobs::acquire)
+            .transpose()
+    }
+
+    /// Replace a timed out error from running `rustc` (see [`Self::timeout`])
+    /// with one saying which crate it was compiling.
+    fn timed_out(&self, e: anyhow::Error) -> anyhow::Error {
+        let timed_out = e
+            .downcast_ref::<io::Error>()
+            .is_some_and(|e| e.kind() == io::ErrorKind::TimedOut);
+        match self.timeout {
+            Some(timeout) if timed_out => anyhow!(
+                "compilation timed out after {timeout:?} in crate `{}`",
+                self.crate_name().as_deref().unwrap_or("<unknown>")
+            ),
+            _ => e,
+        }
+    }
+
+    /// Run `rustc` with `args` (see [`prepare_rustc_command`]), failing with [`CommandFailed`] if it fails.
+    fn run_rustc_with(&self, args: &[OsString]) -> anyhow::Result<()> {
+        let mut rustc = self.rustc_command();
+        let _argfile = prepare_rustc_command(&mut rustc, args, &self.envs)?;
+        self.run_rustc_command(&mut rustc)
+    }
+
+    /// Run a prepared `rustc` command, collecting an ICE report if there's an [`Self::ice_dir`],
+    /// failing with [`CommandFailed`] if it fails.
+    fn run_rustc_command(&self, rustc: &mut Command) -> anyhow::Result<()> {
+        let ice = self.capture_ice(rustc)?;
+        let (status, saw_ice_message) = if ice.is_some() {
+            // `rustc` only writes ICE report files on nightly, so also look for ICE messages.
+            let mut saw_ice_message = false;
+            let status = line_stream::run(rustc, self.timeout, |stream, line| {
+                saw_ice_message |= stream == OutputStream::Stderr && ice::is_ice_message(line);
+                Ok(())
+            })
+            .map_err(|e| self.timed_out(e))?;
+            (status, saw_ice_message)
+        } else {
+            let status = signals::status_with_timeout(rustc, self.timeout)
+                .map_err(|e| self.timed_out(e.into()))?;
+            (status, false)
+        };
+        self.check_rustc_status(rustc, status, ice, saw_ice_message)
+    }
+
+    /// The command to run `rustc`, through [`Self::previous_wrapper`] if there is one,
+    /// in [`Self::current_dir`], with [`Self::limits`],
+    /// and passing on the jobserver if [`Self::acquire_job`] connected to it.
+    fn rustc_command(&self) -> Command {
+        let mut wrapped = WrappedCommand {
+            path: self
+                .previous_wrapper
+                .as_ref()
+                .unwrap_or(&self.rustc)
+                .clone(),
+            current_dir: None,
+        };
+        if let Some(dir) = &self.current_dir {
+            wrapped = wrapped.current_dir(dir);
+        }
+        let mut cmd = wrapped.command();
+        if self.previous_wrapper.is_some() {
+            cmd.arg(&self.rustc);
+        }
+        self.limits.set_on(&mut cmd);
+        if let Some(Some(jobserver)) = self.jobserver.get() {
+            jobs::configure(jobserver, &mut cmd);
+        }
+        cmd
+    }
+
+    /// Run the real `rustc` separately from the crate being compiled,
+    /// with args (like `--print cfg`) added by `f`, capturing its stdout and stderr,
+    /// e.g. for probing what it supports.
+    /// It's still run with the envs from [`Self::set_env`] and in [`Self::current_dir`].
+    ///
+    /// This doesn't go through [`Self::previous_wrapper`] or fail if `rustc` does.
+    pub fn run_rustc_output(
+        &self,
+        f: impl FnOnce(&mut Command) -> anyhow::Result<()>,
+    ) -> anyhow::Result<Output> {
+        let mut cmd = Command::new(&self.rustc);
+        if let Some(dir) = &self.current_dir {
+            cmd.current_dir(dir);
+        }
+        // No args, so no argfile.
+        prepare_rustc_command(&mut cmd, &[], &self.envs)?;
+        f(&mut cmd)?;
+        let output = signals::output(&mut cmd).with_context(|| format!("run {cmd:?}"))?;
+        Ok(output)
+    }
+
+    /// Set what to do if `--sysroot` was already passed to `rustc`.
+    pub fn set_sysroot_policy(&mut self, policy: SysrootPolicy) {
+        self.sysroot_policy = policy;
+    }
+
+    /// The full `rustc` command line, with the `rustc` path as `argv[0]` (as `rustc_driver` expects)
+    /// and `--sysroot` appended (unless already present, depending on the [`SysrootPolicy`]).
+    pub fn rustc_args_os(mut self) -> anyhow::Result<Vec<OsString>> {
+        let args = self.take_transformed_args();
+        let mut args = [self.rustc.as_os_str().to_owned()]
+            .into_iter()
+            .chain(args)
+            .collect::<Vec<_>>();
+        let has_sysroot = flag_values(&args, "--sysroot").next().is_some();
+        match self.sysroot_policy {
+            SysrootPolicy::KeepExisting if has_sysroot => {}
+            SysrootPolicy::KeepExisting | SysrootPolicy::Override => {
+                let sysroot = self.sysroot()?;
+                remove_flag(&mut args, "--sysroot");
+                args.extend(["--sysroot".into(), sysroot.into()]);
+            }
+        }
+        Ok(args)
+    }
+
+    /// Like [`Self::rustc_args_os`], but requiring UTF-8.
+    ///
+    /// This is only a convenience for APIs like `rustc_driver` that require UTF-8;
+    /// prefer [`Self::rustc_args_os`], which (like [`Self::run_rustc`]) handles non-UTF-8 args.
+    pub fn rustc_args(self) -> anyhow::Result<Vec<String>> {
+        self.rustc_args_os()?
+            .into_iter()
+            .map(|arg| arg.into_string())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(os_string_utf8_error)
+    }
+
+    /// Run the real `rustc` (from [`Self::rustc_path`]) with the original args,
+    /// failing with [`CommandFailed`] if it fails.
+    ///
+    /// If the args are too long for the OS, they're passed through an `@path` argfile instead.
+    pub fn run_rustc(mut self) -> anyhow::Result<()> {
+        let args = self.take_transformed_args();
+        self.run_rustc_with(&args)
+    }
+
+    /// Like [`Self::run_rustc`], but on Unix, replace this process with `rustc` (with `exec`)
+    /// instead of spawning it and waiting for it, so only returns on error.
+    ///
+    /// This is for when the wrapper has nothing left to do, like passing through dependencies,
+    /// avoiding an extra process per `rustc` invocation.
+    /// Nothing else is run or dropped afterwards, so don't use this with anything to clean up.
+    /// If the args need an argfile (which would be leaked),
+    /// or there's a [`Self::timeout`] to enforce or an [`Self::ice_dir`] to collect ICEs into,
+    /// this falls back to spawning `rustc`.
+    #[cfg_attr(not(unix), allow(unused_mut))]
+    pub fn exec_rustc(mut self) -> anyhow::Result<()> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::CommandExt;
+
+            let args = self.take_transformed_args();
+            let mut rustc = self.rustc_command();
+            let argfile = prepare_rustc_command(&mut rustc, &args, &self.envs)?;
+            if argfile.is_none() && self.timeout.is_none() && self.ice_dir.is_none() {
+                let e = rustc.exec();
+                return Err(e).with_context(|| format!("exec {rustc:?}"));
+            }
+            self.run_rustc_command(&mut rustc)
+        }
+        #[cfg(not(unix))]
+        self.run_rustc()
+    }
+
+    /// Run the real `rustc` like [`Self::run_rustc`],
+    /// but pass each line of its stdout and stderr to `on_line` as it comes.
+    ///
+    /// The output is still passed through to `cargo`, before `on_line` sees it.
+    /// To inspect the diagnostics themselves, see [`Self::run_rustc_captured`].
+    pub fn run_rustc_streaming(
+        mut self,
+        mut on_line: impl FnMut(OutputStream, &[u8]) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        let args = self.take_transformed_args();
+        let mut rustc = self.rustc_command();
+        let _argfile = prepare_rustc_command(&mut rustc, &args, &self.envs)?;
+        let ice = self.capture_ice(&mut rustc)?;
+        let mut saw_ice_message = false;
+        let status = line_stream::run(&mut rustc, self.timeout, |stream, line| {
+            saw_ice_message |= stream == OutputStream::Stderr && ice::is_ice_message(line);
+            on_line(stream, line)
+        })
+        .map_err(|e| self.timed_out(e))?;
+        self.check_rustc_status(&rustc, status, ice, saw_ice_message)
+    }
+
+    /// Run the real `rustc` like [`Self::run_rustc`], but capture its diagnostics
+    /// (forcing `--error-format=json` if needed) so they can be inspected.
+    ///
+    /// The diagnostics are still shown: re-emitted as JSON if `cargo` asked for it,
+    /// or else rendered like `rustc` normally would.
+    /// Unlike [`Self::run_rustc`], this doesn't fail if `rustc` does, but returns the status.
+    pub fn run_rustc_captured(self) -> anyhow::Result<CapturedRustc> {
+        self.run_rustc_transforming_diagnostics(Some)
+    }
+
+    /// Like [`Self::run_rustc_captured`], but pass each diagnostic through `transform`
+    /// before it reaches `cargo` (or the terminal), dropping it if it returns [`None`],
+    /// e.g. to strip notes referencing generated code
+    /// or to remap spans from instrumented sources back to the originals
+    /// (see [`Diagnostic::for_each_span_mut`]).
+    ///
+    /// If a diagnostic is modified, it's re-rendered with [`Diagnostic::render_simple`].
+    pub fn run_rustc_transforming_diagnostics(
+        mut self,
+        transform: impl FnMut(Diagnostic) -> Option<Diagnostic>,
+    ) -> anyhow::Result<CapturedRustc> {
+        let mut args = self.take_transformed_args();
+        let emit_json = flag_values(&args, "--error-format").last() == Some(OsStr::new("json"));
+        if !emit_json {
+            remove_flag(&mut args, "--error-format");
+            args.push("--error-format=json".into());
+            if self.color {
+                args.push("--json=diagnostic-rendered-ansi".into());
+            }
+        }
+        let mut cmd = self.rustc_command();
+        let _argfile = prepare_rustc_command(&mut cmd, &args, &self.envs)?;
+        let ice = self.capture_ice(&mut cmd)?;
+        let mut captured = diagnostics::run_captured(&mut cmd, emit_json, self.timeout, transform)
+            .map_err(|e| self.timed_out(e))?;
+        if let Some(ice) = ice {
+            let saw_ice_message = captured
+                .diagnostics
+                .iter()
+                .any(|diag| ice::is_ice_message(diag.level.as_bytes()));
+            captured.ice = ice.finish(&cmd, captured.status, saw_ice_message)?;
+        }
+        Ok(captured)
+    }
+
+    /// Run the real compilation (like [`Self::run_rustc`]) so `cargo` gets its artifacts,
+    /// and then run a second, analysis-only `rustc` invocation with the args modified by `f`.
+    ///
+    /// Before calling `f`, the second invocation's args are changed to only `--emit=metadata`
+    /// into a temporary `--out-dir` without incremental compilation,
+    /// so that it can't collide with the real compilation's outputs.
+    /// It also doesn't report artifacts (`--json=artifacts`) or warnings (`--cap-lints=allow`)
+    /// back to `cargo`, as those were already reported by the real compilation.
+    pub fn run_rustc_and(
+        mut self,
+        f: impl FnOnce(&mut Vec<OsString>) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        ensure!(
+            !self.is_stdin_input(),
+            "can't run `rustc` twice on source from stdin"
+        );
+        let args = self.take_transformed_args();
+        self.run_rustc_with(&args)?;
+
+        let out_dir = tempfile::Builder::new()
+            .prefix("rustc-analysis.")
+            .tempdir()
+            .context("create temp dir for analysis-only `rustc`")?;
+        let mut analysis_args = args;
+        remove_flag(&mut analysis_args, "-o");
+        remove_flag(&mut analysis_args, "--out-dir");
+        remove_flag(&mut analysis_args, "--emit");
+        remove_flag(&mut analysis_args, "--json");
+        remove_flag(&mut analysis_args, "--cap-lints");
+        remove_codegen_option(&mut analysis_args, "incremental");
+        analysis_args.extend([
+            "--cap-lints=allow".into(),
+            "--emit=metadata".into(),
+            "--out-dir".into(),
+            out_dir.path().into(),
+        ]);
+        f(&mut analysis_args)?;
+        self.run_rustc_with(&analysis_args)
+    }
+}
+
+/// The `package.default-run` of the `Cargo.toml` at `manifest_path`,
+/// the binary `cargo run` runs if the package has several.
+fn default_run(manifest_path: &Path) -> anyhow::Result<Option<String>> {
+    let doc = fs_err::read_to_string(manifest_path)?
+        .parse::<toml_edit::Document>()
+        .with_context(|| format!("invalid manifest {}", manifest_path.display()))?;
+    Ok(doc
+        .get("package")
+        .and_then(|package| package.get("default-run"))
+        .and_then(|default_run| default_run.as_str())
+        .map(ToOwned::to_owned))
+}
+
+/// Set up `cmd` to run `rustc` with `args` and extra `envs`,
+/// through an `@path` argfile if the args are too long for the OS.
+///
+/// The returned argfile must outlive the command.
+fn prepare_rustc_command(
+    cmd: &mut Command,
+    args: &[OsString],
+    envs: &[(OsString, OsString)],
+) -> anyhow::Result<Option<NamedTempFile>> {
+    let argfile = argfile::write_if_too_long(cmd.get_program(), args)?;
+    // Needed for `rustc -`.
+    cmd.stdin(Stdio::inherit());
+    cmd.envs(envs.iter().map(|(k, v)| (k, v)));
+    match &argfile {
+        Some(argfile) => cmd.arg(argfile::arg(argfile)),
+        None => cmd.args(args),
+    };
+    Ok(argfile)
+}
+
+/// A `cargo`/`rustc` wrapper.
+///
+/// As a `cargo` wrapper, it's invoked as `{wrapper} {own args} -- {cargo args}`,
+/// where the own args are parsed with [`Parser`]
+/// and the `cargo` args are passed to [`CargoWrapper::run_cargo_with_rustc_wrapper`].
+pub trait CargoRustcWrapper: Parser {
+    /// The prefix for this tool's env vars, like `C2RUST_INSTRUMENT` for `c2rust-instrument`.
+    ///
+    /// Defaults to the `clap` command name in `SCREAMING_SNAKE_CASE`.
+    fn env_prefix() -> String {
+        Self::command()
+            .get_name()
+            .to_ascii_uppercase()
+            .replace('-', "_")
+    }
+
+    /// Run as a `cargo` wrapper/plugin, the default invocation.
+    fn wrap_cargo(self, wrapper: CargoWrapper) -> anyhow::Result<()>;
+
+    /// Run as a `rustc` wrapper (a la `$RUSTC_WRAPPER`/[`RUSTC_WRAPPER_VAR`],
+    /// or `$RUSTC_WORKSPACE_WRAPPER`/[`RUSTC_WORKSPACE_WRAPPER_VAR`]).
+    ///
+    /// This is skipped, running `rustc` directly instead, for `cargo`'s probe invocations
+    /// (see [`RustcWrapper::is_probe`]) and when `${PREFIX}_SKIP` is set
+    /// (see [`CargoRustcWrapper::env_prefix`]).
+    fn wrap_rustc(wrapper: RustcWrapper) -> anyhow::Result<()>;
+
+    /// Run as a `rustdoc` wrapper (as `$RUSTDOC`), if enabled with [`CargoWrapper::set_wrap_rustdoc`].
+    ///
+    /// Defaults to passing it straight through.
+    /// Like [`Self::wrap_rustc`], this is skipped for probes (see [`RustdocWrapper::is_probe`])
+    /// and when `${PREFIX}_SKIP` is set.
+    fn wrap_rustdoc(wrapper: RustdocWrapper) -> anyhow::Result<()> {
+        wrapper.run_rustdoc()
+    }
+}
+
+/// `own_exe` as a different path to the same file, `{dir}/./{name}`,
+/// so that it can tell when it's run as `$RUSTDOC` from its `argv[0]` (see [`RUSTDOC_WRAPPER_VAR`]).
+fn rustdoc_wrapper_path(own_exe: &Path) -> OsString {
+    let mut path = own_exe
+        .parent()
+        .unwrap_or(Path::new(""))
+        .join(".")
+        .into_os_string();
+    path.push(std::path::MAIN_SEPARATOR_STR);
+    path.push(own_exe.file_name().unwrap_or_default());
+    path
+}
+
+/// Whether `${prefix}_SKIP` is set to something other than empty, `0`, or `false`.
+fn is_skip_var_set(prefix: &str) -> bool {
+    let key = format!("{prefix}_SKIP");
+    env::var_os(key).is_some_and(|value| !matches!(value.to_str(), Some("" | "0" | "false")))
+}
+
+/// Run the current binary as either a `cargo` or `rustc` wrapper.
+///
+/// It's a `rustc` wrapper if it's `$RUSTC_WRAPPER`/`$RUSTC_WORKSPACE_WRAPPER`
+/// or `$RUSTC` itself (see [`RustcInvocation`]), and a `cargo` wrapper otherwise.
+/// As a `cargo` wrapper, it can also be invoked as a `cargo` subcommand,
+/// like `cargo instrument` for `cargo-instrument`.
+///
+/// Failed commands are returned as [`CommandFailed`] errors,
+/// so pass the result to [`exit_with`] to exit with their status.
+pub fn wrap_cargo_or_rustc<T: CargoRustcWrapper>() -> anyhow::Result<()> {
+    let own_rustc_wrapper = RustcWrapperEnvVar {
+        key: RUSTC_WRAPPER_VAR,
+        value: env::current_exe()?,
+    };
+    let is_own_exe =
+        |key| EnvVar::get_path(key).is_some_and(|var| var.value == own_rustc_wrapper.value);
+    // `$RUSTDOC` is still set (to us) for `rustc`, and as `$RUSTC`, our args look the same,
+    // so tell them apart by how `cargo` ran us (see `rustdoc_wrapper_path`).
+    let is_rustdoc = env::var_os(RUSTDOC_WRAPPER_VAR)
+        .is_some_and(|rustdoc_wrapper| env::args_os().next() == Some(rustdoc_wrapper));
+    if is_rustdoc {
+        let wrapper = RustdocWrapper::new()?;
+        if wrapper.is_probe() || is_skip_var_set(&T::env_prefix()) {
+            return wrapper.run_rustdoc();
+        }
+        return T::wrap_rustdoc(wrapper);
+    }
+    let invocation = if is_own_exe(RUSTC_WRAPPER_VAR) || is_own_exe(RUSTC_WORKSPACE_WRAPPER_VAR) {
+        Some(RustcInvocation::Wrapper)
+    } else if is_own_exe(RUSTC_VAR) {
+        Some(RustcInvocation::Rustc)
+    } else {
+        None
+    };
+    if let Some(invocation) = invocation {
+        let mut wrapper = RustcWrapper::new(invocation)?;
+        if wrapper.is_probe() || is_skip_var_set(&T::env_prefix()) {
+            return wrapper.exec_rustc();
+        }
+        wrapper.add_remap_path_prefixes_from_env();
+        T::wrap_rustc(wrapper)
+    } else {
+        let args = strip_cargo_subcommand(env::args_os().collect());
+        let (own_args, cargo_args) = split_cargo_args(args);
+        // Let `clap` print `--help`, `--version`, and usage errors itself and exit,
+        // as `--help` and `--version` aren't errors.
+        let args = T::try_parse_from(own_args).unwrap_or_else(|e| e.exit());
+        args.wrap_cargo(CargoWrapper::new(own_rustc_wrapper, cargo_args)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rustc_wrapper(args: &[&str], cargo_env: CargoEnv) -> RustcWrapper {
+        RustcWrapper::from_parts(
+            RustcInvocation::Wrapper,
+            "rustc".into(),
+            args.iter().map(OsString::from).collect(),
+            Some(SysrootEnvVar {
+                key: SYSROOT_VAR,
+                value: "/sysroot".into(),
+            }),
+            cargo_env,
+        )
+    }
+
+    fn cargo_env(crate_name: &str, bin_name: Option<&str>) -> CargoEnv {
+        CargoEnv {
+            crate_name: Some(crate_name.into()),
+            bin_name: bin_name.map(Into::into),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn build_script_with_default_file_name() {
+        let wrapper = rustc_wrapper(
+            &[
+                "--crate-name",
+                "build_script_build",
+                "--edition=2021",
+                "build.rs",
+                "--crate-type",
+                "bin",
+            ],
+            cargo_env("build_script_build", None),
+        );
+        assert!(wrapper.is_build_script().unwrap());
+    }
+
+    #[test]
+    fn build_script_with_custom_file_name() {
+        let wrapper = rustc_wrapper(
+            &[
+                "--crate-name",
+                "build_script_build",
+                "--edition=2021",
+                "src/codegen/gen.rs",
+                "--crate-type",
+                "bin",
+            ],
+            cargo_env("build_script_build", None),
+        );
+        assert!(wrapper.is_build_script().unwrap());
+    }
+
+    #[test]
+    fn build_script_without_crate_name_arg_or_crate_type() {
+        let wrapper = rustc_wrapper(&["custom_build.rs"], cargo_env("build_script_build", None));
+        assert!(wrapper.is_build_script().unwrap());
+    }
+
+    #[test]
+    fn bin_is_not_build_script() {
+        let wrapper = rustc_wrapper(
+            &["--crate-name", "foo", "src/main.rs", "--crate-type", "bin"],
+            cargo_env("foo", Some("foo")),
+        );
+        assert!(!wrapper.is_build_script().unwrap());
+    }
+
+    #[test]
+    fn bin_named_like_build_script_is_not_build_script() {
+        let wrapper = rustc_wrapper(
+            &[
+                "--crate-name",
+                "build_script_x",
+                "src/bin/build.rs",
+                "--crate-type",
+                "bin",
+            ],
+            cargo_env("build_script_x", Some("build_script_x")),
+        );
+        assert!(!wrapper.is_build_script().unwrap());
+    }
+
+    #[test]
+    fn lib_is_not_build_script() {
+        let wrapper = rustc_wrapper(
+            &[
+                "--crate-name",
+                "build_script_lib",
+                "src/lib.rs",
+                "--crate-type",
+                "lib",
+            ],
+            cargo_env("build_script_lib", None),
+        );
+        assert!(!wrapper.is_build_script().unwrap());
     }
 }
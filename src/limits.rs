@@ -0,0 +1,104 @@
+//! Resource limits (`rlimit`s) for spawned `rustc`s.
+
+use std::process::Command;
+use std::time::Duration;
+
+use anyhow::anyhow;
+
+/// Limits on the resources of each `rustc` invocation,
+/// set with [`crate::CargoWrapper::set_rustc_limits`],
+/// so that a runaway analysis pass fails that crate instead of taking down the machine.
+///
+/// These are `rlimit`s, so they're only enforced on Unix.
+/// Only the soft limits are lowered (to at most the hard limits).
+/// A `rustc` exceeding them is killed (or fails to allocate and aborts),
+/// failing with [`crate::CommandFailed`] like any other failure.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct ResourceLimits {
+    /// The maximum virtual memory (`RLIMIT_AS`), in bytes.
+    pub memory: Option<u64>,
+    /// The maximum CPU time (`RLIMIT_CPU`), rounded up to whole seconds.
+    pub cpu_time: Option<Duration>,
+}
+
+impl ResourceLimits {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Like `memory=1073741824,cpu-time=60`.
+    pub(crate) fn to_env_value(self) -> String {
+        let Self { memory, cpu_time } = self;
+        let memory = memory.map(|bytes| format!("memory={bytes}"));
+        let cpu_time = cpu_secs(cpu_time).map(|secs| format!("cpu-time={secs}"));
+        [memory, cpu_time]
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    pub(crate) fn from_env_value(value: &str) -> anyhow::Result<Self> {
+        let mut limits = Self::default();
+        for limit in value.split(',').filter(|limit| !limit.is_empty()) {
+            let parsed = limit.split_once('=').and_then(|(name, value)| {
+                let value = value.parse::<u64>().ok()?;
+                match name {
+                    "memory" => limits.memory = Some(value),
+                    "cpu-time" => limits.cpu_time = Some(Duration::from_secs(value)),
+                    _ => return None,
+                }
+                Some(())
+            });
+            parsed.ok_or_else(|| anyhow!("invalid resource limit: {limit}"))?;
+        }
+        Ok(limits)
+    }
+
+    /// Set these limits on `cmd`'s process before it `exec`s.
+    pub(crate) fn set_on(self, cmd: &mut Command) {
+        if self.is_empty() {
+            return;
+        }
+        #[cfg(unix)]
+        {
+            use std::io;
+            use std::os::unix::process::CommandExt;
+
+            let limits = [
+                (libc::RLIMIT_AS, self.memory),
+                (libc::RLIMIT_CPU, cpu_secs(self.cpu_time)),
+            ];
+            // SAFETY: `getrlimit` and `setrlimit` are async-signal-safe.
+            unsafe {
+                cmd.pre_exec(move || {
+                    for (resource, limit) in limits {
+                        let Some(limit) = limit else {
+                            continue;
+                        };
+                        let mut rlimit = libc::rlimit {
+                            rlim_cur: 0,
+                            rlim_max: 0,
+                        };
+                        if libc::getrlimit(resource, &mut rlimit) == -1 {
+                            return Err(io::Error::last_os_error());
+                        }
+                        // Only the soft limit, which can't be raised past the hard limit.
+                        rlimit.rlim_cur = (limit as libc::rlim_t).min(rlimit.rlim_max);
+                        if libc::setrlimit(resource, &rlimit) == -1 {
+                            return Err(io::Error::last_os_error());
+                        }
+                    }
+                    Ok(())
+                });
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = cmd;
+    }
+}
+
+/// `RLIMIT_CPU` is in whole seconds, and 0 would be no time at all.
+fn cpu_secs(cpu_time: Option<Duration>) -> Option<u64> {
+    cpu_time.map(|time| time.as_secs_f64().ceil().max(1.0) as u64)
+}
@@ -0,0 +1,86 @@
+//! Streaming a child's stdout and stderr line by line.
+
+use std::io;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Write;
+use std::process::Command;
+use std::process::ExitStatus;
+use std::process::Stdio;
+use std::sync::mpsc;
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Context;
+
+use crate::signals;
+use crate::signals::Waiter;
+
+/// Which of a child's output streams a line came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+type Line = (OutputStream, io::Result<Vec<u8>>);
+
+fn spawn_reader(stream: OutputStream, reader: impl Read + Send + 'static, tx: Sender<Line>) {
+    thread::spawn(move || {
+        let mut reader = BufReader::new(reader);
+        loop {
+            let mut line = Vec::new();
+            let result = match reader.read_until(b'\n', &mut line) {
+                Ok(0) => return,
+                Ok(_) => Ok(line),
+                Err(e) => Err(e),
+            };
+            let is_err = result.is_err();
+            // The receiver is gone if the callback failed, so stop reading.
+            if tx.send((stream, result)).is_err() || is_err {
+                return;
+            }
+        }
+    });
+}
+
+/// Run `cmd`, echoing its stdout and stderr as they come,
+/// and passing each line (without the trailing newline) to `on_line` after it's echoed.
+///
+/// If `on_line` fails, or `cmd` is still running after `timeout`, `cmd` is killed.
+pub(crate) fn run(
+    cmd: &mut Command,
+    timeout: Option<Duration>,
+    mut on_line: impl FnMut(OutputStream, &[u8]) -> anyhow::Result<()>,
+) -> anyhow::Result<ExitStatus> {
+    let (mut child, _forward) = signals::spawn(cmd.stdout(Stdio::piped()).stderr(Stdio::piped()))
+        .with_context(|| format!("spawn {cmd:?}"))?;
+    let (tx, rx) = mpsc::channel();
+    let stdout = child.stdout.take().expect("stdout was piped");
+    let stderr = child.stderr.take().expect("stderr was piped");
+    let mut child = Waiter::new(child, timeout);
+    spawn_reader(OutputStream::Stdout, stdout, tx.clone());
+    spawn_reader(OutputStream::Stderr, stderr, tx);
+    // Ends once both readers are done.
+    let result = rx.iter().try_for_each(|(stream, line)| {
+        let line = line.with_context(|| format!("read {stream:?} of {cmd:?}"))?;
+        match stream {
+            OutputStream::Stdout => io::stdout().write_all(&line),
+            OutputStream::Stderr => io::stderr().write_all(&line),
+        }
+        .with_context(|| format!("echo {stream:?} of {cmd:?}"))?;
+        let line = line.strip_suffix(b"\n").unwrap_or(&line);
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        on_line(stream, line)
+    });
+    // Don't leave `cmd` running or unreaped if echoing or `on_line` failed,
+    // but report that failure over any from waiting.
+    if result.is_err() {
+        child.kill();
+    }
+    let status = child.wait();
+    result?;
+    Ok(status?)
+}
@@ -0,0 +1,76 @@
+//! Retrying `cargo` when another `cargo` holds a lock it needs.
+
+use std::process::Command;
+use std::process::ExitStatus;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::bail;
+use anyhow::Context;
+
+use crate::stdio;
+use crate::stdio::CommandStdio;
+
+/// How to retry `cargo` when another `cargo` (like an IDE's `cargo check`)
+/// holds the package cache or build directory lock it needs,
+/// set with [`crate::CargoWrapper::set_lock_retry`].
+///
+/// Instead of blocking until the lock is released, `cargo` is killed and retried after a delay,
+/// doubling each time, and after [`Self::attempts`], this fails instead of waiting forever.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LockRetry {
+    /// How many times to retry before giving up.
+    pub attempts: u32,
+    /// The delay before the first retry.
+    pub initial_delay: Duration,
+}
+
+impl Default for LockRetry {
+    fn default() -> Self {
+        Self {
+            attempts: 5,
+            initial_delay: Duration::from_secs(1),
+        }
+    }
+}
+
+/// What `cargo` is waiting for a lock on, if `line` of its stderr says it's waiting,
+/// like `    Blocking waiting for file lock on package cache`.
+fn waiting_for_lock(line: &[u8]) -> Option<String> {
+    const WAITING: &[u8] = b"waiting for file lock on ";
+    let start = line
+        .windows(WAITING.len())
+        .position(|window| window == WAITING)?;
+    let what = String::from_utf8_lossy(&line[start + WAITING.len()..]);
+    Some(what.trim_end().to_owned())
+}
+
+/// Like [`stdio::status`], but retry `cmd` (`cargo`) according to `retry` if it's waiting for a lock.
+pub(crate) fn status(
+    cmd: &mut Command,
+    stdio: &CommandStdio,
+    retry: LockRetry,
+) -> anyhow::Result<ExitStatus> {
+    let mut delay = retry.initial_delay;
+    let mut attempt = 0;
+    loop {
+        let mut waiting_for = None;
+        let status = stdio::status_watching_stderr(cmd, stdio, |line| {
+            waiting_for = waiting_for_lock(line);
+            waiting_for.is_some()
+        })
+        .with_context(|| format!("run {cmd:?}"))?;
+        if let Some(status) = status {
+            return Ok(status);
+        }
+        let what = waiting_for.expect("only stopped when waiting for a lock");
+        attempt += 1;
+        let attempts = retry.attempts;
+        if attempt > attempts {
+            bail!("gave up waiting for the file lock on {what} after {attempts} retries");
+        }
+        eprintln!("     Waiting for another `cargo` to release it, retrying in {delay:?} ({attempt}/{attempts})");
+        thread::sleep(delay);
+        delay *= 2;
+    }
+}
@@ -0,0 +1,145 @@
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::anyhow;
+use anyhow::Context;
+use toml_edit::Array;
+use toml_edit::Document;
+use toml_edit::InlineTable;
+use toml_edit::Item;
+use toml_edit::Table;
+
+use crate::dependency::DependencySource;
+use crate::dependency::FileBackup;
+
+/// Temporary modifications to a `Cargo.toml`.
+///
+/// The `Cargo.toml` and `Cargo.lock` are snapshotted on creation
+/// and restored when this is dropped (including on panic) or [`Self::restore`]d.
+pub struct ManifestGuard {
+    manifest_path: PathBuf,
+    doc: Document,
+    backups: Vec<FileBackup>,
+}
+
+impl ManifestGuard {
+    /// Snapshot `manifest_path` and `lock_path`, which is usually the workspace root's `Cargo.lock`.
+    pub fn new(manifest_path: impl Into<PathBuf>, lock_path: &Path) -> anyhow::Result<Self> {
+        let manifest_path = manifest_path.into();
+        let toml = fs_err::read_to_string(&manifest_path)?;
+        let doc = toml
+            .parse::<Document>()
+            .with_context(|| format!("invalid manifest: {}", manifest_path.display()))?;
+        let backups = vec![
+            FileBackup::new(&manifest_path)?,
+            FileBackup::new(lock_path)?,
+        ];
+        Ok(Self {
+            manifest_path,
+            doc,
+            backups,
+        })
+    }
+
+    pub fn manifest_path(&self) -> &Path {
+        &self.manifest_path
+    }
+
+    /// The (modified) manifest.
+    pub fn document(&self) -> &Document {
+        &self.doc
+    }
+
+    fn table(&mut self, name: &str) -> anyhow::Result<&mut Table> {
+        let item = self
+            .doc
+            .entry(name)
+            .or_insert_with(|| Item::Table(Table::new()));
+        item.as_table_mut().ok_or_else(|| {
+            anyhow!(
+                "`{name}` is not a table in {}",
+                self.manifest_path.display()
+            )
+        })
+    }
+
+    /// Add (or replace) the dependency `name` from `source`.
+    pub fn add_dependency(
+        &mut self,
+        name: &str,
+        source: &DependencySource,
+        optional: bool,
+        features: &[&str],
+    ) -> anyhow::Result<()> {
+        let mut dep = InlineTable::new();
+        match source {
+            DependencySource::Latest => {
+                dep.insert("version", "*".into());
+            }
+            DependencySource::Version(version) => {
+                dep.insert("version", version.as_str().into());
+            }
+            DependencySource::Path(path) => {
+                let path = fs_err::canonicalize(path)?;
+                let path = path.to_str().ok_or_else(|| {
+                    anyhow!("non-UTF-8 path can't be put in a manifest: {path:?}")
+                })?;
+                dep.insert("path", path.into());
+            }
+        }
+        if optional {
+            dep.insert("optional", true.into());
+        }
+        if !features.is_empty() {
+            dep.insert(
+                "features",
+                features.iter().copied().collect::<Array>().into(),
+            );
+        }
+        self.table("dependencies")?
+            .insert(name, toml_edit::value(dep));
+        self.save()
+    }
+
+    /// Add (or replace) the feature `name`, enabling `enables`, like `dep:{optional dependency}`.
+    pub fn add_feature(&mut self, name: &str, enables: &[&str]) -> anyhow::Result<()> {
+        let enables = enables.iter().copied().collect::<Array>();
+        self.table("features")?
+            .insert(name, toml_edit::value(enables));
+        self.save()
+    }
+
+    fn save(&self) -> anyhow::Result<()> {
+        fs_err::write(&self.manifest_path, self.doc.to_string())?;
+        Ok(())
+    }
+
+    /// Restore the original `Cargo.toml` and `Cargo.lock`,
+    /// reporting errors unlike when dropped.
+    pub fn restore(mut self) -> anyhow::Result<()> {
+        self.restore_backups()
+    }
+
+    /// Restore every backup, even if an earlier one fails, and report the first error.
+    fn restore_backups(&mut self) -> anyhow::Result<()> {
+        let mut result = Ok(());
+        for backup in self.backups.drain(..) {
+            let restored = backup.restore();
+            if result.is_ok() {
+                result = restored;
+            }
+        }
+        result
+    }
+}
+
+impl Drop for ManifestGuard {
+    fn drop(&mut self) {
+        if let Err(e) = self.restore_backups() {
+            eprintln!(
+                "error restoring manifest {}: {e:#}",
+                self.manifest_path.display()
+            );
+        }
+    }
+}
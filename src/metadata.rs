@@ -0,0 +1,149 @@
+//! A typed workspace graph from `cargo metadata`.
+//!
+//! See <https://doc.rust-lang.org/cargo/commands/cargo-metadata.html#json-format>.
+
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+
+use anyhow::Context;
+use serde::Deserialize;
+use serde::Serialize;
+use tempfile::NamedTempFile;
+
+use crate::exit;
+
+/// The output of `cargo metadata --format-version 1`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Metadata {
+    pub packages: Vec<Package>,
+    /// The [`Package::id`]s of the workspace members.
+    pub workspace_members: Vec<String>,
+    pub workspace_root: PathBuf,
+    pub target_directory: PathBuf,
+    /// The resolved dependency graph, absent for `--no-deps`.
+    pub resolve: Option<Resolve>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Package {
+    pub name: String,
+    pub version: String,
+    /// An opaque package ID spec, used to refer to this package elsewhere in [`Metadata`].
+    pub id: String,
+    pub manifest_path: PathBuf,
+    pub edition: String,
+    pub targets: Vec<Target>,
+    pub features: BTreeMap<String, Vec<String>>,
+    pub dependencies: Vec<Dependency>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Target {
+    pub name: String,
+    /// Like `lib`, `bin`, `test`, or `custom-build`.
+    pub kind: Vec<String>,
+    pub crate_types: Vec<String>,
+    pub src_path: PathBuf,
+    pub edition: String,
+}
+
+/// A declared dependency, which may not have been resolved (e.g. an unused optional one).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Dependency {
+    pub name: String,
+    /// The version requirement.
+    pub req: String,
+    /// `dev`, `build`, or [`None`] for normal dependencies.
+    pub kind: Option<String>,
+    pub optional: bool,
+    pub rename: Option<String>,
+    pub target: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Resolve {
+    pub nodes: Vec<Node>,
+    /// The root package if not a virtual workspace.
+    pub root: Option<String>,
+}
+
+/// A resolved package and its dependency edges.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Node {
+    pub id: String,
+    pub deps: Vec<NodeDep>,
+    /// The enabled features.
+    pub features: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeDep {
+    /// The crate name as used in the dependent's code, i.e. after renaming.
+    pub name: String,
+    pub pkg: String,
+}
+
+impl Metadata {
+    /// Run `cmd` (already `cargo metadata` with any extra args) and parse its output.
+    pub(crate) fn query(mut cmd: Command) -> anyhow::Result<Self> {
+        cmd.args(["--format-version", "1"]);
+        let output = exit::checked_output(&mut cmd)?;
+        serde_json::from_slice(&output.stdout).context("invalid `cargo metadata` output")
+    }
+
+    /// Serialize to a temp file for passing to the `rustc` wrapper,
+    /// which reads it with [`Self::read`].
+    pub(crate) fn write_snapshot(&self) -> anyhow::Result<NamedTempFile> {
+        let mut file = NamedTempFile::new()?;
+        serde_json::to_writer(&mut file, self)?;
+        file.flush()?;
+        Ok(file)
+    }
+
+    pub(crate) fn read(path: &Path) -> anyhow::Result<Self> {
+        let json = fs_err::read(path)?;
+        serde_json::from_slice(&json)
+            .with_context(|| format!("invalid `cargo metadata` snapshot: {}", path.display()))
+    }
+
+    pub fn package(&self, id: &str) -> Option<&Package> {
+        self.packages.iter().find(|package| package.id == id)
+    }
+
+    pub fn is_workspace_member(&self, id: &str) -> bool {
+        self.workspace_members.iter().any(|member| member == id)
+    }
+
+    pub fn workspace_packages(&self) -> impl Iterator<Item = &Package> {
+        self.packages
+            .iter()
+            .filter(|package| self.is_workspace_member(&package.id))
+    }
+
+    /// The package whose manifest is in `manifest_dir`, like `$CARGO_MANIFEST_DIR`.
+    pub fn package_in(&self, manifest_dir: &Path) -> Option<&Package> {
+        self.packages
+            .iter()
+            .find(|package| package.manifest_path.parent() == Some(manifest_dir))
+    }
+
+    /// The resolved node for the package `id`.
+    pub fn node(&self, id: &str) -> Option<&Node> {
+        self.resolve
+            .as_ref()?
+            .nodes
+            .iter()
+            .find(|node| node.id == id)
+    }
+
+    /// The resolved direct dependencies of the package `id`.
+    pub fn dependencies_of<'a>(&'a self, id: &str) -> impl Iterator<Item = &'a Package> + use<'a> {
+        self.node(id)
+            .into_iter()
+            .flat_map(|node| &node.deps)
+            .filter_map(|dep| self.package(&dep.pkg))
+    }
+}
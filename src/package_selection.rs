@@ -0,0 +1,108 @@
+use anyhow::ensure;
+
+use crate::cargo_env::CargoEnv;
+use crate::util::glob_match;
+
+/// Which packages `cargo` was asked to build,
+/// from `-p`/`--package`, `--workspace`/`--all`, and `--exclude`,
+/// parsed in the `cargo` phase (see [`crate::CargoWrapper::package_selection`])
+/// and checked in the `rustc` phase (see [`crate::RustcWrapper::is_selected_package`]).
+///
+/// Specs are package names or `{name}@{version}`s (or `{name}:{version}`s),
+/// where the version can be partial, like `1.2` for `1.2.*`,
+/// and can be globs (supporting `*` and `?`), like `cargo`'s.
+/// URL specs, like `path+file:///dir#name@1.0.0`, aren't supported (see [`Self::matches`]).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum PackageSelection {
+    /// No selection flags: the package in the current dir, or else the workspace's default members.
+    #[default]
+    Default,
+    /// `-p`/`--package`s.
+    Packages(Vec<String>),
+    /// `--workspace`, except for any `--exclude`s.
+    Workspace { exclude: Vec<String> },
+}
+
+impl PackageSelection {
+    /// Whether the package of the crate described by `cargo_env` was selected.
+    ///
+    /// `cargo` only tells us which packages are workspace members by marking the selected ones
+    /// as primary (`$CARGO_PRIMARY_PACKAGE`), so that's what [`Self::Default`] relies on.
+    ///
+    /// This fails on URL specs, as we don't know which source the package came from.
+    pub fn matches(&self, cargo_env: &CargoEnv) -> anyhow::Result<bool> {
+        let matches_any = |specs: &[String]| -> anyhow::Result<bool> {
+            for spec in specs {
+                if spec_matches(
+                    spec,
+                    cargo_env.pkg_name.as_deref(),
+                    cargo_env.pkg_version.as_deref(),
+                )? {
+                    return Ok(true);
+                }
+            }
+            Ok(false)
+        };
+        Ok(match self {
+            Self::Default => cargo_env.primary_package,
+            Self::Packages(specs) => matches_any(specs)?,
+            Self::Workspace { exclude } => cargo_env.primary_package && !matches_any(exclude)?,
+        })
+    }
+
+    /// Serialize to pass from the `cargo` phase to the `rustc` phase in an env var.
+    pub(crate) fn to_env_value(&self) -> String {
+        let (kind, specs) = match self {
+            Self::Default => ("default", &[][..]),
+            Self::Packages(specs) => ("packages", specs.as_slice()),
+            Self::Workspace { exclude } => ("workspace", exclude.as_slice()),
+        };
+        [kind]
+            .into_iter()
+            .chain(specs.iter().map(String::as_str))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// The inverse of [`Self::to_env_value`].
+    pub(crate) fn from_env_value(value: &str) -> Self {
+        let mut lines = value.lines();
+        let kind = lines.next();
+        let specs = lines.map(ToOwned::to_owned).collect();
+        match kind {
+            Some("packages") => Self::Packages(specs),
+            Some("workspace") => Self::Workspace { exclude: specs },
+            _ => Self::Default,
+        }
+    }
+}
+
+/// Whether the package spec `spec` (see [`PackageSelection`]) matches a package `name` and `version`.
+fn spec_matches(spec: &str, name: Option<&str>, version: Option<&str>) -> anyhow::Result<bool> {
+    ensure!(
+        !spec.contains("://"),
+        "unsupported package spec `{spec}`, only `{{name}}` or `{{name}}@{{version}}` are supported"
+    );
+    let (name_spec, version_spec) = match spec.split_once(['@', ':']) {
+        Some((name, version)) => (name, Some(version)),
+        None => (spec, None),
+    };
+    if !name.is_some_and(|name| glob_match(name_spec, name)) {
+        return Ok(false);
+    }
+    Ok(match version_spec {
+        None => true,
+        Some(version_spec) => version.is_some_and(|version| version_matches(version_spec, version)),
+    })
+}
+
+/// Whether `version` matches `spec`, which may be partial (like `1` or `1.2`) or a glob.
+fn version_matches(spec: &str, version: &str) -> bool {
+    if glob_match(spec, version) {
+        return true;
+    }
+    let is_partial = spec.split('.').count() < 3;
+    version
+        .strip_prefix(spec)
+        .is_some_and(|rest| is_partial && rest.starts_with('.'))
+}
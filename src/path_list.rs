@@ -0,0 +1,112 @@
+//! Path list env vars, like `$PATH` and `$LD_LIBRARY_PATH`.
+
+use std::env;
+use std::ffi::OsString;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+
+use anyhow::Context;
+
+use crate::util::command_env;
+
+/// A path list env var, like `$PATH`, `$LD_LIBRARY_PATH`, or `$DYLD_FALLBACK_LIBRARY_PATH`,
+/// split on and joined with the platform's separator (`:`, or `;` on Windows).
+///
+/// Empty entries are kept, as they usually mean the current directory,
+/// which the original value may rely on, though [`Self::remove_empty`] can drop them.
+/// An empty value is an empty list, though, not a single empty entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathListVar {
+    key: &'static str,
+    paths: Vec<PathBuf>,
+}
+
+impl PathListVar {
+    /// An empty `$key`.
+    pub fn new(key: &'static str) -> Self {
+        Self {
+            key,
+            paths: Vec::new(),
+        }
+    }
+
+    /// `$key` from our own env.
+    pub fn from_env(key: &'static str) -> Self {
+        Self::parse(key, env::var_os(key))
+    }
+
+    /// `$key` as `cmd` will be run with it,
+    /// either set explicitly on `cmd` or else inherited from our own env.
+    pub fn from_command(cmd: &Command, key: &'static str) -> Self {
+        Self::parse(key, command_env(cmd, key))
+    }
+
+    fn parse(key: &'static str, value: Option<OsString>) -> Self {
+        let paths = value
+            .as_deref()
+            .filter(|value| !value.is_empty())
+            .map(env::split_paths)
+            .into_iter()
+            .flatten()
+            .collect();
+        Self { key, paths }
+    }
+
+    pub fn key(&self) -> &'static str {
+        self.key
+    }
+
+    /// The paths, in the order they're searched.
+    pub fn paths(&self) -> &[PathBuf] {
+        &self.paths
+    }
+
+    pub fn contains(&self, path: &Path) -> bool {
+        self.paths.iter().any(|p| p == path)
+    }
+
+    /// Put `path` first, so it's searched first, removing it from later in the list.
+    pub fn prepend(&mut self, path: impl Into<PathBuf>) {
+        let path = path.into();
+        self.paths.retain(|p| *p != path);
+        self.paths.insert(0, path);
+    }
+
+    /// Put `path` last, unless it's already in the list (and so already searched earlier).
+    pub fn append(&mut self, path: impl Into<PathBuf>) {
+        let path = path.into();
+        if !self.contains(&path) {
+            self.paths.push(path);
+        }
+    }
+
+    /// Remove repeated paths, which are never searched after their first occurrence.
+    pub fn dedup(&mut self) {
+        let mut seen = Vec::<PathBuf>::with_capacity(self.paths.len());
+        self.paths.retain(|path| {
+            if seen.contains(path) {
+                return false;
+            }
+            seen.push(path.clone());
+            true
+        });
+    }
+
+    /// Remove empty paths, which usually mean the current directory, which is rarely wanted.
+    pub fn remove_empty(&mut self) {
+        self.paths.retain(|path| !path.as_os_str().is_empty());
+    }
+
+    /// Join the paths into the env var's value,
+    /// failing if a path contains the separator.
+    pub fn to_os_string(&self) -> anyhow::Result<OsString> {
+        env::join_paths(&self.paths).with_context(|| format!("invalid `${}` path", self.key))
+    }
+
+    /// Set this var on `cmd`.
+    pub fn set_on(&self, cmd: &mut Command) -> anyhow::Result<()> {
+        cmd.env(self.key, self.to_os_string()?);
+        Ok(())
+    }
+}
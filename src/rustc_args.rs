@@ -0,0 +1,611 @@
+//! Typed views of `rustc` command line args.
+
+use std::convert::Infallible;
+use std::env;
+use std::ffi::OsStr;
+use std::ffi::OsString;
+use std::fmt;
+use std::fmt::Display;
+use std::fmt::Formatter;
+use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::anyhow;
+
+use crate::util::codegen_option_values;
+use crate::util::flag_values;
+use crate::util::os_str_split_once;
+use crate::util::remove_flag_if;
+
+/// `rustc` flags that take their value as a separate arg, like `-o {path}`.
+const FLAGS_WITH_VALUES: &[&str] = &[
+    "-o",
+    "-A",
+    "-W",
+    "-D",
+    "-F",
+    "-C",
+    "-Z",
+    "-L",
+    "-l",
+    "--cfg",
+    "--check-cfg",
+    "--crate-type",
+    "--crate-name",
+    "--edition",
+    "--emit",
+    "--print",
+    "--out-dir",
+    "--explain",
+    "--target",
+    "--allow",
+    "--warn",
+    "--force-warn",
+    "--deny",
+    "--forbid",
+    "--cap-lints",
+    "--codegen",
+    "--extern",
+    "--sysroot",
+    "--error-format",
+    "--json",
+    "--color",
+    "--diagnostic-width",
+    "--remap-path-prefix",
+];
+
+/// Whether `flag` takes its value as the next arg, like `-C` in `-C opt-level=3`.
+pub(crate) fn takes_separate_value(flag: &OsStr) -> bool {
+    FLAGS_WITH_VALUES.iter().any(|f| flag == *f)
+}
+
+/// The positional (non-flag) args, i.e. the input file (or `-` for stdin).
+pub(crate) fn positional_args(args: &[OsString]) -> impl Iterator<Item = &OsStr> {
+    let mut args = args.iter();
+    std::iter::from_fn(move || loop {
+        let arg = args.next()?;
+        if arg == "-" {
+            return Some(arg.as_os_str());
+        }
+        if takes_separate_value(arg) {
+            args.next();
+            continue;
+        }
+        if !arg.as_encoded_bytes().starts_with(b"-") {
+            return Some(arg.as_os_str());
+        }
+    })
+}
+
+/// An `--extern [{options}:]{name}[={path}]` arg, keyed by `name`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Extern {
+    /// Comma-separated options like `priv` or `noprelude`.
+    pub options: Vec<String>,
+
+    /// The path to the crate's `rlib`/`rmeta`/`dylib`,
+    /// or [`None`] for ones found in the sysroot, like `proc_macro`.
+    pub path: Option<PathBuf>,
+}
+
+impl Extern {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            options: Vec::new(),
+            path: Some(path.into()),
+        }
+    }
+
+    /// Parse the value of an `--extern` arg into its name and [`Extern`].
+    pub fn parse(value: &OsStr) -> anyhow::Result<(String, Self)> {
+        let (spec, path) = match os_str_split_once(value, b'=') {
+            Some((spec, path)) => (spec, Some(PathBuf::from(path))),
+            None => (value, None),
+        };
+        let spec = spec
+            .to_str()
+            .ok_or_else(|| anyhow!("non-UTF-8 `--extern` name: {value:?}"))?;
+        let (options, name) = match spec.split_once(':') {
+            Some((options, name)) => (options.split(',').map(ToOwned::to_owned).collect(), name),
+            None => (Vec::new(), spec),
+        };
+        Ok((name.to_owned(), Self { options, path }))
+    }
+
+    /// The value of the `--extern` arg for this [`Extern`] named `name`.
+    pub fn to_arg(&self, name: &str) -> OsString {
+        let mut arg = OsString::new();
+        if !self.options.is_empty() {
+            arg.push(self.options.join(","));
+            arg.push(":");
+        }
+        arg.push(name);
+        if let Some(path) = &self.path {
+            arg.push("=");
+            arg.push(path);
+        }
+        arg
+    }
+}
+
+fn is_extern_named(value: &OsStr, name: &str) -> bool {
+    Extern::parse(value).is_ok_and(|(extern_name, _)| extern_name == name)
+}
+
+/// Remove all `--extern`s named `name`.
+pub(crate) fn remove_extern(args: &mut Vec<OsString>, name: &str) {
+    remove_flag_if(args, "--extern", None, |value| is_extern_named(value, name));
+}
+
+/// Replace all `--extern`s named `name` with a single one.
+pub(crate) fn set_extern(args: &mut Vec<OsString>, name: &str, extern_: &Extern) {
+    remove_extern(args, name);
+    args.extend(["--extern".into(), extern_.to_arg(name)]);
+}
+
+/// A `--crate-type`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum CrateType {
+    Bin,
+    Lib,
+    Rlib,
+    Dylib,
+    Cdylib,
+    Staticlib,
+    ProcMacro,
+    /// A crate type we don't know, like a new or unstable one (e.g. `sdylib`),
+    /// left for `rustc` to accept or reject.
+    Other(String),
+}
+
+impl CrateType {
+    pub const ALL: [Self; 7] = [
+        Self::Bin,
+        Self::Lib,
+        Self::Rlib,
+        Self::Dylib,
+        Self::Cdylib,
+        Self::Staticlib,
+        Self::ProcMacro,
+    ];
+
+    /// The name used in `--crate-type`.
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Bin => "bin",
+            Self::Lib => "lib",
+            Self::Rlib => "rlib",
+            Self::Dylib => "dylib",
+            Self::Cdylib => "cdylib",
+            Self::Staticlib => "staticlib",
+            Self::ProcMacro => "proc-macro",
+            Self::Other(name) => name,
+        }
+    }
+}
+
+impl FromStr for CrateType {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self::ALL
+            .into_iter()
+            .find(|crate_type| crate_type.name() == s)
+            .unwrap_or_else(|| Self::Other(s.to_owned())))
+    }
+}
+
+impl Display for CrateType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// All the `--crate-type`s in `args`, split on `,`.
+pub(crate) fn crate_types(args: &[OsString]) -> anyhow::Result<Vec<CrateType>> {
+    let mut crate_types = Vec::new();
+    for value in flag_values(args, "--crate-type") {
+        let value = value
+            .to_str()
+            .ok_or_else(|| anyhow!("non-UTF-8 `--crate-type`: {value:?}"))?;
+        for crate_type in value.split(',') {
+            crate_types.push(crate_type.parse()?);
+        }
+    }
+    Ok(crate_types)
+}
+
+/// A Rust `--edition`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Edition {
+    #[default]
+    E2015,
+    E2018,
+    E2021,
+    E2024,
+}
+
+impl Edition {
+    pub const ALL: [Self; 4] = [Self::E2015, Self::E2018, Self::E2021, Self::E2024];
+
+    /// The name used in `--edition`, like `2021`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::E2015 => "2015",
+            Self::E2018 => "2018",
+            Self::E2021 => "2021",
+            Self::E2024 => "2024",
+        }
+    }
+}
+
+impl FromStr for Edition {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|edition| edition.name() == s)
+            .ok_or_else(|| anyhow!("unknown `--edition`: {s}"))
+    }
+}
+
+impl Display for Edition {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// A `cargo` build profile.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Profile {
+    /// The `dev` (and `test`) profile, built into `target/debug`.
+    Debug,
+    /// The `release` (and `bench`) profile, built into `target/release`.
+    Release,
+    /// A custom profile, built into `target/{name}`.
+    Custom(String),
+}
+
+impl Profile {
+    fn from_dir_name(name: &str) -> Self {
+        match name {
+            "debug" => Self::Debug,
+            "release" => Self::Release,
+            _ => Self::Custom(name.to_owned()),
+        }
+    }
+
+    /// Find the profile directory in a path inside `cargo`'s target dir,
+    /// like `target/{profile}/deps` or `target/{triple}/{profile}/build/{pkg}-{hash}/out`.
+    pub(crate) fn from_target_path(path: &Path) -> Option<Self> {
+        const PROFILE_SUBDIRS: &[&str] =
+            &["deps", "build", "examples", "incremental", ".fingerprint"];
+        let mut components = path.components().rev().peekable();
+        while let Some(component) = components.next() {
+            let is_profile_subdir = component
+                .as_os_str()
+                .to_str()
+                .is_some_and(|name| PROFILE_SUBDIRS.contains(&name));
+            if !is_profile_subdir {
+                continue;
+            }
+            let profile = components.peek()?.as_os_str().to_str()?;
+            return Some(Self::from_dir_name(profile));
+        }
+        None
+    }
+}
+
+/// A kind of output `rustc` can `--emit`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum EmitKind {
+    Asm,
+    LlvmBc,
+    LlvmIr,
+    Obj,
+    Metadata,
+    Link,
+    DepInfo,
+    Mir,
+}
+
+impl EmitKind {
+    pub const ALL: [Self; 8] = [
+        Self::Asm,
+        Self::LlvmBc,
+        Self::LlvmIr,
+        Self::Obj,
+        Self::Metadata,
+        Self::Link,
+        Self::DepInfo,
+        Self::Mir,
+    ];
+
+    /// The name used in `--emit`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Asm => "asm",
+            Self::LlvmBc => "llvm-bc",
+            Self::LlvmIr => "llvm-ir",
+            Self::Obj => "obj",
+            Self::Metadata => "metadata",
+            Self::Link => "link",
+            Self::DepInfo => "dep-info",
+            Self::Mir => "mir",
+        }
+    }
+
+    /// The file extension `rustc` uses for this kind,
+    /// except for [`EmitKind::Link`], whose extension depends on the crate type.
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Asm => "s",
+            Self::LlvmBc => "bc",
+            Self::LlvmIr => "ll",
+            Self::Obj => "o",
+            Self::Metadata => "rmeta",
+            Self::Link => "",
+            Self::DepInfo => "d",
+            Self::Mir => "mir",
+        }
+    }
+}
+
+impl FromStr for EmitKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|kind| kind.name() == s)
+            .ok_or_else(|| anyhow!("unknown `--emit` kind: {s}"))
+    }
+}
+
+impl Display for EmitKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// An `--emit {kind}[={path}]` output.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Emit {
+    pub kind: EmitKind,
+
+    /// An explicit output path, overriding the default.
+    pub path: Option<PathBuf>,
+}
+
+impl Emit {
+    /// Parse the (comma-separated) value of an `--emit` arg.
+    pub fn parse_list(value: &OsStr) -> anyhow::Result<Vec<Self>> {
+        let value = value
+            .to_str()
+            .ok_or_else(|| anyhow!("non-UTF-8 `--emit`: {value:?}"))?;
+        value
+            .split(',')
+            .map(|emit| {
+                let (kind, path) = match emit.split_once('=') {
+                    Some((kind, path)) => (kind, Some(PathBuf::from(path))),
+                    None => (emit, None),
+                };
+                Ok(Self {
+                    kind: kind.parse()?,
+                    path,
+                })
+            })
+            .collect()
+    }
+}
+
+impl Emit {
+    /// The (comma-separated) value of an `--emit` arg for these [`Emit`]s.
+    pub fn to_arg_list(emits: &[Self]) -> OsString {
+        let mut arg = OsString::new();
+        for (i, Self { kind, path }) in emits.iter().enumerate() {
+            if i != 0 {
+                arg.push(",");
+            }
+            arg.push(kind.name());
+            if let Some(path) = path {
+                arg.push("=");
+                arg.push(path);
+            }
+        }
+        arg
+    }
+}
+
+/// All the `--emit`s in `args`, defaulting to just [`EmitKind::Link`] like `rustc` does.
+pub(crate) fn emits(args: &[OsString]) -> anyhow::Result<Vec<Emit>> {
+    let mut emits = Vec::new();
+    for value in flag_values(args, "--emit") {
+        emits.extend(Emit::parse_list(value)?);
+    }
+    if emits.is_empty() {
+        emits.push(Emit {
+            kind: EmitKind::Link,
+            path: None,
+        });
+    }
+    Ok(emits)
+}
+
+/// A file `rustc` will produce.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputPath {
+    pub kind: EmitKind,
+
+    /// The crate type, only for [`EmitKind::Link`] outputs.
+    pub crate_type: Option<CrateType>,
+
+    pub path: PathBuf,
+}
+
+/// How a target names its linked files.
+struct FileNaming {
+    dll_prefix: &'static str,
+    dll_suffix: &'static str,
+    exe_suffix: &'static str,
+    staticlib_prefix: &'static str,
+    staticlib_suffix: &'static str,
+}
+
+impl FileNaming {
+    /// The naming for the target `triple`, or for the host if [`None`].
+    fn for_target(triple: Option<&str>) -> Self {
+        let Some(triple) = triple else {
+            let msvc = cfg!(target_env = "msvc");
+            return Self {
+                dll_prefix: env::consts::DLL_PREFIX,
+                dll_suffix: env::consts::DLL_SUFFIX,
+                exe_suffix: env::consts::EXE_SUFFIX,
+                staticlib_prefix: if msvc { "" } else { "lib" },
+                staticlib_suffix: if msvc { ".lib" } else { ".a" },
+            };
+        };
+        if triple.contains("windows") {
+            let msvc = triple.ends_with("-msvc");
+            Self {
+                dll_prefix: "",
+                dll_suffix: ".dll",
+                exe_suffix: ".exe",
+                staticlib_prefix: if msvc { "" } else { "lib" },
+                staticlib_suffix: if msvc { ".lib" } else { ".a" },
+            }
+        } else if triple.contains("apple") {
+            Self {
+                dll_prefix: "lib",
+                dll_suffix: ".dylib",
+                exe_suffix: "",
+                staticlib_prefix: "lib",
+                staticlib_suffix: ".a",
+            }
+        } else if triple.starts_with("wasm") {
+            Self {
+                dll_prefix: "",
+                dll_suffix: ".wasm",
+                exe_suffix: ".wasm",
+                staticlib_prefix: "lib",
+                staticlib_suffix: ".a",
+            }
+        } else {
+            Self {
+                dll_prefix: "lib",
+                dll_suffix: ".so",
+                exe_suffix: "",
+                staticlib_prefix: "lib",
+                staticlib_suffix: ".a",
+            }
+        }
+    }
+
+    /// The linked file name, or [`None`] for [`CrateType::Other`]s, whose naming we don't know.
+    fn link_file_name(&self, crate_type: &CrateType, stem: &str) -> Option<String> {
+        Some(match crate_type {
+            CrateType::Bin => format!("{stem}{}", self.exe_suffix),
+            CrateType::Dylib | CrateType::Cdylib | CrateType::ProcMacro => {
+                format!("{}{stem}{}", self.dll_prefix, self.dll_suffix)
+            }
+            CrateType::Staticlib => {
+                format!("{}{stem}{}", self.staticlib_prefix, self.staticlib_suffix)
+            }
+            CrateType::Lib | CrateType::Rlib => format!("lib{stem}.rlib"),
+            CrateType::Other(_) => return None,
+        })
+    }
+}
+
+/// Compute the files `rustc` will produce for `args`.
+pub(crate) fn output_paths(
+    args: &[OsString],
+    crate_name: Option<&str>,
+    crate_types: &[CrateType],
+) -> anyhow::Result<Vec<OutputPath>> {
+    let output = flag_values(args, "-o").last().map(Path::new);
+    let (out_dir, stem) = match output {
+        Some(output) => {
+            let stem = output
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .ok_or_else(|| anyhow!("invalid `-o`: {}", output.display()))?;
+            (output.parent().unwrap_or(Path::new("")), stem.to_owned())
+        }
+        None => {
+            let out_dir = flag_values(args, "--out-dir")
+                .last()
+                .map_or(Path::new(""), Path::new);
+            let crate_name = crate_name.ok_or_else(|| anyhow!("can't determine the crate name"))?;
+            let extra_filename = codegen_option_values(args, "extra-filename")
+                .last()
+                .map(|extra| {
+                    extra
+                        .to_str()
+                        .ok_or_else(|| anyhow!("non-UTF-8 `-C extra-filename`: {extra:?}"))
+                })
+                .transpose()?
+                .unwrap_or_default();
+            (out_dir, format!("{crate_name}{extra_filename}"))
+        }
+    };
+    let crate_types = if crate_types.is_empty() {
+        &[CrateType::Bin][..]
+    } else {
+        crate_types
+    };
+    let target = flag_values(args, "--target").last().and_then(OsStr::to_str);
+    let naming = FileNaming::for_target(target);
+
+    let mut outputs = Vec::new();
+    for Emit { kind, path } in emits(args)? {
+        if kind == EmitKind::Link {
+            for crate_type in crate_types {
+                let path = match (&path, output) {
+                    (Some(path), _) => path.clone(),
+                    (None, Some(output)) => output.to_owned(),
+                    (None, None) => match naming.link_file_name(crate_type, &stem) {
+                        Some(file_name) => out_dir.join(file_name),
+                        // We can't tell what `rustc` will name it.
+                        None => continue,
+                    },
+                };
+                outputs.push(OutputPath {
+                    kind,
+                    crate_type: Some(crate_type.clone()),
+                    path,
+                });
+            }
+            continue;
+        }
+        let path = path.unwrap_or_else(|| {
+            let file_name = match kind {
+                EmitKind::Metadata => format!("lib{stem}.rmeta"),
+                _ => format!("{stem}.{}", kind.extension()),
+            };
+            out_dir.join(file_name)
+        });
+        outputs.push(OutputPath {
+            kind,
+            crate_type: None,
+            path,
+        });
+    }
+    Ok(outputs)
+}
+
+/// Modify the `--emit`s in `args` with `f`, replacing them with a single `--emit`.
+///
+/// Does nothing if the existing `--emit`s can't be parsed, leaving that for `rustc` to report.
+pub(crate) fn modify_emits(args: &mut Vec<OsString>, f: impl FnOnce(&mut Vec<Emit>)) {
+    let Ok(mut emits) = emits(args) else {
+        return;
+    };
+    f(&mut emits);
+    remove_flag_if(args, "--emit", None, |_| true);
+    if !emits.is_empty() {
+        args.extend(["--emit".into(), Emit::to_arg_list(&emits)]);
+    }
+}
@@ -0,0 +1,142 @@
+//! Wrapping `rustdoc` (as `$RUSTDOC`) for `cargo doc` and doctests.
+
+use std::ffi::OsString;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+
+use anyhow::anyhow;
+
+use crate::argfile;
+use crate::cargo_args::Verbosity;
+use crate::cargo_env::CargoEnv;
+use crate::exit;
+use crate::prepare_rustc_command;
+use crate::signals;
+use crate::util::flag_values;
+use crate::util::EnvVar;
+use crate::util::TypedEnvVar;
+use crate::FAILURE_LOG_VAR;
+use crate::REAL_RUSTDOC_VAR;
+use crate::VERBOSITY_VAR;
+
+/// An invocation of `rustdoc` by `cargo` (e.g. for `cargo doc` or doctests),
+/// when wrapping it is enabled with [`crate::CargoWrapper::set_wrap_rustdoc`].
+///
+/// Unlike `$RUSTC_WRAPPER`, `cargo` has no `rustdoc` wrapper,
+/// so this replaces `rustdoc` itself as `$RUSTDOC`, and the real `rustdoc` is passed separately.
+pub struct RustdocWrapper {
+    /// The real `rustdoc`.
+    rustdoc: PathBuf,
+    args: Vec<OsString>,
+    cargo_env: CargoEnv,
+    /// Extra env vars to set for [`Self::run_rustdoc`].
+    envs: Vec<(OsString, OsString)>,
+    verbosity: Verbosity,
+    /// See [`crate::CargoWrapper::set_failure_log`].
+    failure_log: Option<PathBuf>,
+}
+
+impl RustdocWrapper {
+    pub(crate) fn new() -> anyhow::Result<Self> {
+        let rustdoc = EnvVar::get_path(REAL_RUSTDOC_VAR)
+            .ok_or_else(|| {
+                anyhow!("the `cargo` wrapper should've set `${REAL_RUSTDOC_VAR}` for the `rustdoc` wrapper")
+            })?
+            .value;
+        let args = argfile::expand(std::env::args_os().skip(1).collect())?;
+        let verbosity = TypedEnvVar::<Verbosity>::new(VERBOSITY_VAR)
+            .get()?
+            .unwrap_or_default();
+        Ok(Self {
+            rustdoc,
+            args,
+            cargo_env: CargoEnv::from_env()?,
+            envs: Vec::new(),
+            verbosity,
+            failure_log: EnvVar::get_path(FAILURE_LOG_VAR).map(|var| var.value),
+        })
+    }
+
+    /// The real `rustdoc`: the one `cargo` would've otherwise used, or else the sysroot's.
+    pub fn rustdoc_path(&self) -> &Path {
+        &self.rustdoc
+    }
+
+    /// The args passed to `rustdoc`, with any `@path` argfiles expanded.
+    pub fn args(&self) -> &[OsString] {
+        &self.args
+    }
+
+    pub fn cargo_env(&self) -> &CargoEnv {
+        &self.cargo_env
+    }
+
+    /// Whether this is documenting a package `cargo` was asked to build (see [`CargoEnv::primary_package`]).
+    pub fn is_primary_package(&self) -> bool {
+        self.cargo_env.primary_package
+    }
+
+    /// The `--crate-name` passed to `rustdoc`, or else `$CARGO_CRATE_NAME`.
+    pub fn crate_name(&self) -> Option<String> {
+        flag_values(&self.args, "--crate-name")
+            .next()
+            .and_then(|name| name.to_str())
+            .map(|name| name.to_owned())
+            .or_else(|| self.cargo_env.crate_name.clone())
+    }
+
+    /// Whether this is running doctests (`--test`) rather than generating docs.
+    pub fn is_doctest(&self) -> bool {
+        self.args.iter().any(|arg| arg == "--test")
+    }
+
+    /// Whether this is `cargo` probing `rustdoc`, like `rustdoc -vV`, rather than documenting a crate.
+    ///
+    /// These are always passed straight through to `rustdoc` by [`crate::wrap_cargo_or_rustc`].
+    pub fn is_probe(&self) -> bool {
+        flag_values(&self.args, "--crate-name").next().is_none()
+    }
+
+    /// The verbosity `cargo` was run with (see [`crate::CargoWrapper::verbosity`]).
+    pub fn verbosity(&self) -> Verbosity {
+        self.verbosity
+    }
+
+    pub fn add_args<I, S>(&mut self, args: I)
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<OsString>,
+    {
+        self.args.extend(args.into_iter().map(Into::into));
+    }
+
+    /// Add `--cfg {cfg}`, e.g. to match a `cfg` added to the crate by the `rustc` wrapper.
+    pub fn add_cfg(&mut self, cfg: impl Into<OsString>) {
+        self.add_args(["--cfg".into(), cfg.into()]);
+    }
+
+    /// Set an env var for [`Self::run_rustdoc`].
+    pub fn set_env(&mut self, key: impl Into<OsString>, value: impl Into<OsString>) {
+        let key = key.into();
+        let value = value.into();
+        self.envs.retain(|(k, _)| *k != key);
+        self.envs.push((key, value));
+    }
+
+    /// Run the real `rustdoc` (from [`Self::rustdoc_path`]) with the args,
+    /// failing with [`crate::CommandFailed`] if it fails.
+    ///
+    /// If the args are too long for the OS, they're passed through an `@path` argfile instead.
+    pub fn run_rustdoc(self) -> anyhow::Result<()> {
+        let mut rustdoc = Command::new(&self.rustdoc);
+        let _argfile = prepare_rustc_command(&mut rustdoc, &self.args, &self.envs)?;
+        let status = signals::status(&mut rustdoc)?;
+        exit::check_compiler_status(
+            &rustdoc,
+            status,
+            self.verbosity,
+            self.failure_log.as_deref(),
+        )
+    }
+}
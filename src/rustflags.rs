@@ -0,0 +1,355 @@
+use std::ffi::OsStr;
+use std::ffi::OsString;
+use std::process::Command;
+
+use crate::rustc_args::takes_separate_value;
+use crate::util::command_env;
+use crate::util::os_str_split;
+use crate::util::os_str_split_once;
+use crate::util::os_str_split_whitespace;
+use crate::util::os_str_strip_prefix;
+use crate::ENV_LIST_SEPARATOR;
+
+/// The env vars a kind of flags are passed through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FlagVars {
+    space_separated: &'static str,
+    encoded: &'static str,
+}
+
+const RUSTFLAGS_VARS: FlagVars = FlagVars {
+    space_separated: "RUSTFLAGS",
+    encoded: "CARGO_ENCODED_RUSTFLAGS",
+};
+
+const RUSTDOCFLAGS_VARS: FlagVars = FlagVars {
+    space_separated: "RUSTDOCFLAGS",
+    encoded: "CARGO_ENCODED_RUSTDOCFLAGS",
+};
+
+impl Default for FlagVars {
+    fn default() -> Self {
+        RUSTFLAGS_VARS
+    }
+}
+
+/// Extra `rustc` flags passed through `cargo`,
+/// from `$CARGO_ENCODED_RUSTFLAGS` (0x1f-separated), which takes precedence,
+/// or else `$RUSTFLAGS` (space-separated).
+///
+/// The same goes for `rustdoc` flags with `$CARGO_ENCODED_RUSTDOCFLAGS` and `$RUSTDOCFLAGS`
+/// (see [`Self::rustdoc`]).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RustFlags {
+    flags: Vec<OsString>,
+    /// Whether these came from the encoded var.
+    encoded: bool,
+    vars: FlagVars,
+}
+
+impl RustFlags {
+    /// Empty `rustdoc` flags, set in `$RUSTDOCFLAGS` or `$CARGO_ENCODED_RUSTDOCFLAGS`.
+    pub fn rustdoc() -> Self {
+        Self {
+            vars: RUSTDOCFLAGS_VARS,
+            ..Default::default()
+        }
+    }
+
+    fn from_vars(
+        vars: FlagVars,
+        encoded: Option<OsString>,
+        space_separated: Option<OsString>,
+    ) -> Option<Self> {
+        let flags = match (encoded, space_separated) {
+            (Some(encoded), _) => Self::parse_encoded(&encoded),
+            (None, Some(space_separated)) => Self::parse_space_separated(&space_separated),
+            (None, None) => return None,
+        };
+        Some(Self { vars, ..flags })
+    }
+
+    /// The flags `cargo` run from this process would use,
+    /// or [`None`] if neither var is set, in which case `cargo` uses its config instead
+    /// (see [`crate::CargoConfig::rustflags`]).
+    pub fn from_env() -> Option<Self> {
+        Self::from_env_vars(RUSTFLAGS_VARS)
+    }
+
+    /// Like [`Self::from_env`], but for `rustdoc` flags.
+    pub fn rustdoc_from_env() -> Option<Self> {
+        Self::from_env_vars(RUSTDOCFLAGS_VARS)
+    }
+
+    fn from_env_vars(vars: FlagVars) -> Option<Self> {
+        Self::from_vars(
+            vars,
+            std::env::var_os(vars.encoded),
+            std::env::var_os(vars.space_separated),
+        )
+    }
+
+    /// The flags `cmd` (`cargo`) will use, either set on it or inherited,
+    /// or [`None`] if neither var is set.
+    pub fn from_command(cmd: &Command) -> Option<Self> {
+        Self::from_command_vars(RUSTFLAGS_VARS, cmd)
+    }
+
+    /// Like [`Self::from_command`], but for `rustdoc` flags.
+    pub fn rustdoc_from_command(cmd: &Command) -> Option<Self> {
+        Self::from_command_vars(RUSTDOCFLAGS_VARS, cmd)
+    }
+
+    fn from_command_vars(vars: FlagVars, cmd: &Command) -> Option<Self> {
+        Self::from_vars(
+            vars,
+            command_env(cmd, vars.encoded),
+            command_env(cmd, vars.space_separated),
+        )
+    }
+
+    /// Parse `$RUSTFLAGS`-style whitespace-separated flags.
+    pub fn parse_space_separated(flags: &OsStr) -> Self {
+        Self {
+            flags: os_str_split_whitespace(flags)
+                .map(ToOwned::to_owned)
+                .collect(),
+            encoded: false,
+            vars: RUSTFLAGS_VARS,
+        }
+    }
+
+    /// Parse `$CARGO_ENCODED_RUSTFLAGS`-style 0x1f-separated flags,
+    /// which can contain spaces.
+    pub fn parse_encoded(flags: &OsStr) -> Self {
+        Self {
+            flags: if flags.is_empty() {
+                Vec::new()
+            } else {
+                os_str_split(flags, ENV_LIST_SEPARATOR)
+                    .map(ToOwned::to_owned)
+                    .collect()
+            },
+            encoded: true,
+            vars: RUSTFLAGS_VARS,
+        }
+    }
+
+    pub fn flags(&self) -> &[OsString] {
+        &self.flags
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.flags.is_empty()
+    }
+
+    pub fn push(&mut self, flag: impl Into<OsString>) {
+        self.flags.push(flag.into());
+    }
+
+    /// Remove repeated flags (along with their values, like `-C opt-level=3`, however they're written),
+    /// where that can't change what they do.
+    ///
+    /// Flags where later ones take precedence, like `-C` and lint levels, keep their last occurrence,
+    /// and ones where earlier ones take precedence, like `-L` and `--extern`, keep their first.
+    /// Others, like `-l` and `-C link-arg`, which may need to be repeated, are all kept.
+    pub fn dedup(&mut self) {
+        let mut groups = Vec::new();
+        let mut rest = self.flags.as_slice();
+        while !rest.is_empty() {
+            let (len, key) = FlagKey::split(rest);
+            let (group, after) = rest.split_at(len);
+            groups.push((group, key));
+            rest = after;
+        }
+        let flags = groups
+            .iter()
+            .enumerate()
+            .filter(|(i, (_, key))| {
+                let (before, after) = (&groups[..*i], &groups[i + 1..]);
+                match key.keep() {
+                    Keep::Last => !after.iter().any(|(_, k)| k == key),
+                    Keep::First => !before.iter().any(|(_, k)| k == key),
+                    Keep::All => true,
+                }
+            })
+            .flat_map(|(_, (group, _))| group.iter().cloned())
+            .collect();
+        self.flags = flags;
+    }
+
+    /// Whether these need `$CARGO_ENCODED_RUSTFLAGS`,
+    /// because they came from it or a flag contains whitespace.
+    pub(crate) fn needs_encoding(&self) -> bool {
+        self.encoded
+            || self
+                .flags
+                .iter()
+                .any(|flag| flag.as_encoded_bytes().iter().any(u8::is_ascii_whitespace))
+    }
+
+    /// The var and value to pass these flags in:
+    /// the space-separated `$RUSTFLAGS` if possible,
+    /// or else the 0x1f-separated `$CARGO_ENCODED_RUSTFLAGS`.
+    pub fn to_env_var(&self) -> (&'static str, OsString) {
+        if self.needs_encoding() {
+            let separator = OsString::from(char::from(ENV_LIST_SEPARATOR).to_string());
+            (self.vars.encoded, self.flags.join(separator.as_os_str()))
+        } else {
+            (self.vars.space_separated, self.flags.join(OsStr::new(" ")))
+        }
+    }
+
+    /// Set these flags on `cmd` (see [`Self::to_env_var`]),
+    /// removing the other var so it can't conflict.
+    pub fn set_on(&self, cmd: &mut Command) {
+        let FlagVars {
+            space_separated,
+            encoded,
+        } = self.vars;
+        let (key, value) = self.to_env_var();
+        cmd.env(key, value);
+        let other = if key == encoded {
+            space_separated
+        } else {
+            encoded
+        };
+        cmd.env_remove(other);
+    }
+}
+
+/// A flag and its value, however it was written,
+/// like `-C opt-level=3` or `-Copt-level=3`, or `--cfg x` or `--cfg=x`.
+#[derive(PartialEq, Eq)]
+struct FlagKey<'a> {
+    flag: &'a OsStr,
+    value: Option<&'a OsStr>,
+}
+
+/// Which of a repeated [`FlagKey`] [`RustFlags::dedup`] keeps.
+enum Keep {
+    Last,
+    First,
+    All,
+}
+
+impl<'a> FlagKey<'a> {
+    /// Split the first flag off of `flags`, returning how many args it spans.
+    fn split(flags: &'a [OsString]) -> (usize, Self) {
+        let first = flags[0].as_os_str();
+        if let Some(value) = flags.get(1).filter(|_| takes_separate_value(first)) {
+            let key = Self {
+                flag: first,
+                value: Some(value),
+            };
+            return (2, key);
+        }
+        if os_str_strip_prefix(first, "--").is_some() {
+            if let Some((flag, value)) = os_str_split_once(first, b'=') {
+                if takes_separate_value(flag) {
+                    let key = Self {
+                        flag,
+                        value: Some(value),
+                    };
+                    return (1, key);
+                }
+            }
+        }
+        for short in SHORT_FLAGS_WITH_VALUES {
+            if let Some(value) = os_str_strip_prefix(first, short).filter(|value| !value.is_empty())
+            {
+                let key = Self {
+                    flag: OsStr::new(short),
+                    value: Some(value),
+                };
+                return (1, key);
+            }
+        }
+        let key = Self {
+            flag: first,
+            value: None,
+        };
+        (1, key)
+    }
+
+    fn keep(&self) -> Keep {
+        let value = self.value.unwrap_or_default();
+        match self.flag.to_str() {
+            Some("-C" | "--codegen") if os_str_strip_prefix(value, "link-arg").is_some() => {
+                Keep::All
+            }
+            Some(
+                "-C" | "--codegen" | "-Z" | "-A" | "-W" | "-D" | "-F" | "--allow" | "--warn"
+                | "--deny" | "--forbid" | "--force-warn" | "--cap-lints",
+            ) => Keep::Last,
+            Some("-L" | "--extern" | "--cfg" | "--check-cfg") => Keep::First,
+            _ => Keep::All,
+        }
+    }
+}
+
+/// The short flags that can take their value attached, like `-Copt-level=3`.
+const SHORT_FLAGS_WITH_VALUES: [&str; 8] = ["-C", "-Z", "-L", "-l", "-A", "-W", "-D", "-F"];
+
+impl<T: Into<OsString>> Extend<T> for RustFlags {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        self.flags.extend(iter.into_iter().map(Into::into));
+    }
+}
+
+impl IntoIterator for RustFlags {
+    type Item = OsString;
+    type IntoIter = std::vec::IntoIter<OsString>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.flags.into_iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dedup(flags: &str) -> String {
+        let mut flags = RustFlags::parse_space_separated(OsStr::new(flags));
+        flags.dedup();
+        flags.to_env_var().1.into_string().unwrap()
+    }
+
+    #[test]
+    fn dedup_keeps_first_search_path() {
+        assert_eq!(dedup("-L a -L b -L a"), "-L a -L b");
+    }
+
+    #[test]
+    fn dedup_keeps_repeated_libs() {
+        assert_eq!(dedup("-l foo -l bar -l foo"), "-l foo -l bar -l foo");
+        assert_eq!(
+            dedup("-C link-arg=-lfoo -C link-arg=-lfoo"),
+            "-C link-arg=-lfoo -C link-arg=-lfoo"
+        );
+    }
+
+    #[test]
+    fn dedup_keeps_last_codegen_option() {
+        assert_eq!(dedup("-Copt-level=3 -C opt-level=3"), "-C opt-level=3");
+        assert_eq!(dedup("-C opt-level=3 -Copt-level=3"), "-Copt-level=3");
+        assert_eq!(
+            dedup("-C opt-level=3 -C opt-level=2 -C opt-level=3"),
+            "-C opt-level=2 -C opt-level=3"
+        );
+    }
+
+    #[test]
+    fn dedup_keeps_last_lint_level() {
+        assert_eq!(
+            dedup("-A warnings -D warnings -A warnings"),
+            "-D warnings -A warnings"
+        );
+    }
+
+    #[test]
+    fn dedup_cfgs() {
+        assert_eq!(dedup("--cfg x --cfg=x --cfg y"), "--cfg x --cfg y");
+    }
+}
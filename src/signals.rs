@@ -0,0 +1,405 @@
+//! Forwarding `SIGINT` and `SIGTERM` to spawned children (on Unix),
+//! so that we wait for them to exit and clean up after them, instead of dying first
+//! and leaving them orphaned,
+//! and killing them if we're killed abruptly anyways
+//! (on Linux and Windows, see [`kill_with_parent`] and [`job`])
+//! or if they run for too long (see [`Waiter`]).
+
+use std::io;
+use std::io::Read;
+use std::process::Child;
+use std::process::Command;
+use std::process::ExitStatus;
+use std::process::Output;
+use std::process::Stdio;
+use std::sync::mpsc;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use std::time::Instant;
+
+/// Forwards signals to a child while alive, see [`ForwardSignals::to`].
+pub(crate) struct ForwardSignals {
+    #[cfg(unix)]
+    slot: Option<(usize, i32)>,
+}
+
+#[cfg(unix)]
+mod imp {
+    use std::io;
+    use std::ptr;
+    use std::sync::atomic::AtomicI32;
+    use std::sync::atomic::Ordering;
+    use std::sync::Once;
+
+    /// More children than we ever run at once; any beyond this just don't get signals forwarded.
+    const MAX_CHILDREN: usize = 64;
+
+    /// The pids of children to forward signals to, or 0 for free slots.
+    /// Atomics since they're read in the signal handler.
+    pub static CHILDREN: [AtomicI32; MAX_CHILDREN] = [const { AtomicI32::new(0) }; MAX_CHILDREN];
+
+    /// Register `pid`, returning its slot and the `pid` as stored.
+    pub fn register(pid: u32) -> Option<(usize, i32)> {
+        install();
+        let pid = i32::try_from(pid).ok()?;
+        let slot = CHILDREN.iter().position(|slot| {
+            slot.compare_exchange(0, pid, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+        })?;
+        Some((slot, pid))
+    }
+
+    /// Unregister `pid` from `slot`, unless it was already unregistered (see [`unregister_pid`])
+    /// and the slot reused.
+    pub fn unregister(slot: usize, pid: i32) {
+        let _ = CHILDREN[slot].compare_exchange(pid, 0, Ordering::SeqCst, Ordering::SeqCst);
+    }
+
+    /// Stop forwarding signals to `pid`, which must be done before it's reaped,
+    /// as then its pid may be reused by an unrelated process.
+    pub fn unregister_pid(pid: u32) {
+        let Ok(pid) = i32::try_from(pid) else {
+            return;
+        };
+        for slot in &CHILDREN {
+            let _ = slot.compare_exchange(pid, 0, Ordering::SeqCst, Ordering::SeqCst);
+        }
+    }
+
+    /// Wait for `pid` to exit without reaping it (with `WNOWAIT`),
+    /// returning whether it has exited, which is only `false` if `block` is `false`.
+    pub fn wait_exited(pid: u32, block: bool) -> io::Result<bool> {
+        let mut options = libc::WEXITED | libc::WNOWAIT;
+        if !block {
+            options |= libc::WNOHANG;
+        }
+        loop {
+            // SAFETY: `info` is zeroed, so `si_signo` is still 0 if no child has exited yet.
+            let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+            // SAFETY: `info` is a valid `siginfo_t` to write to.
+            let ret = unsafe { libc::waitid(libc::P_PID, pid as libc::id_t, &mut info, options) };
+            if ret == 0 {
+                return Ok(info.si_signo != 0);
+            }
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+        }
+    }
+
+    extern "C" fn forward(signal: libc::c_int) {
+        let mut forwarded = false;
+        for child in &CHILDREN {
+            let pid = child.load(Ordering::SeqCst);
+            if pid != 0 {
+                // SAFETY: `kill` is async-signal-safe.
+                unsafe { libc::kill(pid, signal) };
+                forwarded = true;
+            }
+        }
+        if !forwarded {
+            // No child to wait for, so die like we would've without the handler.
+            // SAFETY: `signal` and `raise` are async-signal-safe.
+            unsafe {
+                libc::signal(signal, libc::SIG_DFL);
+                libc::raise(signal);
+            }
+        }
+    }
+
+    fn install() {
+        static INSTALLED: Once = Once::new();
+        INSTALLED.call_once(|| {
+            for signal in [libc::SIGINT, libc::SIGTERM] {
+                // SAFETY: `forward` only does async-signal-safe things,
+                // and the `sigaction`s are fully initialized.
+                unsafe {
+                    let mut old: libc::sigaction = std::mem::zeroed();
+                    libc::sigaction(signal, ptr::null(), &mut old);
+                    // Stay ignoring it if we were started that way, like by `nohup`.
+                    if old.sa_sigaction == libc::SIG_IGN {
+                        continue;
+                    }
+                    let mut action: libc::sigaction = std::mem::zeroed();
+                    action.sa_sigaction = forward as extern "C" fn(libc::c_int) as usize;
+                    action.sa_flags = libc::SA_RESTART;
+                    libc::sigemptyset(&mut action.sa_mask);
+                    libc::sigaction(signal, &action, ptr::null_mut());
+                }
+            }
+        });
+    }
+}
+
+impl ForwardSignals {
+    /// Forward `SIGINT` and `SIGTERM` received by us to `child` until this is dropped
+    /// or `child` is waited for with [`wait`], installing the handlers the first time.
+    ///
+    /// Once a child is registered, these signals no longer kill us directly,
+    /// so we can wait for the child, clean up, and then propagate its status
+    /// (see [`crate::exit_with`]).
+    /// If no child is registered, they kill us like normal.
+    ///
+    /// This does nothing on other platforms,
+    /// where, like on Unix, Ctrl-C is already sent to the whole console.
+    pub(crate) fn to(child: &Child) -> Self {
+        #[cfg(unix)]
+        return Self {
+            slot: imp::register(child.id()),
+        };
+        #[cfg(not(unix))]
+        {
+            let _ = child;
+            Self {}
+        }
+    }
+}
+
+impl Drop for ForwardSignals {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Some((slot, pid)) = self.slot {
+            imp::unregister(slot, pid);
+        }
+    }
+}
+
+/// Have `cmd`'s process be killed (with `SIGTERM`) when we die, even if we're `SIGKILL`ed,
+/// so that it doesn't linger and hold `cargo`'s locks on the target dir.
+///
+/// This uses `PR_SET_PDEATHSIG`, so it's only on Linux,
+/// and it's technically tied to the spawning thread rather than our whole process.
+fn kill_with_parent(cmd: &mut Command) {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        use std::os::unix::process::CommandExt;
+
+        // SAFETY: `getpid` has no preconditions.
+        let parent = unsafe { libc::getpid() };
+        // SAFETY: `prctl`, `getppid`, and `raise` are async-signal-safe.
+        unsafe {
+            cmd.pre_exec(move || {
+                if libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                // We may have already died before it was set.
+                if libc::getppid() != parent {
+                    libc::raise(libc::SIGTERM);
+                }
+                Ok(())
+            });
+        }
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let _ = cmd;
+}
+
+/// Killing children when we die on Windows, like [`kill_with_parent`] does on Linux.
+#[cfg(windows)]
+mod job {
+    use std::ffi::c_void;
+    use std::mem;
+    use std::os::windows::io::AsRawHandle;
+    use std::process::Child;
+    use std::ptr;
+    use std::sync::OnceLock;
+
+    use windows_sys::Win32::Foundation::CloseHandle;
+    use windows_sys::Win32::Foundation::HANDLE;
+    use windows_sys::Win32::System::JobObjects::AssignProcessToJobObject;
+    use windows_sys::Win32::System::JobObjects::CreateJobObjectW;
+    use windows_sys::Win32::System::JobObjects::JobObjectExtendedLimitInformation;
+    use windows_sys::Win32::System::JobObjects::SetInformationJobObject;
+    use windows_sys::Win32::System::JobObjects::JOBOBJECT_EXTENDED_LIMIT_INFORMATION;
+    use windows_sys::Win32::System::JobObjects::JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE;
+
+    /// Our Job Object (as an address, so it can be shared), created the first time,
+    /// or [`None`] if that failed.
+    ///
+    /// Its handle is never closed, so it's only closed by our death, which kills its processes.
+    fn job() -> Option<usize> {
+        static JOB: OnceLock<Option<usize>> = OnceLock::new();
+        *JOB.get_or_init(|| {
+            // SAFETY: The job handle is checked before use, and `info` is fully initialized.
+            unsafe {
+                let job = CreateJobObjectW(ptr::null(), ptr::null());
+                if job.is_null() {
+                    return None;
+                }
+                let mut info: JOBOBJECT_EXTENDED_LIMIT_INFORMATION = mem::zeroed();
+                info.BasicLimitInformation.LimitFlags = JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE;
+                let set = SetInformationJobObject(
+                    job,
+                    JobObjectExtendedLimitInformation,
+                    &info as *const _ as *const c_void,
+                    mem::size_of_val(&info) as u32,
+                );
+                if set == 0 {
+                    CloseHandle(job);
+                    return None;
+                }
+                Some(job as usize)
+            }
+        })
+    }
+
+    /// Put `child` (and so the processes it spawns) in our Job Object, if there is one.
+    ///
+    /// This is best effort, as the child is already running,
+    /// and can fail if it's already in a job that doesn't allow nesting (before Windows 8).
+    pub fn assign(child: &Child) {
+        let Some(job) = job() else {
+            return;
+        };
+        // SAFETY: Both handles are valid: the job's is never closed, and `child` isn't waited on yet.
+        unsafe {
+            AssignProcessToJobObject(job as HANDLE, child.as_raw_handle() as HANDLE);
+        }
+    }
+}
+
+/// Spawn `cmd`, killing it if we die (see [`kill_with_parent`] and [`job`])
+/// and forwarding signals to it until the returned [`ForwardSignals`] is dropped
+/// or it's waited for with [`wait`] (which `child` should always be waited for with).
+pub(crate) fn spawn(cmd: &mut Command) -> io::Result<(Child, ForwardSignals)> {
+    kill_with_parent(cmd);
+    let child = cmd.spawn()?;
+    #[cfg(windows)]
+    job::assign(&child);
+    let forward = ForwardSignals::to(&child);
+    Ok((child, forward))
+}
+
+/// Like [`Child::wait`], but stop forwarding signals to `child` (see [`spawn`])
+/// after it exits but before it's reaped,
+/// so they're never sent to an unrelated process that reuses its pid.
+pub(crate) fn wait(child: &mut Child) -> io::Result<ExitStatus> {
+    #[cfg(unix)]
+    {
+        imp::wait_exited(child.id(), true)?;
+        imp::unregister_pid(child.id());
+    }
+    child.wait()
+}
+
+/// Like [`Child::try_wait`], but see [`wait`].
+pub(crate) fn try_wait(child: &mut Child) -> io::Result<Option<ExitStatus>> {
+    #[cfg(unix)]
+    {
+        if !imp::wait_exited(child.id(), false)? {
+            return Ok(None);
+        }
+        imp::unregister_pid(child.id());
+    }
+    child.try_wait()
+}
+
+/// Like [`Command::status`], but see [`spawn`].
+pub(crate) fn status(cmd: &mut Command) -> io::Result<ExitStatus> {
+    let (mut child, _forward) = spawn(cmd)?;
+    wait(&mut child)
+}
+
+/// Like [`Command::output`], but see [`spawn`].
+pub(crate) fn output(cmd: &mut Command) -> io::Result<Output> {
+    let (mut child, _forward) = spawn(
+        cmd.stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped()),
+    )?;
+    // Read both pipes ourselves, as `Child::wait_with_output` would reap `child` with `wait`.
+    let mut stderr = child.stderr.take().expect("stderr was piped");
+    let stderr = thread::spawn(move || {
+        let mut buf = Vec::new();
+        stderr.read_to_end(&mut buf).map(|_| buf)
+    });
+    let mut stdout = Vec::new();
+    let read = child
+        .stdout
+        .take()
+        .expect("stdout was piped")
+        .read_to_end(&mut stdout);
+    let stderr = stderr.join().expect("stderr reader panicked");
+    let status = wait(&mut child)?;
+    read?;
+    Ok(Output {
+        status,
+        stdout,
+        stderr: stderr?,
+    })
+}
+
+/// Like [`status`], but kill `cmd` if it's still running after `timeout`,
+/// failing with an [`io::ErrorKind::TimedOut`] error (see [`Waiter`]).
+pub(crate) fn status_with_timeout(
+    cmd: &mut Command,
+    timeout: Option<Duration>,
+) -> io::Result<ExitStatus> {
+    let (child, _forward) = spawn(cmd)?;
+    Waiter::new(child, timeout).wait()
+}
+
+/// Waits for a child, killing it if it's still running after a timeout.
+///
+/// With a timeout, the child is waited for on another thread,
+/// so that the caller can keep reading its output until it exits or is killed.
+pub(crate) enum Waiter {
+    Child(Child),
+    Watchdog {
+        kill: mpsc::Sender<()>,
+        thread: JoinHandle<io::Result<ExitStatus>>,
+    },
+}
+
+impl Waiter {
+    pub(crate) fn new(mut child: Child, timeout: Option<Duration>) -> Self {
+        let Some(timeout) = timeout else {
+            return Self::Child(child);
+        };
+        let (kill, killed) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            let deadline = Instant::now() + timeout;
+            let mut poll = Duration::from_millis(1);
+            loop {
+                if let Some(status) = try_wait(&mut child)? {
+                    return Ok(status);
+                }
+                if Instant::now() >= deadline {
+                    child.kill()?;
+                    wait(&mut child)?;
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("timed out after {timeout:?}"),
+                    ));
+                }
+                // Back off so quick commands are noticed quickly but long ones aren't polled much.
+                if let Ok(()) = killed.recv_timeout(poll) {
+                    let _ = child.kill();
+                }
+                poll = (poll * 2).min(Duration::from_millis(50));
+            }
+        });
+        Self::Watchdog { kill, thread }
+    }
+
+    /// Kill the child, like on an error reading its output, so it's not left blocked on a full pipe.
+    pub(crate) fn kill(&mut self) {
+        match self {
+            Self::Child(child) => {
+                let _ = child.kill();
+            }
+            Self::Watchdog { kill, .. } => {
+                let _ = kill.send(());
+            }
+        }
+    }
+
+    pub(crate) fn wait(self) -> io::Result<ExitStatus> {
+        match self {
+            Self::Child(mut child) => wait(&mut child),
+            Self::Watchdog { thread, .. } => thread.join().expect("watchdog thread panicked"),
+        }
+    }
+}
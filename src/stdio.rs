@@ -0,0 +1,244 @@
+//! Configurable stdin, stdout, and stderr handling for commands run by the `cargo` wrapper.
+
+use std::io;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::Command;
+use std::process::ExitStatus;
+use std::process::Stdio;
+use std::thread;
+
+use crate::line_stream::OutputStream;
+use crate::signals;
+
+/// The kinds of commands the `cargo` wrapper runs, each with its own [`CommandStdio`]
+/// (see [`crate::CargoWrapper::set_stdio`]).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum CommandKind {
+    /// `cargo` commands run by [`crate::CargoWrapper::run_cargo`],
+    /// like `cargo add` (for [`crate::CargoWrapper::add_runtime_dependency`]) or `cargo clean`.
+    Cargo,
+    /// The wrapped build run by [`crate::CargoWrapper::run_cargo_with_rustc_wrapper`].
+    ///
+    /// Builds that parse `cargo`'s output themselves,
+    /// like [`crate::CargoWrapper::run_cargo_json`], ignore its stdout and stderr policies.
+    Build,
+    /// Test binaries and binaries run by [`crate::CargoWrapper::run_cargo_test`]
+    /// and [`crate::CargoWrapper::run_cargo_run`].
+    Run,
+}
+
+/// What to do with a command's stdin.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum StdinPolicy {
+    /// Inherit ours, or whatever the command was already configured with.
+    #[default]
+    Inherit,
+    /// Read nothing (`/dev/null`).
+    Null,
+}
+
+/// What to do with a command's stdout or stderr.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub enum OutputPolicy {
+    /// Inherit ours, or whatever the command was already configured with.
+    #[default]
+    Inherit,
+    /// Discard it.
+    Null,
+    /// Buffer it, and only show it if the command fails, e.g. to silence `cargo add` unless it fails.
+    Capture,
+    /// Show it, and also append it to a file, e.g. to keep a build log.
+    Tee(PathBuf),
+}
+
+/// How to handle a command's stdin, stdout, and stderr, see [`crate::CargoWrapper::set_stdio`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct CommandStdio {
+    pub stdin: StdinPolicy,
+    pub stdout: OutputPolicy,
+    pub stderr: OutputPolicy,
+}
+
+impl CommandStdio {
+    /// Discard stdout and stderr unless the command fails.
+    pub fn quiet() -> Self {
+        Self {
+            stdin: StdinPolicy::Inherit,
+            stdout: OutputPolicy::Capture,
+            stderr: OutputPolicy::Capture,
+        }
+    }
+
+    fn set_on(&self, cmd: &mut Command) {
+        if self.stdin == StdinPolicy::Null {
+            cmd.stdin(Stdio::null());
+        }
+        match &self.stdout {
+            OutputPolicy::Inherit => {}
+            OutputPolicy::Null => {
+                cmd.stdout(Stdio::null());
+            }
+            OutputPolicy::Capture | OutputPolicy::Tee(_) => {
+                cmd.stdout(Stdio::piped());
+            }
+        }
+        match &self.stderr {
+            OutputPolicy::Inherit => {}
+            OutputPolicy::Null => {
+                cmd.stderr(Stdio::null());
+            }
+            OutputPolicy::Capture | OutputPolicy::Tee(_) => {
+                cmd.stderr(Stdio::piped());
+            }
+        }
+    }
+}
+
+/// Where a piped stream of a command goes, according to its [`OutputPolicy`].
+enum Sink {
+    Echo(OutputStream),
+    Capture(OutputStream, Vec<u8>),
+    Tee(OutputStream, fs_err::File),
+}
+
+impl Sink {
+    fn new(stream: OutputStream, policy: &OutputPolicy) -> io::Result<Self> {
+        Ok(match policy {
+            OutputPolicy::Inherit | OutputPolicy::Null => Self::Echo(stream),
+            OutputPolicy::Capture => Self::Capture(stream, Vec::new()),
+            OutputPolicy::Tee(path) => Self::Tee(
+                stream,
+                fs_err::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)?,
+            ),
+        })
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        match self {
+            Self::Echo(stream) => echo(*stream, bytes),
+            Self::Capture(_, captured) => {
+                captured.extend_from_slice(bytes);
+                Ok(())
+            }
+            Self::Tee(stream, file) => {
+                echo(*stream, bytes)?;
+                file.write_all(bytes)
+            }
+        }
+    }
+
+    /// Show the captured output if the command failed.
+    fn finish(self, status: ExitStatus) -> io::Result<()> {
+        match self {
+            Self::Capture(stream, captured) if !status.success() => echo(stream, &captured),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn echo(stream: OutputStream, bytes: &[u8]) -> io::Result<()> {
+    match stream {
+        OutputStream::Stdout => io::stdout().write_all(bytes),
+        OutputStream::Stderr => io::stderr().write_all(bytes),
+    }
+}
+
+/// Copy `reader` into `sink` on another thread, returning the sink once it's done.
+fn spawn_copier(
+    mut reader: impl Read + Send + 'static,
+    mut sink: Sink,
+) -> thread::JoinHandle<io::Result<Sink>> {
+    thread::spawn(move || {
+        let mut buf = [0; 8192];
+        loop {
+            let n = reader.read(&mut buf)?;
+            if n == 0 {
+                return Ok(sink);
+            }
+            sink.write(&buf[..n])?;
+        }
+    })
+}
+
+/// Like [`signals::status`], but with `stdio`.
+pub(crate) fn status(cmd: &mut Command, stdio: &CommandStdio) -> io::Result<ExitStatus> {
+    let status = run(cmd, stdio, None::<fn(&[u8]) -> bool>)?;
+    Ok(status.expect("never stopped"))
+}
+
+/// Like [`status`], but also pass each line of stderr to `stop` as it comes (piping it if needed),
+/// killing `cmd` and returning [`None`] if it returns `true`.
+pub(crate) fn status_watching_stderr(
+    cmd: &mut Command,
+    stdio: &CommandStdio,
+    stop: impl FnMut(&[u8]) -> bool,
+) -> io::Result<Option<ExitStatus>> {
+    run(cmd, stdio, Some(stop))
+}
+
+fn run(
+    cmd: &mut Command,
+    stdio: &CommandStdio,
+    mut stop: Option<impl FnMut(&[u8]) -> bool>,
+) -> io::Result<Option<ExitStatus>> {
+    stdio.set_on(cmd);
+    if stop.is_some() && stdio.stderr != OutputPolicy::Null {
+        cmd.stderr(Stdio::piped());
+    }
+    // Open the sinks first, so that failing to (like for an unwritable tee file)
+    // doesn't leave `cmd` running.
+    let stdout_sink = Sink::new(OutputStream::Stdout, &stdio.stdout)?;
+    let mut stderr_sink = Sink::new(OutputStream::Stderr, &stdio.stderr)?;
+    let (mut child, _forward) = signals::spawn(cmd)?;
+    let stdout = child
+        .stdout
+        .take()
+        .map(|stdout| spawn_copier(stdout, stdout_sink));
+    let mut stopped = false;
+    let mut result = Ok(());
+    if let Some(stderr) = child.stderr.take() {
+        let mut stderr = BufReader::new(stderr);
+        let mut line = Vec::new();
+        result = loop {
+            line.clear();
+            match stderr.read_until(b'\n', &mut line) {
+                Ok(0) => break Ok(()),
+                Ok(_) => {}
+                Err(e) => break Err(e),
+            }
+            if let Err(e) = stderr_sink.write(&line) {
+                break Err(e);
+            }
+            if stop.as_mut().is_some_and(|stop| stop(&line)) {
+                stopped = true;
+                let _ = child.kill();
+                break Ok(());
+            }
+        };
+    }
+    // Don't leave `cmd` running or unreaped, or the stdout copier running, if echoing failed,
+    // but report that failure over any from waiting.
+    if result.is_err() {
+        let _ = child.kill();
+    }
+    let status = signals::wait(&mut child);
+    let stdout_sink = stdout.map(|stdout| stdout.join().expect("stdout copier panicked"));
+    result?;
+    let status = status?;
+    let stdout_sink = stdout_sink.transpose()?;
+    if stopped {
+        return Ok(None);
+    }
+    if let Some(sink) = stdout_sink {
+        sink.finish(status)?;
+    }
+    stderr_sink.finish(status)?;
+    Ok(Some(status))
+}
@@ -0,0 +1,98 @@
+//! `cargo`'s `--unit-graph` output: every unit (roughly, `rustc` invocation) a build would run.
+//!
+//! See <https://doc.rust-lang.org/cargo/reference/unstable.html#unit-graph>.
+
+use serde::Deserialize;
+use serde_json::Map;
+use serde_json::Value;
+
+use crate::cargo_env::CargoEnv;
+use crate::cargo_env::PackageId;
+use crate::cargo_messages::ArtifactTarget;
+
+/// The units of a build and their dependencies, from `cargo build --unit-graph`,
+/// queried with [`crate::CargoWrapper::unit_graph`].
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct UnitGraph {
+    pub version: u32,
+    pub units: Vec<Unit>,
+    /// Indices into [`Self::units`] of the units `cargo` was asked to build.
+    pub roots: Vec<usize>,
+}
+
+/// A single unit of work, i.e. compiling (or documenting, or running the build script of) a target
+/// with a specific profile, platform, and features.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Unit {
+    pub pkg_id: String,
+    pub target: ArtifactTarget,
+    pub profile: UnitProfile,
+    /// The target triple, or [`None`] for the host.
+    pub platform: Option<String>,
+    /// Like `build`, `check`, `test`, `doc`, or `run-custom-build`.
+    pub mode: String,
+    pub features: Vec<String>,
+    pub dependencies: Vec<UnitDependency>,
+}
+
+/// The profile settings a [`Unit`] is built with.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct UnitProfile {
+    pub name: String,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// An edge in the [`UnitGraph`].
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct UnitDependency {
+    /// Index into [`UnitGraph::units`].
+    pub index: usize,
+    /// The name it's `--extern`ed as.
+    pub extern_crate_name: String,
+    #[serde(default)]
+    pub public: bool,
+    #[serde(default)]
+    pub noprelude: bool,
+}
+
+impl UnitGraph {
+    /// The units `cargo` was asked to build.
+    pub fn roots(&self) -> impl Iterator<Item = &Unit> {
+        self.roots.iter().map(|&index| &self.units[index])
+    }
+
+    /// The direct dependencies of `unit`.
+    pub fn dependencies<'a>(&'a self, unit: &'a Unit) -> impl Iterator<Item = &'a Unit> {
+        unit.dependencies.iter().map(|dep| &self.units[dep.index])
+    }
+
+    /// The [`CargoEnv`] `cargo` will (roughly) run `rustc` with for `unit`,
+    /// for predicting what the `rustc` wrapper will see, like with [`crate::CrateFilter::matches`].
+    ///
+    /// Only the crate and package names and versions
+    /// and [`CargoEnv::primary_package`] (whether it's a package of a root) are known ahead of time.
+    pub fn cargo_env(&self, unit: &Unit) -> CargoEnv {
+        let package_id = unit.package_id();
+        CargoEnv {
+            crate_name: Some(unit.target.name.replace('-', "_")),
+            pkg_name: package_id.as_ref().map(|id| id.name.clone()),
+            pkg_version: package_id.map(|id| id.version),
+            primary_package: self.roots().any(|root| root.pkg_id == unit.pkg_id),
+            ..Default::default()
+        }
+    }
+}
+
+impl Unit {
+    /// Whether this unit compiles a crate with `rustc` (through the `rustc` wrapper),
+    /// as opposed to running a build script or `rustdoc`.
+    pub fn is_rustc(&self) -> bool {
+        matches!(self.mode.as_str(), "build" | "check" | "test")
+    }
+
+    /// The `{name}@{version}` of [`Self::pkg_id`].
+    pub fn package_id(&self) -> Option<PackageId> {
+        PackageId::parse_qualified(&self.pkg_id)
+    }
+}
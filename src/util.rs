@@ -1,14 +1,57 @@
+use std::borrow::Cow;
 use std::env;
+use std::error::Error;
 use std::ffi::OsStr;
 use std::ffi::OsString;
+use std::fmt;
+use std::fmt::Display;
+use std::iter;
+use std::marker::PhantomData;
+use std::ops::Range;
+use std::path::Path;
 use std::path::PathBuf;
 use std::process::Command;
+use std::str::FromStr;
 use std::str::Utf8Error;
+use std::sync::atomic::AtomicU8;
+use std::sync::atomic::Ordering;
+
+use anyhow::anyhow;
+use anyhow::Context;
+
+/// A value of an [`EnvVar`], where [`None`] means the var is unset.
+pub trait EnvValue {
+    fn env_value(&self) -> Option<Cow<'_, OsStr>>;
+}
+
+impl EnvValue for OsString {
+    fn env_value(&self) -> Option<Cow<'_, OsStr>> {
+        Some(Cow::Borrowed(self))
+    }
+}
+
+impl EnvValue for String {
+    fn env_value(&self) -> Option<Cow<'_, OsStr>> {
+        Some(Cow::Borrowed(self.as_ref()))
+    }
+}
+
+impl EnvValue for PathBuf {
+    fn env_value(&self) -> Option<Cow<'_, OsStr>> {
+        Some(Cow::Borrowed(self.as_os_str()))
+    }
+}
+
+impl<V: EnvValue> EnvValue for Option<V> {
+    fn env_value(&self) -> Option<Cow<'_, OsStr>> {
+        self.as_ref()?.env_value()
+    }
+}
 
 #[derive(PartialEq, Eq)]
 pub struct EnvVar<V>
 where
-    V: AsRef<OsStr>,
+    V: EnvValue,
 {
     pub key: &'static str,
     pub value: V,
@@ -16,14 +59,145 @@ where
 
 impl<V> EnvVar<V>
 where
-    V: AsRef<OsStr>,
+    V: EnvValue,
 {
+    /// Set this var on `cmd`, or remove it (including if inherited) if it has no value.
     pub fn set_on(&self, cmd: &mut Command) {
-        cmd.env(self.key, self.value.as_ref());
+        match self.value.env_value() {
+            Some(value) => cmd.env(self.key, value),
+            None => cmd.env_remove(self.key),
+        };
+    }
+}
+
+/// Sets (or unsets) env vars of our own process,
+/// restoring their previous values when dropped.
+///
+/// Like [`env::set_var`], this must not race with other threads reading or writing the env.
+#[derive(Debug, Default)]
+#[must_use]
+pub struct EnvGuard {
+    previous: Vec<(OsString, Option<OsString>)>,
+}
+
+impl EnvGuard {
+    /// Set each of `vars` until this is dropped.
+    #[cfg_attr(not(feature = "rustc-driver"), allow(dead_code))]
+    pub fn set_all<K, V>(vars: impl IntoIterator<Item = (K, V)>) -> Self
+    where
+        K: AsRef<OsStr>,
+        V: AsRef<OsStr>,
+    {
+        let mut guard = Self::default();
+        for (key, value) in vars {
+            guard.set(key.as_ref(), Some(value.as_ref()));
+        }
+        guard
+    }
+
+    /// Set `key` to `value`, or unset it if it's [`None`], until this is dropped.
+    pub fn set(&mut self, key: impl AsRef<OsStr>, value: Option<&OsStr>) {
+        let key = key.as_ref();
+        self.previous.push((key.to_owned(), env::var_os(key)));
+        match value {
+            Some(value) => env::set_var(key, value),
+            None => env::remove_var(key),
+        }
     }
+}
+
+impl Drop for EnvGuard {
+    fn drop(&mut self) {
+        // In reverse, so a var set more than once ends up with its original value.
+        for (key, value) in self.previous.drain(..).rev() {
+            match value {
+                Some(value) => env::set_var(key, value),
+                None => env::remove_var(key),
+            }
+        }
+    }
+}
 
-    pub fn set(&self) {
-        env::set_var(self.key, self.value.as_ref());
+impl<V> EnvVar<Option<V>>
+where
+    V: EnvValue,
+{
+    /// The var `key` with no value, to remove it from a command with [`Self::set_on`],
+    /// like an inherited var that would otherwise leak through.
+    pub fn unset(key: &'static str) -> Self {
+        Self { key, value: None }
+    }
+}
+
+/// An env var with a typed value, like a mode enum, flag, or count,
+/// set as its [`Display`] and read back with its [`FromStr`],
+/// e.g. for passing typed configuration from the `cargo` wrapper to the `rustc` wrapper:
+///
+/// ```
+/// # use cargo_rustc_wrapper::TypedEnvVar;
+/// const JOBS: TypedEnvVar<u32> = TypedEnvVar::new("MY_TOOL_JOBS");
+///
+/// let mut cmd = std::process::Command::new("cargo");
+/// JOBS.set_on(&mut cmd, &4);
+/// // Later, in the `rustc` wrapper:
+/// let jobs = JOBS.get()?.unwrap_or(1);
+/// # anyhow::Ok(())
+/// ```
+pub struct TypedEnvVar<T> {
+    key: &'static str,
+    value: PhantomData<fn() -> T>,
+}
+
+impl<T> TypedEnvVar<T> {
+    pub const fn new(key: &'static str) -> Self {
+        Self {
+            key,
+            value: PhantomData,
+        }
+    }
+
+    pub fn key(&self) -> &'static str {
+        self.key
+    }
+
+    /// Remove this var from `cmd`, including if it's inherited.
+    pub fn unset_on(&self, cmd: &mut Command) {
+        cmd.env_remove(self.key);
+    }
+}
+
+impl<T: Display> TypedEnvVar<T> {
+    /// Set this var to `value` on `cmd`.
+    pub fn set_on(&self, cmd: &mut Command, value: &T) {
+        cmd.env(self.key, value.to_string());
+    }
+
+    /// Set this var to `value` on `cmd`, or remove it (including if inherited) if it's [`None`].
+    pub fn set_or_unset_on(&self, cmd: &mut Command, value: Option<&T>) {
+        match value {
+            Some(value) => self.set_on(cmd, value),
+            None => self.unset_on(cmd),
+        }
+    }
+}
+
+impl<T> TypedEnvVar<T>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    /// Parse this var from our own env if it's set, with an error naming it if it's invalid.
+    pub fn get(&self) -> anyhow::Result<Option<T>> {
+        let key = self.key;
+        let value = match env::var(key) {
+            Ok(value) => value,
+            Err(env::VarError::NotPresent) => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("invalid `${key}`")),
+        };
+        let value = value
+            .parse()
+            .map_err(|e| anyhow!("invalid `${key}` ({value:?}): {e}"))?;
+        Ok(Some(value))
     }
 }
 
@@ -55,25 +229,475 @@ impl EnvVar<PathBuf> {
     }
 }
 
+/// What [`os_str_from_bytes`] does with bytes that aren't UTF-8
+/// where [`OsStr`]s can't hold arbitrary bytes (i.e. on Windows),
+/// set with [`set_non_utf8_strategy`].
+///
+/// On Unix, any bytes are a valid [`OsStr`], so this has no effect.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum NonUtf8Strategy {
+    /// Fail with a [`NonUtf8Error`].
+    #[default]
+    Error,
+    /// Decode them with the console's output code page (or else the ANSI code page),
+    /// which is what non-Rust tools usually write in,
+    /// failing with a [`NonUtf8Error`] if they're not valid in it either.
+    CodePage,
+    /// Replace invalid sequences with `U+FFFD`, printing a warning.
+    Lossy,
+}
+
+impl NonUtf8Strategy {
+    #[cfg_attr(unix, allow(dead_code))]
+    const ALL: [Self; 3] = [Self::Error, Self::CodePage, Self::Lossy];
+}
+
+static NON_UTF8_STRATEGY: AtomicU8 = AtomicU8::new(NonUtf8Strategy::Error as u8);
+
+/// Set the [`NonUtf8Strategy`] for decoding the output of commands the wrapper parses,
+/// like `rustc --print sysroot`, for this process.
+///
+/// Call this at the start of `main`, before [`crate::wrap_cargo_or_rustc`].
+pub fn set_non_utf8_strategy(strategy: NonUtf8Strategy) {
+    NON_UTF8_STRATEGY.store(strategy as u8, Ordering::Relaxed);
+}
+
+#[cfg_attr(unix, allow(dead_code))]
+fn non_utf8_strategy() -> NonUtf8Strategy {
+    NonUtf8Strategy::ALL[NON_UTF8_STRATEGY.load(Ordering::Relaxed) as usize]
+}
+
+/// Bytes (like a command's output) that couldn't be converted to an [`OsStr`]
+/// (see [`NonUtf8Strategy`]).
+#[derive(Debug, Clone)]
+pub struct NonUtf8Error {
+    bytes: Vec<u8>,
+    utf8_error: Utf8Error,
+}
+
+impl NonUtf8Error {
+    /// All of the bytes that were being converted.
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Where the bytes stopped being UTF-8.
+    pub fn utf8_error(&self) -> Utf8Error {
+        self.utf8_error
+    }
+}
+
+impl fmt::Display for NonUtf8Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Self { bytes, utf8_error } = self;
+        write!(f, "{utf8_error} in \"{}\"", bytes.escape_ascii())
+    }
+}
+
+impl Error for NonUtf8Error {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.utf8_error)
+    }
+}
+
 /// Create an [`OsStr`] from bytes.
 ///
 /// Where possible (i.e. `cfg(unix)`), do an `O(1)` unchecked conversion,
-/// and fallback to checked conversion through UTF-8.
-pub fn os_str_from_bytes(bytes: &[u8]) -> Result<&OsStr, Utf8Error> {
+/// and fallback to checked conversion through UTF-8,
+/// handling invalid UTF-8 according to the [`NonUtf8Strategy`].
+pub fn os_str_from_bytes(bytes: &[u8]) -> Result<Cow<'_, OsStr>, NonUtf8Error> {
     #[cfg(not(unix))]
-    fn convert(it: &[u8]) -> Result<&OsStr, Utf8Error> {
-        let it = std::str::from_utf8(it)?;
-        let it = OsStr::new(it);
-        Ok(it)
+    fn convert(it: &[u8]) -> Result<Cow<'_, OsStr>, NonUtf8Error> {
+        let utf8_error = match std::str::from_utf8(it) {
+            Ok(it) => return Ok(Cow::Borrowed(OsStr::new(it))),
+            Err(e) => e,
+        };
+        let error = || NonUtf8Error {
+            bytes: it.to_owned(),
+            utf8_error,
+        };
+        match non_utf8_strategy() {
+            NonUtf8Strategy::Error => Err(error()),
+            #[cfg(windows)]
+            NonUtf8Strategy::CodePage => code_page::decode(it).map(Cow::Owned).ok_or_else(error),
+            #[cfg(not(windows))]
+            NonUtf8Strategy::CodePage => Err(error()),
+            NonUtf8Strategy::Lossy => {
+                eprintln!("warning: {}, decoding it lossily", error());
+                let it = String::from_utf8_lossy(it).into_owned();
+                Ok(Cow::Owned(it.into()))
+            }
+        }
     }
 
     #[cfg(unix)]
-    fn convert(it: &[u8]) -> Result<&OsStr, Utf8Error> {
+    fn convert(it: &[u8]) -> Result<Cow<'_, OsStr>, NonUtf8Error> {
         use std::os::unix::ffi::OsStrExt;
 
         let it = OsStr::from_bytes(it);
-        Ok(it)
+        Ok(Cow::Borrowed(it))
     }
 
     convert(bytes)
 }
+
+#[cfg(windows)]
+mod code_page {
+    use std::ffi::OsString;
+    use std::os::windows::ffi::OsStringExt;
+    use std::ptr;
+
+    use windows_sys::Win32::Globalization::GetACP;
+    use windows_sys::Win32::Globalization::MultiByteToWideChar;
+    use windows_sys::Win32::Globalization::MB_ERR_INVALID_CHARS;
+    use windows_sys::Win32::System::Console::GetConsoleOutputCP;
+
+    /// Decode `bytes` in the console's output code page, or the ANSI code page without a console.
+    pub fn decode(bytes: &[u8]) -> Option<OsString> {
+        if bytes.is_empty() {
+            return Some(OsString::new());
+        }
+        let len = i32::try_from(bytes.len()).ok()?;
+        // SAFETY: These have no preconditions.
+        let code_page = match unsafe { GetConsoleOutputCP() } {
+            0 => unsafe { GetACP() },
+            code_page => code_page,
+        };
+        // SAFETY: `bytes` is valid for `len` bytes, and with no output buffer,
+        // this only returns the length needed.
+        let wide_len = unsafe {
+            MultiByteToWideChar(
+                code_page,
+                MB_ERR_INVALID_CHARS,
+                bytes.as_ptr(),
+                len,
+                ptr::null_mut(),
+                0,
+            )
+        };
+        if wide_len <= 0 {
+            return None;
+        }
+        let mut wide = vec![0; wide_len as usize];
+        // SAFETY: `wide` is valid for `wide_len` `u16`s.
+        let written = unsafe {
+            MultiByteToWideChar(
+                code_page,
+                MB_ERR_INVALID_CHARS,
+                bytes.as_ptr(),
+                len,
+                wide.as_mut_ptr(),
+                wide_len,
+            )
+        };
+        if written <= 0 {
+            return None;
+        }
+        wide.truncate(written as usize);
+        Some(OsString::from_wide(&wide))
+    }
+}
+
+/// Strip an ASCII `prefix` from an [`OsStr`] without requiring the rest to be UTF-8.
+pub fn os_str_strip_prefix<'a>(s: &'a OsStr, prefix: &str) -> Option<&'a OsStr> {
+    let rest = s.as_encoded_bytes().strip_prefix(prefix.as_bytes())?;
+    // SAFETY: `prefix` is valid UTF-8, so the split is right after a valid UTF-8 substring.
+    Some(unsafe { OsStr::from_encoded_bytes_unchecked(rest) })
+}
+
+/// Split an [`OsStr`] at the first occurrence of the ASCII `sep`, without requiring it to be UTF-8.
+pub fn os_str_split_once(s: &OsStr, sep: u8) -> Option<(&OsStr, &OsStr)> {
+    assert!(sep.is_ascii());
+    let bytes = s.as_encoded_bytes();
+    let i = bytes.iter().position(|&b| b == sep)?;
+    let (before, after) = (&bytes[..i], &bytes[i + 1..]);
+    // SAFETY: `sep` is ASCII, so splitting around it is splitting at valid UTF-8 boundaries.
+    unsafe {
+        Some((
+            OsStr::from_encoded_bytes_unchecked(before),
+            OsStr::from_encoded_bytes_unchecked(after),
+        ))
+    }
+}
+
+/// Join [`OsStr`]s with a separator, like [`slice::join`] but for any iterator,
+/// e.g. to pass a list of flags or paths through an env var.
+pub trait OsStringJoin {
+    fn join(&mut self, sep: &OsStr) -> OsString;
+}
+
+impl<I, T> OsStringJoin for I
+where
+    I: Iterator<Item = T>,
+    T: AsRef<OsStr>,
+{
+    fn join(&mut self, sep: &OsStr) -> OsString {
+        let mut joined = OsString::new();
+        if let Some(first) = self.next() {
+            joined.push(first);
+        }
+        for s in self {
+            joined.push(sep);
+            joined.push(s);
+        }
+        joined
+    }
+}
+
+/// Split an [`OsStr`] on every occurrence of the ASCII `sep`, without requiring it to be UTF-8,
+/// the inverse of [`OsStringJoin::join`].
+///
+/// Like [`str::split`], an empty `s` is split into one empty [`OsStr`],
+/// so an empty list can't be round-tripped this way.
+pub fn os_str_split(s: &OsStr, sep: u8) -> impl Iterator<Item = &OsStr> {
+    let mut rest = Some(s);
+    iter::from_fn(move || {
+        let s = rest?;
+        match os_str_split_once(s, sep) {
+            Some((before, after)) => {
+                rest = Some(after);
+                Some(before)
+            }
+            None => {
+                rest = None;
+                Some(s)
+            }
+        }
+    })
+}
+
+/// Split an [`OsStr`] on ASCII whitespace, skipping empty parts, without requiring it to be UTF-8.
+pub fn os_str_split_whitespace(s: &OsStr) -> impl Iterator<Item = &OsStr> {
+    s.as_encoded_bytes()
+        .split(u8::is_ascii_whitespace)
+        .filter(|part| !part.is_empty())
+        // SAFETY: Whitespace is ASCII, so splitting around it is splitting at valid UTF-8 boundaries.
+        .map(|part| unsafe { OsStr::from_encoded_bytes_unchecked(part) })
+}
+
+/// Find the occurrences of a command line flag and its value,
+/// given as `{long} {value}` or `{long}={value}`,
+/// or if it has a `short` form, as `{short} {value}` or `{short}{value}`.
+///
+/// Yields the range of `args` that each occurrence spans along with its value.
+fn flag_occurrences<'a>(
+    args: &'a [OsString],
+    long: &'a str,
+    short: Option<&'a str>,
+) -> impl Iterator<Item = (Range<usize>, &'a OsStr)> {
+    let mut i = 0;
+    iter::from_fn(move || {
+        while let Some(arg) = args.get(i) {
+            let start = i;
+            i += 1;
+            if arg == long || short.is_some_and(|short| arg == short) {
+                let value = args.get(i)?;
+                i += 1;
+                return Some((start..i, value.as_os_str()));
+            }
+            let value = os_str_strip_prefix(arg, long)
+                .and_then(|rest| os_str_strip_prefix(rest, "="))
+                .or_else(|| {
+                    short
+                        .and_then(|short| os_str_strip_prefix(arg, short))
+                        .filter(|value| !value.is_empty())
+                });
+            if let Some(value) = value {
+                return Some((start..i, value));
+            }
+        }
+        None
+    })
+}
+
+/// Iterate over the values of a command line `flag`,
+/// given either as `{flag} {value}` or `{flag}={value}`.
+pub fn flag_values<'a>(args: &'a [OsString], flag: &'a str) -> impl Iterator<Item = &'a OsStr> {
+    flag_occurrences(args, flag, None).map(|(_, value)| value)
+}
+
+/// Iterate over the values of a command line flag with a `short` form,
+/// given as `{long} {value}`, `{long}={value}`, `{short} {value}`, or `{short}{value}`.
+pub fn short_flag_values<'a>(
+    args: &'a [OsString],
+    long: &'a str,
+    short: &'a str,
+) -> impl Iterator<Item = &'a OsStr> {
+    flag_occurrences(args, long, Some(short)).map(|(_, value)| value)
+}
+
+/// Iterate over the values of the `-C`/`--codegen` option `name`,
+/// like `3` for `-C opt-level=3`, `-Copt-level=3`, or `--codegen opt-level=3`.
+///
+/// Options given without a value (`-C {name}`) yield an empty value.
+pub fn codegen_option_values<'a, 'n>(
+    args: &'a [OsString],
+    name: &'n str,
+) -> impl Iterator<Item = &'a OsStr> + use<'a, 'n> {
+    flag_occurrences(args, "--codegen", Some("-C")).filter_map(move |(_, value)| {
+        let rest = os_str_strip_prefix(value, name)?;
+        if rest.is_empty() {
+            return Some(rest);
+        }
+        os_str_strip_prefix(rest, "=")
+    })
+}
+
+/// Remove all occurrences of a command line flag (see [`flag_occurrences`])
+/// whose value matches `predicate`.
+pub fn remove_flag_if(
+    args: &mut Vec<OsString>,
+    long: &str,
+    short: Option<&str>,
+    mut predicate: impl FnMut(&OsStr) -> bool,
+) {
+    let ranges = flag_occurrences(args, long, short)
+        .filter(|(_, value)| predicate(value))
+        .map(|(range, _)| range)
+        .collect::<Vec<_>>();
+    for range in ranges.into_iter().rev() {
+        args.drain(range);
+    }
+}
+
+/// Remove all occurrences of a command line `flag` and its value,
+/// given either as `{flag} {value}` or `{flag}={value}`.
+pub fn remove_flag(args: &mut Vec<OsString>, flag: &str) {
+    remove_flag_if(args, flag, None, |_| true);
+}
+
+/// Remove all occurrences of the `-C`/`--codegen` option `name`, like `-C embed-bitcode=no`.
+pub fn remove_codegen_option(args: &mut Vec<OsString>, name: &str) {
+    remove_flag_if(args, "--codegen", Some("-C"), |value| {
+        os_str_strip_prefix(value, name)
+            .is_some_and(|rest| rest.is_empty() || os_str_strip_prefix(rest, "=").is_some())
+    });
+}
+
+/// The value of the env var `key` that `cmd` will be run with,
+/// either set explicitly on `cmd` or else inherited from the current process.
+pub fn command_env(cmd: &Command, key: &str) -> Option<OsString> {
+    match cmd.get_envs().find(|(k, _)| *k == key) {
+        Some((_, value)) => value.map(ToOwned::to_owned),
+        None => env::var_os(key),
+    }
+}
+
+/// A copy of `cmd` with its args replaced by `args`,
+/// keeping its program, env (including removed vars), and working directory,
+/// since a [`Command`]'s args can only be appended to.
+///
+/// The stdio of `cmd` can't be read, so it isn't copied.
+pub fn command_with_args<I, S>(cmd: &Command, args: I) -> Command
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let mut new = Command::new(cmd.get_program());
+    new.args(args);
+    for (key, value) in cmd.get_envs() {
+        match value {
+            Some(value) => new.env(key, value),
+            None => new.env_remove(key),
+        };
+    }
+    if let Some(dir) = cmd.get_current_dir() {
+        new.current_dir(dir);
+    }
+    new
+}
+
+/// Match an env var `key` against a glob `pattern`, like [`glob_match`],
+/// but case-insensitively on Windows, where env var names are case-insensitive (`Path` is `PATH`).
+pub fn env_key_glob_match(pattern: &str, key: &str) -> bool {
+    if cfg!(windows) {
+        glob_match(&pattern.to_uppercase(), &key.to_uppercase())
+    } else {
+        glob_match(pattern, key)
+    }
+}
+
+/// Match `s` against a glob `pattern`, where `*` matches any sequence of chars
+/// and `?` matches any single char.
+pub fn glob_match(pattern: &str, s: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let s = s.chars().collect::<Vec<_>>();
+    let (mut p, mut i) = (0, 0);
+    // The position of the last `*` in `pattern` and where it started matching in `s`.
+    let mut star = None;
+    while i < s.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, i));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == s[i] => {
+                p += 1;
+                i += 1;
+            }
+            _ => match star {
+                Some((star_p, star_i)) => {
+                    p = star_p + 1;
+                    i = star_i + 1;
+                    star = Some((star_p, star_i + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Quote `s` for a POSIX shell, if needed.
+///
+/// Non-UTF-8 is replaced lossily, so this is only for display.
+pub fn shell_quote(s: &OsStr) -> String {
+    let s = s.to_string_lossy();
+    let is_safe = |b: u8| b.is_ascii_alphanumeric() || b"-_./=:,+@%^".contains(&b);
+    if !s.is_empty() && s.bytes().all(is_safe) {
+        return s.into_owned();
+    }
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// `cmd` as a copy-pastable POSIX shell command line,
+/// including its working directory and the env vars set or removed on it.
+pub fn shell_command_line(cmd: &Command) -> String {
+    shell_command_line_inheriting(cmd, None, &[])
+}
+
+/// Like [`shell_command_line`], but also with the working directory `dir`
+/// and env vars `envs` that `cmd` would otherwise inherit (unless it sets them itself),
+/// so that it can be re-run elsewhere.
+pub fn shell_command_line_inheriting(
+    cmd: &Command,
+    dir: Option<&Path>,
+    envs: &[(OsString, OsString)],
+) -> String {
+    let mut words = Vec::new();
+    if let Some(dir) = cmd.get_current_dir().or(dir) {
+        words.extend(["cd".into(), shell_quote(dir.as_os_str()), "&&".into()]);
+    }
+    let inherited = envs
+        .iter()
+        .filter(|(key, _)| !cmd.get_envs().any(|(k, _)| k == key))
+        .map(|(key, value)| (key.as_os_str(), Some(value.as_os_str())));
+    let (mut set, removed) = cmd
+        .get_envs()
+        .chain(inherited)
+        .partition::<Vec<_>, _>(|(_, value)| value.is_some());
+    set.sort();
+    if !removed.is_empty() {
+        words.push("env".into());
+        for (key, _) in removed {
+            words.extend(["-u".into(), shell_quote(key)]);
+        }
+    }
+    for (key, value) in set {
+        let value = value.unwrap_or_default();
+        words.push(format!("{}={}", key.to_string_lossy(), shell_quote(value)));
+    }
+    words.push(shell_quote(cmd.get_program()));
+    words.extend(cmd.get_args().map(shell_quote));
+    words.join(" ")
+}
@@ -0,0 +1,156 @@
+use std::fmt;
+use std::fmt::Display;
+use std::fmt::Formatter;
+use std::process::Command;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::ensure;
+use anyhow::Context;
+
+use crate::exit;
+
+/// A `rustc` (or `cargo`) release channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Channel {
+    Stable,
+    Beta,
+    Nightly,
+    Dev,
+}
+
+/// A `rustc` or `cargo` version, as reported by `rustc -vV` or `cargo -vV`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ToolVersion {
+    /// `rustc` or `cargo`.
+    pub tool: String,
+    /// Like `1.70.0-nightly`.
+    pub release: String,
+    /// The `major.minor.patch` of [`Self::release`].
+    pub semver: (u64, u64, u64),
+    pub channel: Channel,
+    /// The full commit hash, if known.
+    pub commit_hash: Option<String>,
+    pub commit_date: Option<String>,
+    /// The host triple.
+    pub host: String,
+    /// Only reported by `rustc`.
+    pub llvm_version: Option<String>,
+}
+
+impl Display for ToolVersion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.tool, self.release)?;
+        if let Some(commit_hash) = &self.commit_hash {
+            let short_hash = commit_hash.get(..9).unwrap_or(commit_hash);
+            write!(f, " ({short_hash}")?;
+            if let Some(commit_date) = &self.commit_date {
+                write!(f, " {commit_date}")?;
+            }
+            write!(f, ")")?;
+        }
+        Ok(())
+    }
+}
+
+impl Channel {
+    fn from_release(release: &str) -> Self {
+        match release.split_once('-').map(|(_, channel)| channel) {
+            None => Self::Stable,
+            Some(channel) if channel.starts_with("beta") => Self::Beta,
+            Some("nightly") => Self::Nightly,
+            Some(_) => Self::Dev,
+        }
+    }
+}
+
+/// The value of field `name` in `-vV` output, like `release: 1.70.0`.
+fn verbose_version_field(verbose_version: &str, name: &str) -> Option<String> {
+    verbose_version.lines().find_map(|line| {
+        let value = line.strip_prefix(name)?.strip_prefix(": ")?;
+        Some(value.trim().to_owned())
+    })
+}
+
+/// Run `{cmd} -vV`, returning its stdout.
+fn query_verbose_version(mut cmd: Command, name: &str) -> anyhow::Result<String> {
+    let output = exit::checked_output(cmd.arg("-vV"))
+        .with_context(|| format!("could not find the `{name}` version"))?;
+    String::from_utf8(output.stdout).with_context(|| format!("non-UTF-8 `{name} -vV` output"))
+}
+
+fn parse_semver(release: &str) -> anyhow::Result<(u64, u64, u64)> {
+    let version = release
+        .split_once('-')
+        .map_or(release, |(version, _)| version);
+    let mut parts = version.split('.').map(str::parse::<u64>);
+    let mut next = || -> anyhow::Result<u64> {
+        parts
+            .next()
+            .ok_or_else(|| anyhow!("invalid version: {release}"))?
+            .with_context(|| format!("invalid version: {release}"))
+    };
+    Ok((next()?, next()?, next()?))
+}
+
+impl ToolVersion {
+    /// Parse the output of `{tool} -vV`, like `rustc -vV` or `cargo -vV`.
+    pub fn parse(tool: &str, verbose_version: &str) -> anyhow::Result<Self> {
+        let field = |name: &str| verbose_version_field(verbose_version, name);
+        let release =
+            field("release").ok_or_else(|| anyhow!("no release in `{tool} -vV` output"))?;
+        let semver = parse_semver(&release)?;
+        let channel = Channel::from_release(&release);
+        let known = |value: Option<String>| value.filter(|value| value != "unknown");
+        Ok(Self {
+            tool: tool.to_owned(),
+            release,
+            semver,
+            channel,
+            commit_hash: known(field("commit-hash")),
+            commit_date: known(field("commit-date")),
+            host: field("host").ok_or_else(|| anyhow!("no host in `{tool} -vV` output"))?,
+            llvm_version: field("LLVM version"),
+        })
+    }
+
+    /// Run `{cmd} -vV` and parse its output, where `cmd` runs `tool`, like `rustc` or `cargo`.
+    pub fn query(cmd: Command, tool: &str) -> anyhow::Result<Self> {
+        Self::parse(tool, &query_verbose_version(cmd, tool)?)
+    }
+
+    /// Fail unless this is at least version `min`, like `1.70.0`.
+    pub fn ensure_at_least(&self, min: &str) -> anyhow::Result<()> {
+        let min_semver = parse_semver(min)?;
+        ensure!(
+            self.semver >= min_semver,
+            "{self} is too old, need at least {min}"
+        );
+        Ok(())
+    }
+
+    /// Fail unless this is exactly the commit `commit_hash` (which may be abbreviated),
+    /// as needed for tools linking to the `rustc_private` crates of a specific `rustc`.
+    pub fn ensure_commit(&self, commit_hash: &str) -> anyhow::Result<()> {
+        let Some(own_hash) = &self.commit_hash else {
+            bail!("{self} has an unknown commit hash, need {commit_hash}");
+        };
+        ensure!(
+            !commit_hash.is_empty() && own_hash.starts_with(commit_hash),
+            "{self} is the wrong `{}`, need commit {commit_hash}",
+            self.tool
+        );
+        Ok(())
+    }
+
+    /// Whether this `cargo` supports `$CARGO_ENCODED_RUSTFLAGS` (and `$CARGO_ENCODED_RUSTDOCFLAGS`),
+    /// which are needed for flags containing spaces (since 1.55).
+    pub fn supports_encoded_rustflags(&self) -> bool {
+        self.semver >= (1, 55, 0)
+    }
+
+    /// Whether `--config {key}={value}` is stable in this `cargo` (since 1.63).
+    pub fn supports_config_flag(&self) -> bool {
+        self.semver >= (1, 63, 0)
+    }
+}